@@ -2,14 +2,15 @@
 #[macro_use] extern crate prettytable;
 use prettytable::Table;
 use std::io::{Write, StdoutLock};
-use orderbook::Summary;
+use orderbook::{Level, Summary};
 use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub mod orderbook {
     tonic::include_proto!("orderbook");
 }
 
-use orderbook::{orderbook_aggregator_client, Empty};
+use orderbook::{orderbook_aggregator_client, SummaryRequest};
 use tokio_stream::StreamExt;
 
 const GRPC_SERVER_URL: &str = "http://[::1]:";
@@ -20,20 +21,61 @@ fn clear(lock: &mut StdoutLock) {
     let _ = write!(lock, "{esc}[2J{esc}[1;1H", esc = 27 as char);
 }
 
-fn print_summary(lock: &mut StdoutLock, summary: &Summary) {
-    let spread_table = table!(["Spread", summary.spread]);
-    let _ = writeln!(lock, "{}", spread_table.to_string());
+//The exact value of a number of ticks, e.g. 6512300 with 8 decimals is 0.06512300
+fn decimal(units: i64, decimals: u32) -> String {
+    let sign = if units < 0 { "-" } else { "" };
+    let digits = format!("{:0>width$}", units.unsigned_abs(), width = decimals as usize + 1);
+    let (int, frac) = digits.split_at(digits.len() - decimals as usize);
+    match frac.is_empty() {
+        true => format!("{sign}{int}"),
+        false => format!("{sign}{int}.{frac}"),
+    }
+}
+
+//The amount of each venue of a consolidated level
+fn exchange(level: &Level, summary: &Summary) -> String {
+    if level.venues.is_empty() {
+        return level.exchange.clone();
+    }
+    level.venues.iter().map(|venue| format!("{} {}", venue.exchange, decimal(venue.amount_units, summary.amount_decimals))).collect::<Vec<_>>().join(", ")
+}
+
+//The time elapsed since a time in microseconds since the unix epoch, in milliseconds
+fn elapsed_ms(time_us: u64) -> String {
+    let now_us = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as u64).unwrap_or_default();
+    format!("{:.1}ms", now_us.saturating_sub(time_us) as f64 / 1000.0)
+}
+
+//How old the book of each venue is, since the venue generated it when the venue sends its time, otherwise
+//since the server received it
+fn book_ages(summary: &Summary) -> String {
+    summary.venue_times.iter().map(|time| {
+        let since = if time.exchange_time_us > 0 { time.exchange_time_us } else { time.received_time_us };
+        format!("{} {}", time.exchange, elapsed_ms(since))
+    }).collect::<Vec<_>>().join(", ")
+}
+
+fn print_summary(lock: &mut StdoutLock, summary: &Summary, missed: u64) {
+    let spread_table = table!(["Symbol", summary.symbol], ["Spread", decimal(summary.spread_units, summary.price_decimals)],
+        ["Published", elapsed_ms(summary.publish_time_us)], ["Book age", book_ages(summary)],
+        ["Sequence", format!("{} ({} missed)", summary.sequence, missed)]);
+    if !summary.excluded_venues.is_empty() {
+        let _ = writeln!(lock, "Stale, excluded: {}", summary.excluded_venues.join(", "));
+    }
+    let _ = writeln!(lock, "{}", spread_table);
 
     let mut bid_ask_table = Table::new();
     
+    let price = |level: &Level| decimal(level.price_units, summary.price_decimals);
+    let amount = |level: &Level| decimal(level.amount_units, summary.amount_decimals);
     for n in summary.asks.iter().rev() {
-        bid_ask_table.add_row(row!["", "", "", n.price, n.amount, n.exchange]);
+        bid_ask_table.add_row(row!["", "", "", price(n), amount(n), exchange(n, summary)]);
     }
     for n in summary.bids.iter() {
-        bid_ask_table.add_row(row![n.exchange, n.amount, n.price, "", "", ""]);
+        bid_ask_table.add_row(row![exchange(n, summary), amount(n), price(n), "", "", ""]);
     }
     
-    let _ = writeln!(lock, "{}", bid_ask_table.to_string());
+    let _ = writeln!(lock, "{}", bid_ask_table);
 }
 
 #[tokio::main]
@@ -43,6 +85,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         1 => { GRPC_SERVER_DEFAULT_PORT },
         _ => { args[1].parse::<usize>().expect("Cannot get the grpc port from command line argument") }
     };
+    //The server streams its first instrument when no symbol is given
+    let symbol = match args.len() {
+        1 | 2 => { String::new() },
+        _ => { args[2].clone() }
+    };
+    //0 lets the server send all the levels of the instrument
+    let depth = match args.len() {
+        1..=3 => { 0 },
+        _ => { args[3].parse::<u32>().expect("Cannot get the depth from command line argument") }
+    };
+    //One level per price with the amount of each venue
+    let consolidated = args.len() > 4 && args[4] == "consolidated";
     
     let mut grpc_url = GRPC_SERVER_URL.to_owned();
     grpc_url.push_str(&grpc_port.to_string());
@@ -50,16 +104,23 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut client =
         orderbook_aggregator_client::OrderbookAggregatorClient::connect(grpc_url).await?;
 
-    let mut stream = client.book_summary(Empty {}).await?.into_inner();
+    let mut stream = client.book_summary(SummaryRequest { symbol, depth, consolidated, ..Default::default() }).await?.into_inner();
 
+    //The summaries skipped by the server since the stream started, e.g. conflated while the client was slow
+    let mut missed = 0;
+    let mut last_sequence = None;
     while let Some(summary) = stream.next().await {
         if let Ok(summary) = summary {
+            if let Some(last_sequence) = last_sequence {
+                missed += summary.sequence.saturating_sub(last_sequence + 1);
+            }
+            last_sequence = Some(summary.sequence);
             //println!("GRPC client got: {:?}", summary);
             //print!("{esc}[2J{esc}[1;1H", esc = 27 as char);
             let stdout = std::io::stdout();
             let mut lock = stdout.lock();
             clear(&mut lock);
-            print_summary(&mut lock, &summary);
+            print_summary(&mut lock, &summary, missed);
         }
     }
     Ok(())
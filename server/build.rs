@@ -1,4 +1,81 @@
+const PROTO: &str = "../proto/order_book.proto";
+
+//The methods of the service: name, route name, input type, output type and whether the output is
+//streamed. BookSummary streams the summaries encoded once by the aggregator instead of encoding them
+//again for each client, the other types are the messages generated from the proto file
+const METHODS: [(&str, &str, &str, &str, bool); 4] = [
+    ("book_summary", "BookSummary", "crate::orderbook::SummaryRequest", "crate::encoded_summary::EncodedSummary", true),
+    ("book_snapshot", "BookSnapshot", "crate::orderbook::SummaryRequest", "crate::orderbook::SummarySnapshot", false),
+    ("cost_to_fill", "CostToFill", "crate::orderbook::CostToFillRequest", "crate::orderbook::CostToFillQuote", false),
+    ("cross_events", "CrossEvents", "crate::orderbook::CrossEventsRequest", "crate::orderbook::CrossEvent", true),
+];
+
 fn main () -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("../proto/order_book.proto")?;
+    //The messages come from the proto file, the service is defined below
+    tonic_build::configure()
+        .build_server(false)
+        .build_client(false)
+        .compile(&[PROTO], &["../proto"])?;
+
+    //Same service as in the proto file, the build fails when they differ
+    check_against_proto(&std::fs::read_to_string(PROTO)?)?;
+    let mut service = tonic_build::manual::Service::builder()
+        .name("OrderbookAggregator")
+        .package("orderbook");
+    for (name, route_name, input_type, output_type, streaming) in METHODS {
+        let method = tonic_build::manual::Method::builder()
+            .name(name)
+            .route_name(route_name)
+            .input_type(input_type)
+            .output_type(output_type)
+            .codec_path("tonic::codec::ProstCodec");
+        let method = match streaming {
+            true => method.server_streaming(),
+            false => method,
+        };
+        service = service.method(method.build());
+    }
+    tonic_build::manual::Builder::new().build_client(false).compile(&[service.build()]);
     Ok(())
-}
\ No newline at end of file
+}
+
+//The proto message a type stands for, EncodedSummary is a Summary already encoded
+fn proto_type(rust_type: &str) -> &str {
+    match rust_type {
+        "crate::encoded_summary::EncodedSummary" => "Summary",
+        _ => rust_type.trim_start_matches("crate::orderbook::"),
+    }
+}
+
+//Each rpc of the proto service, e.g. "rpc BookSummary(SummaryRequest) returns (stream Summary);", must
+//be one of the methods with the same types, and each method an rpc
+fn check_against_proto(proto: &str) -> Result<(), String> {
+    let service = proto
+        .split("service OrderbookAggregator {")
+        .nth(1)
+        .and_then(|service| service.split('}').next())
+        .ok_or_else(|| format!("No OrderbookAggregator service in {PROTO}"))?;
+    let mut rpcs = Vec::new();
+    for rpc in service.lines().map(str::trim).filter(|line| line.starts_with("rpc ")) {
+        let words: Vec<&str> = rpc.split(|c: char| !c.is_alphanumeric() && c != '_').filter(|word| !word.is_empty()).collect();
+        match words[..] {
+            ["rpc", route_name, input_type, "returns", "stream", output_type] => rpcs.push((route_name, input_type, output_type, true)),
+            ["rpc", route_name, input_type, "returns", output_type] => rpcs.push((route_name, input_type, output_type, false)),
+            _ => return Err(format!("Cannot read {rpc} in {PROTO}")),
+        }
+    }
+    let methods: Vec<_> = METHODS.iter()
+        .map(|(_, route_name, input_type, output_type, streaming)| (*route_name, proto_type(input_type), proto_type(output_type), *streaming))
+        .collect();
+    for rpc in &rpcs {
+        if !methods.contains(rpc) {
+            return Err(format!("The rpc {:?} of {PROTO} is not in the methods of build.rs", rpc));
+        }
+    }
+    for method in &methods {
+        if !rpcs.contains(method) {
+            return Err(format!("The method {:?} of build.rs is not an rpc of {PROTO}", method));
+        }
+    }
+    Ok(())
+}
@@ -1,46 +1,100 @@
 use tokio::sync::mpsc::Receiver;
 use crate::market_data_source::*;
-use crate::orderbook::Summary;
+use crate::orderbook::{CrossEvent, CrossState, Summary, VenueTime};
+use crate::cross_detector::CrossDetector;
 use crate::{orderbook::Level, multi_receiver_channels::MultiReceiverChannel};
+use std::borrow::Borrow;
 use std::cmp::Ordering;
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use crate::DEFAULT_DEPTH;
-use arrayvec::ArrayVec;
+use std::time::{Duration, Instant, SystemTime};
+use crate::book_validator::{BookValidator, Validation, ValidationConfig};
+use crate::encoded_summary::EncodedSummary;
+use crate::last_value_cache::LastValueCache;
+use crate::scale::Scale;
+use crate::sequence_tracker::{Sequence, SequenceMetrics, SequenceTracker};
+use crate::venue_registry::{VenueId, VenueRegistry};
 use min_max_heap::MinMaxHeap;
 
+const STALE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+//What is published for an instrument: the merged book of all the venues, along with the book of each
+//venue so a subscription can merge a subset of the venues. It is immutable and shared by all the
+//subscriptions
+#[derive(Debug, Clone)]
+pub struct BookUpdate {
+    pub summary: Summary,
+    //The summary encoded once for all the clients streaming it as it is
+    pub encoded: EncodedSummary,
+    //Indexed by venue id, the books of the stale venues are empty. Shared with the aggregator and the
+    //other updates, only the book of the venue that changed is replaced
+    pub books: Vec<Arc<OrderBook>>,
+    //All the levels of each venue, indexed by venue id, for the cost to fill quotes. None when the
+    //book of the venue is all its source holds
+    pub full_books: Vec<Option<FullBookRef>>,
+    //The tick sizes the levels of the instrument are rounded to
+    pub scale: Scale,
+    pub generated_at: SystemTime,
+}
+
+impl BookUpdate {
+    //In microseconds since the unix epoch
+    pub fn generation_time_us(&self) -> u64 {
+        unix_time_us(self.generated_at)
+    }
+
+    //All the levels of the book of a venue, read from its source when it holds more than the top
+    pub fn full_book(&self, index: usize) -> FullOrderBook {
+        match self.full_books.get(index) {
+            Some(Some(full_book)) => full_book.read(),
+            _ => self.books.get(index).map(|book| FullOrderBook::from(book.as_ref())).unwrap_or_default(),
+        }
+    }
+}
+
+//When the latest book of a venue was generated by the venue and received
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct BookTime {
+    exchange: Option<SystemTime>,
+    received: SystemTime,
+}
+
+//The channel an instrument is published to, each grpc client has a receiver
+pub type BookChannel = Arc<MultiReceiverChannel<Arc<BookUpdate>>>;
+
+//The channel the crossed markets of all the instruments are published to
+pub type CrossChannel = Arc<MultiReceiverChannel<CrossEvent>>;
+
 //The struct that will be added to the min-max-heap when merging the bids
 #[derive(Debug, PartialEq)]
 struct BidMergeEntry<'a> {
     level: &'a MarketDatSourceLevel,
-    exchange: Exchange
+    venue: VenueId,
+    //The price the level is ranked by in ticks, what selling on the venue receives after its taker fee
+    price: i64,
+    //The amount in ticks, for the levels at the same price
+    amount: i64
+}
+
+impl<'a> BidMergeEntry<'a> {
+    fn new(level: &'a MarketDatSourceLevel, venue: VenueId, registry: &VenueRegistry, scale: &Scale) -> BidMergeEntry<'a> {
+        let price = scale.adjusted_price_units(level.price, 1.0 - registry.taker_fee(venue));
+        BidMergeEntry { level, venue, price, amount: scale.amount_units(level.amount) }
+    }
 }
 
 impl Eq for BidMergeEntry<'_> {}
 
 impl PartialOrd for BidMergeEntry<'_> {
     fn partial_cmp(&self, other: &BidMergeEntry) -> Option<Ordering> {
-        let c = match self.level.price.partial_cmp(&other.level.price) {
-            Some(c) => c,
-            None => { return None; }
-        };
-        if c == Ordering::Equal {
-            let c = match self.level.amount.partial_cmp(&other.level.amount) {
-                Some(c) => c,
-                None => { return None; }
-            };
-            Some(c)
-        } else {
-            Some(c)
-        }
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for BidMergeEntry<'_> {
     fn cmp(&self, other: &BidMergeEntry) -> Ordering {
-         let c = self.level.price.total_cmp(&other.level.price);
+         let c = self.price.cmp(&other.price);
             if c == Ordering::Equal {
-                self.level.amount.total_cmp(&other.level.amount)
+                self.amount.cmp(&other.amount)
             } else {
                 c
             }
@@ -51,34 +105,33 @@ impl Ord for BidMergeEntry<'_> {
 #[derive(Debug, PartialEq)]
 struct AskMergeEntry<'a> {
     level: &'a MarketDatSourceLevel,
-    exchange: Exchange
+    venue: VenueId,
+    //The price the level is ranked by in ticks, what buying on the venue costs after its taker fee
+    price: i64,
+    //The amount in ticks, for the levels at the same price
+    amount: i64
+}
+
+impl<'a> AskMergeEntry<'a> {
+    fn new(level: &'a MarketDatSourceLevel, venue: VenueId, registry: &VenueRegistry, scale: &Scale) -> AskMergeEntry<'a> {
+        let price = scale.adjusted_price_units(level.price, 1.0 + registry.taker_fee(venue));
+        AskMergeEntry { level, venue, price, amount: scale.amount_units(level.amount) }
+    }
 }
 
 impl Eq for AskMergeEntry<'_> {}
 
 impl PartialOrd for AskMergeEntry<'_> {
     fn partial_cmp(&self, other: &AskMergeEntry) -> Option<Ordering> {
-        let c = match self.level.price.partial_cmp(&other.level.price) {
-            Some(c) => c,
-            None => { return None; }
-        };
-        if c == Ordering::Equal {
-            let c = match self.level.amount.partial_cmp(&other.level.amount) {
-                Some(c) => c,
-                None => { return None; }
-            };
-            Some(c.reverse())
-        } else {
-            Some(c)
-        }
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for AskMergeEntry<'_> {
     fn cmp(&self, other: &AskMergeEntry) -> Ordering {
-         let c = self.level.price.total_cmp(&other.level.price);
+         let c = self.price.cmp(&other.price);
             if c == Ordering::Equal {
-                self.level.amount.total_cmp(&other.level.amount).reverse()                    
+                self.amount.cmp(&other.amount).reverse()                    
             } else {
                 c
             }
@@ -87,34 +140,103 @@ impl Ord for AskMergeEntry<'_> {
 
 pub struct Aggregator {
     rx: Receiver<OrderBookSnap>,
-    mpc: Arc<Mutex<MultiReceiverChannel<Summary>>>,
-    exchange_orderbook_array: [OrderBook; Exchange::VARIANT_COUNT]
+    //The symbol of each instrument, indexed by instrument id
+    symbols: Vec<String>,
+    //The number of levels merged for each instrument, indexed by instrument id
+    depths: Vec<usize>,
+    //The tick sizes of each instrument, indexed by instrument id
+    scales: Vec<Scale>,
+    //The channel of the merged book of each instrument, indexed by instrument id
+    mpc_array: Vec<BookChannel>,
+    //The latest update of each instrument, for the snapshot requests
+    last_value_cache: Arc<LastValueCache>,
+    cross_channel: CrossChannel,
+    cross_detector: CrossDetector,
+    registry: Arc<VenueRegistry>,
+    //The latest orderbook of each venue for each instrument, indexed by instrument id then venue id
+    exchange_orderbook_array: Vec<Vec<Arc<OrderBook>>>,
+    //All the levels the sources hold, indexed the same way. Shared with the published updates
+    full_orderbook_array: Vec<Vec<Option<FullBookRef>>>,
+    //A book older than this is stale, zero disables the watchdog
+    max_book_age: Duration,
+    //When a book of each venue was last updated, None while all its books are empty. The staleness
+    //is checked per venue as all the instruments of a venue share the same connection
+    last_update_array: Vec<Option<Instant>>,
+    //The venues excluded from the merge because their book is stale
+    stale_array: Vec<bool>,
+    //The times of the latest book of each venue, indexed by instrument id then venue id. None while the
+    //book is empty
+    book_times: Vec<Vec<Option<BookTime>>>,
+    //Checks the books before they are merged, and the venues quarantined for sending invalid books
+    validator: BookValidator,
+    //Drops the books older than the latest one of their venue
+    sequence_tracker: SequenceTracker,
+    //The sequence of the latest summary published for each instrument, indexed by instrument id
+    summary_sequences: Vec<u64>,
 }
 
 impl Aggregator {
-    pub fn new (rx: Receiver<OrderBookSnap> ,mpc: Arc<Mutex<MultiReceiverChannel<Summary>>>) -> Aggregator {
-        Aggregator { rx, mpc, exchange_orderbook_array: Default::default() } 
+    pub fn new (rx: Receiver<OrderBookSnap>, symbols: Vec<String>, mpc_array: Vec<BookChannel>, last_value_cache: Arc<LastValueCache>, cross_channel: CrossChannel, registry: Arc<VenueRegistry>, max_book_age: Duration) -> Aggregator {
+        assert_eq!(symbols.len(), mpc_array.len(), "One channel is needed per instrument");
+        let exchange_orderbook_array = vec![vec![Arc::new(OrderBook::new()); registry.len()]; symbols.len()];
+        let full_orderbook_array = vec![vec![None; registry.len()]; symbols.len()];
+        let last_update_array = vec![None; registry.len()];
+        let stale_array = vec![false; registry.len()];
+        let book_times = vec![vec![None; registry.len()]; symbols.len()];
+        let cross_detector = CrossDetector::new(symbols.len());
+        let depths = vec![DEFAULT_DEPTH; symbols.len()];
+        let scales = vec![Scale::default(); symbols.len()];
+        let validator = BookValidator::new(&ValidationConfig::default(), symbols.len(), &registry);
+        let sequence_tracker = SequenceTracker::new(symbols.len(), &registry);
+        let summary_sequences = vec![0; symbols.len()];
+        Aggregator { rx, symbols, depths, scales, mpc_array, last_value_cache, cross_channel, cross_detector, registry, exchange_orderbook_array, full_orderbook_array, max_book_age, last_update_array, stale_array, book_times, validator,
+            sequence_tracker, summary_sequences }
     }
 
-    //This merge algorithm assumes that each exchange's bids are in the correct order and has the same depth
-    fn merge_bid(exchange_orderbook_array: &[OrderBook]) -> Result<ArrayVec<Level, DEFAULT_DEPTH>, String> {
+    //The number of levels merged for each instrument, DEFAULT_DEPTH unless set
+    pub fn with_depths(mut self, depths: Vec<usize>) -> Aggregator {
+        assert_eq!(self.symbols.len(), depths.len(), "One depth is needed per instrument");
+        self.depths = depths;
+        self
+    }
+
+    //The tick sizes of each instrument, 8 decimals unless set
+    pub fn with_scales(mut self, scales: Vec<Scale>) -> Aggregator {
+        assert_eq!(self.symbols.len(), scales.len(), "One scale is needed per instrument");
+        self.scales = scales;
+        self
+    }
+
+    //The rules the books are checked with, all the rules of the default ValidationConfig unless set
+    pub fn with_validator(mut self, validator: BookValidator) -> Aggregator {
+        self.validator = validator;
+        self
+    }
+
+    pub fn sequence_metrics(&self) -> Arc<SequenceMetrics> {
+        self.sequence_tracker.metrics()
+    }
+
+    //This merge algorithm assumes that each exchange's bids are in the correct order. At most depth levels are merged
+    pub fn merge_bid<B: Borrow<OrderBook>>(registry: &VenueRegistry, exchange_orderbook_array: &[B], depth: usize, scale: &Scale) -> Result<Vec<Level>, String> {
         //a min_max_heap for picking the best level
         let mut min_max_heap = MinMaxHeap::<BidMergeEntry>::new();
         // A vector that keeps track of the index of the next element in each exchange        
-        let mut exchange_index_vec: [usize; Exchange::VARIANT_COUNT] = [0; Exchange::VARIANT_COUNT];
-        //The result arrayVec
-        let mut result = ArrayVec::<Level, DEFAULT_DEPTH>::new();
+        let mut exchange_index_vec: Vec<usize> = vec![0; exchange_orderbook_array.len()];
+        //The result, at most MAX_DEPTH levels
+        let depth = depth.min(MAX_DEPTH);
+        let mut result = Vec::with_capacity(depth);
         
         //Initially put the first entry of each exchange into the array
-        for (index, book) in exchange_orderbook_array.iter().enumerate() {
+        for (index, book) in exchange_orderbook_array.iter().map(B::borrow).enumerate() {
             if !book.bids.is_empty() {
-                match num::FromPrimitive::from_usize(index) {
-                    Some(exchange) => {
-                        min_max_heap.push(BidMergeEntry{level: &book.bids[0], exchange});
+                match u16::try_from(index) {
+                    Ok(id) if registry.get(VenueId(id)).is_some() => {
+                        min_max_heap.push(BidMergeEntry::new(&book.bids[0], VenueId(id), registry, scale));
                         //Increment the index as the first element has been pushed into the value_vec
                         exchange_index_vec[index] += 1;
                     },
-                    None => { return Err("Cannot get exchange enum from index".to_string()); }
+                    _ => { return Err("Cannot get venue from index".to_string()); }
                 }
             }
         }        
@@ -123,22 +245,26 @@ impl Aggregator {
             return Ok(result);
         }
         
-        //Sort, take the first entry, and then put the next one into the vector. Repeat until result is full
-        while !result.is_full() {            
+        //Sort, take the first entry, and then put the next one into the vector. Repeat until result has depth levels,
+        //or all the levels of the exchanges are merged
+        while result.len() < depth {
             //get the first item and push to result
-            let first_item = match min_max_heap.pop_max() {
-                Some(i) => i,
-                None => { return Err("Fail to merge bid depth".to_string()); }
-            };
-            result.push(first_item.level.to_orderbook_level(first_item.exchange.to_string()));
+            let Some(first_item) = min_max_heap.pop_max() else { break; };
+            let name = registry.name(first_item.venue).unwrap_or_default();
+            let mut level = first_item.level.to_orderbook_level(name.to_string(), scale);
+            //Ranked by the fee adjusted price, the price of the venue is kept as raw_price
+            level.price_units = first_item.price;
+            level.price = scale.price(first_item.price);
+            result.push(level);
             //push the next entry from the respective exchange into value_vec.
-            let first_item_exchange_index = first_item.exchange as usize;
-            if exchange_index_vec[first_item_exchange_index] >= exchange_orderbook_array[first_item_exchange_index].bids.len() {
+            let first_item_exchange_index = first_item.venue.index();
+            let bids = &exchange_orderbook_array[first_item_exchange_index].borrow().bids;
+            if exchange_index_vec[first_item_exchange_index] >= bids.len() {
                 continue;                
             }
 
-            let next_item = BidMergeEntry { level: &exchange_orderbook_array[first_item_exchange_index].bids[exchange_index_vec[first_item_exchange_index]],
-                exchange: first_item.exchange };
+            let next_item = BidMergeEntry::new(&bids[exchange_index_vec[first_item_exchange_index]],
+                first_item.venue, registry, scale);
             min_max_heap.push(next_item);
             //update the index of the respective exchange
             exchange_index_vec[first_item_exchange_index] += 1;
@@ -147,26 +273,27 @@ impl Aggregator {
         Ok(result)
     }
 
-    //This merge algorithm assumes that each exchange's asks are in the correct order and has the same depth
-    fn merge_ask(exchange_orderbook_array: &[OrderBook]) -> Result<ArrayVec<Level, DEFAULT_DEPTH>, String> {
+    //This merge algorithm assumes that each exchange's asks are in the correct order. At most depth levels are merged
+    pub fn merge_ask<B: Borrow<OrderBook>>(registry: &VenueRegistry, exchange_orderbook_array: &[B], depth: usize, scale: &Scale) -> Result<Vec<Level>, String> {
         //Use a min max heap for picking the best entry
         let mut min_max_heap = MinMaxHeap::<AskMergeEntry>::new();
         // A vector that keeps track of the index of the next element in each exchange        
-        let mut exchange_index_vec: [usize; Exchange::VARIANT_COUNT] = [0; Exchange::VARIANT_COUNT];
-        //The result arrayVec
-        let mut result = ArrayVec::<Level, DEFAULT_DEPTH>::new();
+        let mut exchange_index_vec: Vec<usize> = vec![0; exchange_orderbook_array.len()];
+        //The result, at most MAX_DEPTH levels
+        let depth = depth.min(MAX_DEPTH);
+        let mut result = Vec::with_capacity(depth);
 
         //Initially put the first entry of each exchange into the array
-        for (index, book) in exchange_orderbook_array.iter().enumerate() {
+        for (index, book) in exchange_orderbook_array.iter().map(B::borrow).enumerate() {
             if !book.asks.is_empty() {
-                match num::FromPrimitive::from_usize(index) {
-                    Some(exchange) => {
+                match u16::try_from(index) {
+                    Ok(id) if registry.get(VenueId(id)).is_some() => {
                         //value_vec.push(MergeEntry{level: &book.asks[0], exchange});
-                        min_max_heap.push(AskMergeEntry{level: &book.asks[0], exchange});
+                        min_max_heap.push(AskMergeEntry::new(&book.asks[0], VenueId(id), registry, scale));
                         //Increment the index as the first element has been pushed into the value_vec
                         exchange_index_vec[index] += 1;
                     },
-                    None => { return Err("Cannot get exchange enum from index".to_string()); }
+                    _ => { return Err("Cannot get venue from index".to_string()); }
                 }
             }
         }        
@@ -175,22 +302,26 @@ impl Aggregator {
             return Ok(result);
         }
 
-        //Sort, take the first entry, and then pust the next one into the vector. Repeat until result is full
-        while !result.is_full() {
+        //Sort, take the first entry, and then pust the next one into the vector. Repeat until result has depth levels,
+        //or all the levels of the exchanges are merged
+        while result.len() < depth {
             //get the first item and push to result
-            let first_item = match min_max_heap.pop_min() {
-                Some(i) => i,
-                None => { return Err("Fail to merge ask depth".to_string()); }
-            };
-            result.push(first_item.level.to_orderbook_level(first_item.exchange.to_string()));
+            let Some(first_item) = min_max_heap.pop_min() else { break; };
+            let name = registry.name(first_item.venue).unwrap_or_default();
+            let mut level = first_item.level.to_orderbook_level(name.to_string(), scale);
+            //Ranked by the fee adjusted price, the price of the venue is kept as raw_price
+            level.price_units = first_item.price;
+            level.price = scale.price(first_item.price);
+            result.push(level);
             //push the next entry from the respective exchange into value_vec.
-            let first_item_exchange_index = first_item.exchange as usize;
-            if exchange_index_vec[first_item_exchange_index] >= exchange_orderbook_array[first_item_exchange_index].asks.len() {
+            let first_item_exchange_index = first_item.venue.index();
+            let asks = &exchange_orderbook_array[first_item_exchange_index].borrow().asks;
+            if exchange_index_vec[first_item_exchange_index] >= asks.len() {
                 continue;
             }
 
-            let next_item = AskMergeEntry { level: &exchange_orderbook_array[first_item_exchange_index].asks[exchange_index_vec[first_item_exchange_index]],
-                exchange: first_item.exchange };
+            let next_item = AskMergeEntry::new(&asks[exchange_index_vec[first_item_exchange_index]],
+                first_item.venue, registry, scale);
             min_max_heap.push(next_item);
             //update the index of the respective exchange
             exchange_index_vec[first_item_exchange_index] += 1;
@@ -199,68 +330,325 @@ impl Aggregator {
         Ok(result)
     }
 
-    fn merge(&mut self, order_book_snap: OrderBookSnap) -> Result<(Vec<Level>, Vec<Level>), String> {
+    fn update(&mut self, order_book_snap: OrderBookSnap, now: Instant) -> Result<(), String> {
         //First update the exchange image in the exchange_orderbook_array        
-        let order_book = &mut self.exchange_orderbook_array[order_book_snap.exchange as usize];        
-        order_book.bids = order_book_snap.order_book.bids;        
-        order_book.asks = order_book_snap.order_book.asks;        
-                
-        //let mut result = OrderBook::new();
-        let bids = match Aggregator::merge_bid(&self.exchange_orderbook_array) {
-            Ok(bids) => bids.to_vec(),
-            Err(s) => { return Err(s); } 
+        let index = order_book_snap.venue.index();
+        let books = match self.exchange_orderbook_array.get_mut(order_book_snap.instrument.index()) {
+            Some(books) => books,
+            None => { return Err(format!("Unknown instrument {:?}", order_book_snap.instrument)); }
         };
-        
-        let asks = match Aggregator::merge_ask(&self.exchange_orderbook_array) {
-            Ok(asks) => asks.to_vec(),
-            Err(s) => { return Err(s); }
+        let order_book = match books.get_mut(index) {
+            Some(order_book) => order_book,
+            None => { return Err(format!("Unknown venue {:?}", order_book_snap.venue)); }
         };
-        Ok((bids, asks))
+        self.full_orderbook_array[order_book_snap.instrument.index()][index] = order_book_snap.full_book;
+        //The book sent by the source is kept as it is, the updates published before still share the previous one
+        *order_book = Arc::new(order_book_snap.order_book);
+        self.book_times[order_book_snap.instrument.index()][index] = match order_book.bids.is_empty() && order_book.asks.is_empty() {
+            true => None,
+            false => Some(BookTime { exchange: order_book_snap.exchange_time, received: order_book_snap.received_at }),
+        };
+
+        //An empty book is sent for each instrument when the source disconnects, there is nothing to watch
+        //until it is back
+        if self.exchange_orderbook_array.iter().all(|books| books[index].bids.is_empty() && books[index].asks.is_empty()) {
+            self.last_update_array[index] = None;
+            return Ok(());
+        }
+        self.last_update_array[index] = Some(now);
+        if self.stale_array[index] {
+            self.stale_array[index] = false;
+            log::info!("{} is updating again, merged back", self.venue_name(index));
+        }
+        Ok(())
+    }
+
+    //Exclude the books not updated for max_book_age from the merge and ask their source to reconnect.
+    //The request is repeated every max_book_age until the venue is updated again. Returns true when
+    //a book has been excluded
+    fn evict_stale(&mut self, now: Instant) -> bool {
+        if self.max_book_age.is_zero() {
+            return false;
+        }
+        let mut evicted = false;
+        for index in 0..self.last_update_array.len() {
+            let last_update = match self.last_update_array[index] {
+                Some(last_update) => last_update,
+                None => { continue; }
+            };
+            if now.saturating_duration_since(last_update) < self.max_book_age {
+                continue;
+            }
+            if !self.stale_array[index] {
+                log::warn!("{} not updated for {:?}, excluded from the merge", self.venue_name(index), self.max_book_age);
+                self.stale_array[index] = true;
+                self.clear_venue(index);
+                evicted = true;
+            }
+            self.last_update_array[index] = Some(now);
+            if let Some(venue) = self.registry.get(VenueId(index as u16)) {
+                venue.reconnect.notify_one();
+            }
+        }
+        evicted
     }
 
-    pub fn merge_and_gen_summary(&mut self, order_book_snap: OrderBookSnap) -> Result<Summary, String> {        
-        let bid_ask_depth = match self.merge(order_book_snap) {
-            Ok(depth) => depth,
-            Err(e) => { return Err(e); },
+    //Check a book before it is merged, an invalid book is dropped. A venue sending invalid books of an instrument
+    //in a row is quarantined for the instrument: its book is excluded from the merge of the instrument, like the
+    //one of a stale venue. The venue is not reconnected, as its connection carries its other instruments
+    fn validate(&mut self, order_book_snap: &OrderBookSnap) -> Validation {
+        let index = order_book_snap.venue.index();
+        let quarantined = self.validator.is_quarantined(order_book_snap.instrument, order_book_snap.venue);
+        let scale = self.scales.get(order_book_snap.instrument.index()).copied().unwrap_or_default();
+        let validation = self.validator.check(order_book_snap, &scale);
+        let symbol = self.symbols.get(order_book_snap.instrument.index()).map(|s| s.as_str()).unwrap_or_default();
+        match validation {
+            Validation::Valid => {},
+            Validation::Released => {
+                log::info!("{} sent a valid {} book, out of quarantine", self.venue_name(index), symbol);
+            },
+            Validation::Rejected(rule) => {
+                //Counted in the metrics, not logged again while quarantined
+                if !quarantined {
+                    log::warn!("{} book of {} rejected: {}", symbol, self.venue_name(index), rule);
+                }
+            },
+            Validation::Quarantined(rule) => {
+                log::warn!("{} book of {} rejected: {}, quarantined until it sends a valid book", symbol, self.venue_name(index), rule);
+                self.clear_book(order_book_snap.instrument.index(), index);
+                //Nothing to watch until it sends a valid book
+                if self.exchange_orderbook_array.iter().all(|books| books[index].bids.is_empty() && books[index].asks.is_empty()) {
+                    self.last_update_array[index] = None;
+                }
+            },
+        }
+        validation
+    }
+
+    //Check the sequence of a book before it is merged, returns false when it is not newer than the latest book of
+    //its venue, e.g. a duplicate or a message replayed by the venue, which is dropped. A gap is only logged, the
+    //sources resync their book when they miss an update
+    fn check_sequence(&mut self, order_book_snap: &OrderBookSnap) -> bool {
+        let sequence = self.sequence_tracker.check(order_book_snap);
+        let symbol = self.symbols.get(order_book_snap.instrument.index()).map(|s| s.as_str()).unwrap_or_default();
+        let venue = self.venue_name(order_book_snap.venue.index());
+        match sequence {
+            Sequence::InOrder => true,
+            Sequence::Gap(missed) => {
+                log::warn!("{} book of {} after a gap of {} in its sequence", symbol, venue, missed);
+                true
+            },
+            Sequence::Stale{sequence, latest} => {
+                log::warn!("{} book {} of {} dropped, not after {}", symbol, sequence, venue, latest);
+                false
+            },
+        }
+    }
+
+    //Empty the books of a venue for all the instruments
+    fn clear_venue(&mut self, index: usize) {
+        for instrument in 0..self.symbols.len() {
+            self.clear_book(instrument, index);
+        }
+    }
+
+    fn clear_book(&mut self, instrument: usize, index: usize) {
+        self.exchange_orderbook_array[instrument][index] = Arc::new(OrderBook::new());
+        self.full_orderbook_array[instrument][index] = None;
+        self.book_times[instrument][index] = None;
+    }
+
+    //The times of the latest book of each venue of an instrument, and their age when published
+    fn venue_times(&self, instrument: InstrumentId, published: SystemTime) -> Vec<VenueTime> {
+        self.book_times[instrument.index()].iter().enumerate()
+            .filter_map(|(index, time)| time.map(|time| (index, time)))
+            .map(|(index, time)| VenueTime {
+                exchange: self.venue_name(index).to_string(),
+                exchange_time_us: time.exchange.map(unix_time_us).unwrap_or_default(),
+                received_time_us: unix_time_us(time.received),
+                book_age_us: published.duration_since(time.received).map(|d| d.as_micros() as u64).unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    fn venue_name(&self, index: usize) -> &str {
+        self.registry.name(VenueId(index as u16)).unwrap_or_default()
+    }
+
+    //The summary of the merged levels, with the spread computed exactly in ticks
+    pub fn summary(scale: &Scale, symbol: String, bids: Vec<Level>, asks: Vec<Level>, excluded_venues: Vec<String>) -> Summary {
+        //Either side can be empty while a source is reconnecting
+        let spread_units = match (asks.first(), bids.first()) {
+            (Some(ask), Some(bid)) => ask.price_units - bid.price_units,
+            _ => 0,
+        };
+        Summary{spread: scale.price(spread_units), bids, asks, excluded_venues, symbol, spread_units,
+            price_decimals: scale.price_decimals, amount_decimals: scale.amount_decimals,
+            //The times are set when the summary is published
+            ..Default::default()}
+    }
+
+    fn gen_summary(&self, instrument: InstrumentId) -> Result<Summary, String> {
+        let exchange_orderbook_array = match self.exchange_orderbook_array.get(instrument.index()) {
+            Some(books) => books,
+            None => { return Err(format!("Unknown instrument {:?}", instrument)); }
         };
-        let spread = bid_ask_depth.1[0].price - bid_ask_depth.0[0].price;        
-        let summary = Summary{spread, bids: bid_ask_depth.0, asks: bid_ask_depth.1};
+        let depth = self.depths[instrument.index()];
+        let scale = &self.scales[instrument.index()];
+        let bids = match Aggregator::merge_bid(&self.registry, exchange_orderbook_array, depth, scale) {
+            Ok(bids) => bids,
+            Err(s) => { return Err(s); } 
+        };
+        
+        let asks = match Aggregator::merge_ask(&self.registry, exchange_orderbook_array, depth, scale) {
+            Ok(asks) => asks,
+            Err(s) => { return Err(s); }
+        };
+        let excluded_venues = (0..self.stale_array.len())
+            .filter(|index| self.stale_array[*index] || self.validator.is_quarantined(instrument, VenueId(*index as u16)))
+            .map(|index| self.venue_name(index).to_string())
+            .collect();
+        let symbol = self.symbols[instrument.index()].clone();
+        let summary = Aggregator::summary(scale, symbol, bids, asks, excluded_venues);
         log::info!("{:?}", summary);
         Ok(summary)
     }
 
+
+    fn publish(&mut self, instrument: InstrumentId, summary: Result<Summary, String>) {
+        match summary {
+            Ok(mut summary) => { 
+                let generated_at = SystemTime::now();
+                self.summary_sequences[instrument.index()] += 1;
+                summary.sequence = self.summary_sequences[instrument.index()];
+                summary.publish_time_us = unix_time_us(generated_at);
+                summary.venue_times = self.venue_times(instrument, generated_at);
+                //The books are shared, not copied
+                let books = self.exchange_orderbook_array[instrument.index()].clone();
+                let full_books = self.full_orderbook_array[instrument.index()].clone();
+                let encoded = EncodedSummary::new(&summary);
+                let scale = self.scales[instrument.index()];
+                let update = Arc::new(BookUpdate{summary, encoded, books, full_books, scale, generated_at});
+                self.last_value_cache.set(instrument, update.clone());
+                self.mpc_array[instrument.index()].send(update);
+            },
+            Err(e) => { log::error!("Merging snapshot return error: {}", e); },
+        }
+    }
+
+    //Publish and log the crossed markets between the venues of an instrument that started or ended
+    fn detect_crosses(&mut self, instrument: InstrumentId, now: Instant) {
+        let books = &self.exchange_orderbook_array[instrument.index()];
+        let events = self.cross_detector.detect(instrument.index(), &self.symbols[instrument.index()], books, &self.registry,
+            &self.scales[instrument.index()], now);
+        for event in events {
+            let kind = if event.crossed { "crossed" } else { "locked" };
+            if event.state == CrossState::Started as i32 {
+                log::warn!("{}: {} bid {} {} with {} ask {}, {} available", event.symbol, event.bid_exchange, event.bid_price, kind,
+                    event.ask_exchange, event.ask_price, event.available);
+            } else {
+                log::info!("{}: {} bid no longer {} with {} ask after {}us, {} available at most", event.symbol, event.bid_exchange, kind,
+                    event.ask_exchange, event.duration_us, event.available);
+            }
+            self.cross_channel.send(event);
+        }
+    }
+
+    //An excluded venue changes the merged book of every instrument
+    fn publish_all(&mut self, now: Instant) {
+        for index in 0..self.symbols.len() {
+            let instrument = InstrumentId(index as u16);
+            let summary = self.gen_summary(instrument);
+            self.publish(instrument, summary);
+            self.detect_crosses(instrument, now);
+        }
+    }
+
+    //Merge a book received from a source, and publish the merged books it changes
+    fn handle(&mut self, order_book_snap: OrderBookSnap, now: Instant) {
+        let instrument = order_book_snap.instrument;
+        if !self.check_sequence(&order_book_snap) {
+            return;
+        }
+        match self.validate(&order_book_snap) {
+            Validation::Valid | Validation::Released => {
+                if let Err(e) = self.update(order_book_snap, now) {
+                    log::error!("Merging snapshot return error: {}", e);
+                    return;
+                }
+                //A venue excluded on the way changes the other instruments too, as on a tick
+                if self.evict_stale(now) {
+                    self.publish_all(now);
+                    return;
+                }
+                let summary = self.gen_summary(instrument);
+                self.publish(instrument, summary);
+                self.detect_crosses(instrument, now);
+            },
+            Validation::Rejected(_) => {},
+            //Without the book of the quarantined venue
+            Validation::Quarantined(_) => {
+                let summary = self.gen_summary(instrument);
+                self.publish(instrument, summary);
+                self.detect_crosses(instrument, now);
+            },
+        }
+    }
+
     pub async fn run(&mut self) {
-        while let Some(msg) = self.rx.recv().await {
-            match self.merge_and_gen_summary(msg) {
-                Ok(summary) => { 
-                    let mut mpc = self.mpc.lock().await;
-                    mpc.send(summary).await;
+        //Check the stale books even when nothing is received, so a silent venue is excluded in time
+        let check_interval = if self.max_book_age.is_zero() {
+            STALE_CHECK_INTERVAL
+        } else {
+            self.max_book_age.min(STALE_CHECK_INTERVAL)
+        };
+        let mut interval = tokio::time::interval(check_interval);
+        loop {
+            tokio::select! {
+                msg = self.rx.recv() => {
+                    match msg {
+                        Some(msg) => self.handle(msg, Instant::now()),
+                        None => { break; }
+                    };
+                },
+                _ = interval.tick() => {
+                    let now = Instant::now();
+                    if self.evict_stale(now) {
+                        self.publish_all(now);
+                    }
                 },
-                Err(e) => { log::error!("Merging snapshot return error: {}", e); },
             }
         }
     }
 }
 
-
+#[cfg(test)]
 mod test {
     use super::*;
+    use arrayvec::ArrayVec;
+    use futures_util::FutureExt;
+    use crate::book_validator::Rule;
+    use crate::multi_receiver_channels::SlowConsumerPolicy;
     //Test the BidMergeEntry PartialEq
     #[test]
     fn test_bid_merge_entry() {
-        let mut vec = Vec::new();
-        vec.push(BidMergeEntry{level: &MarketDatSourceLevel { price: 1.0, amount: 1.0 }, exchange: Exchange::Binance});
-        vec.push(BidMergeEntry{level: &MarketDatSourceLevel { price: 2.0, amount: 1.0 }, exchange: Exchange::Binance});
-        vec.push(BidMergeEntry{level: &MarketDatSourceLevel { price: 3.0, amount: 1.0 }, exchange: Exchange::Binance});
-        vec.push(BidMergeEntry{level: &MarketDatSourceLevel { price: 2.0, amount: 2.0 }, exchange: Exchange::Binance});
-        vec.push(BidMergeEntry{level: &MarketDatSourceLevel { price: 1.0, amount: 2.0 }, exchange: Exchange::Binance});
+        let (level_1_1, level_1_2, level_2_1, level_2_2, level_3_1) = (MarketDatSourceLevel::from_f64(1.0, 1.0), MarketDatSourceLevel::from_f64(1.0, 2.0),
+            MarketDatSourceLevel::from_f64(2.0, 1.0), MarketDatSourceLevel::from_f64(2.0, 2.0), MarketDatSourceLevel::from_f64(3.0, 1.0));
+        let mut vec = vec![
+            BidMergeEntry{level: &level_1_1, venue: BINANCE, price: 1, amount: 1},
+            BidMergeEntry{level: &level_2_1, venue: BINANCE, price: 2, amount: 1},
+            BidMergeEntry{level: &level_3_1, venue: BINANCE, price: 3, amount: 1},
+            BidMergeEntry{level: &level_2_2, venue: BINANCE, price: 2, amount: 2},
+            BidMergeEntry{level: &level_1_2, venue: BINANCE, price: 1, amount: 2},
+        ];
         vec.sort();
-        let mut result = Vec::new();
-        result.push(BidMergeEntry{level: &MarketDatSourceLevel { price: 1.0, amount: 1.0 }, exchange: Exchange::Binance});
-        result.push(BidMergeEntry{level: &MarketDatSourceLevel { price: 1.0, amount: 2.0 }, exchange: Exchange::Binance});
-        result.push(BidMergeEntry{level: &MarketDatSourceLevel { price: 2.0, amount: 1.0 }, exchange: Exchange::Binance});
-        result.push(BidMergeEntry{level: &MarketDatSourceLevel { price: 2.0, amount: 2.0 }, exchange: Exchange::Binance});
-        result.push(BidMergeEntry{level: &MarketDatSourceLevel { price: 3.0, amount: 1.0 }, exchange: Exchange::Binance});
+        let result = vec![
+            BidMergeEntry{level: &level_1_1, venue: BINANCE, price: 1, amount: 1},
+            BidMergeEntry{level: &level_1_2, venue: BINANCE, price: 1, amount: 2},
+            BidMergeEntry{level: &level_2_1, venue: BINANCE, price: 2, amount: 1},
+            BidMergeEntry{level: &level_2_2, venue: BINANCE, price: 2, amount: 2},
+            BidMergeEntry{level: &level_3_1, venue: BINANCE, price: 3, amount: 1},
+        ];
 
         assert_eq!(vec, result);
     }
@@ -268,64 +656,79 @@ mod test {
      //Test the AskMergeEntry PartialEq
      #[test]
      fn test_ask_merge_entry() {
-         let mut vec = Vec::new();
-         vec.push(AskMergeEntry{level: &MarketDatSourceLevel { price: 1.0, amount: 1.0 }, exchange: Exchange::Binance});
-         vec.push(AskMergeEntry{level: &MarketDatSourceLevel { price: 2.0, amount: 1.0 }, exchange: Exchange::Binance});
-         vec.push(AskMergeEntry{level: &MarketDatSourceLevel { price: 3.0, amount: 1.0 }, exchange: Exchange::Binance});
-         vec.push(AskMergeEntry{level: &MarketDatSourceLevel { price: 2.0, amount: 2.0 }, exchange: Exchange::Binance});
-         vec.push(AskMergeEntry{level: &MarketDatSourceLevel { price: 1.0, amount: 2.0 }, exchange: Exchange::Binance});
+         let (level_1_1, level_1_2, level_2_1, level_2_2, level_3_1) = (MarketDatSourceLevel::from_f64(1.0, 1.0), MarketDatSourceLevel::from_f64(1.0, 2.0),
+             MarketDatSourceLevel::from_f64(2.0, 1.0), MarketDatSourceLevel::from_f64(2.0, 2.0), MarketDatSourceLevel::from_f64(3.0, 1.0));
+         let mut vec = vec![
+             AskMergeEntry{level: &level_1_1, venue: BINANCE, price: 1, amount: 1},
+             AskMergeEntry{level: &level_2_1, venue: BINANCE, price: 2, amount: 1},
+             AskMergeEntry{level: &level_3_1, venue: BINANCE, price: 3, amount: 1},
+             AskMergeEntry{level: &level_2_2, venue: BINANCE, price: 2, amount: 2},
+             AskMergeEntry{level: &level_1_2, venue: BINANCE, price: 1, amount: 2},
+         ];
          vec.sort();
-         let mut result = Vec::new();
-         result.push(AskMergeEntry{level: &MarketDatSourceLevel { price: 1.0, amount: 2.0 }, exchange: Exchange::Binance});
-         result.push(AskMergeEntry{level: &MarketDatSourceLevel { price: 1.0, amount: 1.0 }, exchange: Exchange::Binance});
-         result.push(AskMergeEntry{level: &MarketDatSourceLevel { price: 2.0, amount: 2.0 }, exchange: Exchange::Binance});
-         result.push(AskMergeEntry{level: &MarketDatSourceLevel { price: 2.0, amount: 1.0 }, exchange: Exchange::Binance});
-         result.push(AskMergeEntry{level: &MarketDatSourceLevel { price: 3.0, amount: 1.0 }, exchange: Exchange::Binance});
+         let result = vec![
+             AskMergeEntry{level: &level_1_2, venue: BINANCE, price: 1, amount: 2},
+             AskMergeEntry{level: &level_1_1, venue: BINANCE, price: 1, amount: 1},
+             AskMergeEntry{level: &level_2_2, venue: BINANCE, price: 2, amount: 2},
+             AskMergeEntry{level: &level_2_1, venue: BINANCE, price: 2, amount: 1},
+             AskMergeEntry{level: &level_3_1, venue: BINANCE, price: 3, amount: 1},
+         ];
  
          assert_eq!(vec, result);
      }
 
     //Test the aggregator merge logic
-    const INVALID_SIZE: usize = Exchange::VARIANT_COUNT + 1;
+    const VENUE_COUNT: usize = 5;
+    const INVALID_SIZE: usize = VENUE_COUNT + 1;
+    const BINANCE: VenueId = VenueId(0);
+    const BITSTAMP: VenueId = VenueId(1);
+
+    fn registry() -> VenueRegistry {
+        let mut registry = VenueRegistry::new();
+        for exchange in [Exchange::Binance, Exchange::Bitstamp, Exchange::Kraken, Exchange::Coinbase, Exchange::Okx] {
+            registry.register(&exchange.to_string(), exchange).expect("Error");
+        }
+        registry
+    }
     
     #[test]
     fn test_merge_bid_invalid_exchange_size_1() {         
-        //Number of exchange is more than defined in the exchange enum, but empty orderbook for each exchange        
+        //Number of exchange is more than registered, but empty orderbook for each exchange        
         let exchange_orderbook_array: [OrderBook; INVALID_SIZE] = std::array::from_fn(|_|             
             OrderBook::new()
         );        
-        assert_eq!(Aggregator::merge_bid(&exchange_orderbook_array).expect("Error").len(), 0);
+        assert_eq!(Aggregator::merge_bid(&registry(), &exchange_orderbook_array, DEFAULT_DEPTH, &Scale::default()).expect("Error").len(), 0);
     }
     #[test]
     fn test_merge_bid_invalid_exchange_size_2() {         
-        //Number of exchange is more than defined in the exchange enum, add one level to each exchange
+        //Number of exchange is more than registered, add one level to each exchange
         let mut exchange_orderbook_array: [OrderBook; INVALID_SIZE] = std::array::from_fn(|_|             
             OrderBook::new()
         );
         for n in &mut exchange_orderbook_array {
-            n.bids.push(MarketDatSourceLevel {amount: 1.0, price: 1.0});
+            n.bids.push(MarketDatSourceLevel::from_f64(1.0, 1.0));
         }
-        assert_eq!(if let Err(e) = Aggregator::merge_bid(&exchange_orderbook_array) {
+        assert_eq!(if let Err(e) = Aggregator::merge_bid(&registry(), &exchange_orderbook_array, DEFAULT_DEPTH, &Scale::default()) {
             e
         } else { "".to_string() },
-         "Cannot get exchange enum from index".to_string());
+         "Cannot get venue from index".to_string());
     }
     #[test]
     fn test_merge_bid_one_order_book() {
         //Only one exchange has order book, others are empty
-        let mut exchange_orderbook_array: [OrderBook; Exchange::VARIANT_COUNT] = std::array::from_fn(|_|             
+        let mut exchange_orderbook_array: [OrderBook; VENUE_COUNT] = std::array::from_fn(|_|             
             OrderBook::new()
         );
-        let index = Exchange::Binance as usize;
-        //exchange_orderbook_array[index].bids.push(MarketDatSourceLevel {amount: 1.0, price: 1.0});
+        let index = BINANCE.index();
+        //exchange_orderbook_array[index].bids.push(MarketDatSourceLevel::from_f64(1.0, 1.0));
         let mut expected_result = ArrayVec::<Level, DEFAULT_DEPTH>::new();
 
         let mut best_price = 10.0;
         let step = 0.1;
         while !expected_result.is_full() {
-            exchange_orderbook_array[index].bids.push(MarketDatSourceLevel {amount: 1.0, price: best_price});
-            expected_result.push(Level{amount: 1.0, price: best_price, exchange: Exchange::Binance.to_string()});
-            assert_eq!(Aggregator::merge_bid(&exchange_orderbook_array).expect("Error"), expected_result);
+            exchange_orderbook_array[index].bids.push(MarketDatSourceLevel::from_f64(best_price, 1.0));
+            expected_result.push(MarketDatSourceLevel::from_f64(best_price, 1.0).to_orderbook_level("binance".to_string(), &Scale::default()));
+            assert_eq!(Aggregator::merge_bid(&registry(), &exchange_orderbook_array, DEFAULT_DEPTH, &Scale::default()).expect("Error").to_vec(), expected_result.to_vec());
             best_price -= step;
         }
     }
@@ -333,164 +736,524 @@ mod test {
     #[test]
     fn test_merge_bid_multiple_order_book_1() {
         //Same price but different amount, the entry with the higher amount will go first
-        let mut exchange_orderbook_array: [OrderBook; Exchange::VARIANT_COUNT] = std::array::from_fn(|_|             
+        let mut exchange_orderbook_array: [OrderBook; VENUE_COUNT] = std::array::from_fn(|_|             
             OrderBook::new()
         );
-        let binance_index = Exchange::Binance as usize;
-        let bitstamp_index = Exchange::Bitstamp as usize;
-        //exchange_orderbook_array[index].bids.push(MarketDatSourceLevel {amount: 1.0, price: 1.0});
+        let binance_index = BINANCE.index();
+        let bitstamp_index = BITSTAMP.index();
+        //exchange_orderbook_array[index].bids.push(MarketDatSourceLevel::from_f64(1.0, 1.0));
         let mut expected_result = ArrayVec::<Level, DEFAULT_DEPTH>::new();
 
         let mut binance_best_price = 10.0;
         let mut bitstamp_best_price = 10.0;
         let step = 0.1;
         for _ in 0..DEFAULT_DEPTH {
-            exchange_orderbook_array[binance_index].bids.push(MarketDatSourceLevel {amount: 2.0, price: binance_best_price});
-            exchange_orderbook_array[bitstamp_index].bids.push(MarketDatSourceLevel {amount: 1.0, price: bitstamp_best_price});            
+            exchange_orderbook_array[binance_index].bids.push(MarketDatSourceLevel::from_f64(binance_best_price, 2.0));
+            exchange_orderbook_array[bitstamp_index].bids.push(MarketDatSourceLevel::from_f64(bitstamp_best_price, 1.0));            
             binance_best_price -= step;
             bitstamp_best_price -= step;
         }
 
         let mut index = 0;
         while !expected_result.is_full() {
-            expected_result.push(exchange_orderbook_array[binance_index].bids[index].to_orderbook_level(Exchange::Binance.to_string()));
-            expected_result.push(exchange_orderbook_array[bitstamp_index].bids[index].to_orderbook_level(Exchange::Bitstamp.to_string()));
+            expected_result.push(exchange_orderbook_array[binance_index].bids[index].to_orderbook_level("binance".to_string(), &Scale::default()));
+            expected_result.push(exchange_orderbook_array[bitstamp_index].bids[index].to_orderbook_level("bitstamp".to_string(), &Scale::default()));
             index += 1;
         }
         
-        assert_eq!(Aggregator::merge_bid(&exchange_orderbook_array).expect("Error"), expected_result);            
+        assert_eq!(Aggregator::merge_bid(&registry(), &exchange_orderbook_array, DEFAULT_DEPTH, &Scale::default()).expect("Error").to_vec(), expected_result.to_vec());            
     }
 
     #[test]
     fn test_merge_bid_multiple_order_book_2() {
         //All with different price, higher price go first
-        let mut exchange_orderbook_array: [OrderBook; Exchange::VARIANT_COUNT] = std::array::from_fn(|_|             
+        let mut exchange_orderbook_array: [OrderBook; VENUE_COUNT] = std::array::from_fn(|_|             
             OrderBook::new()
         );
-        let binance_index = Exchange::Binance as usize;
-        let bitstamp_index = Exchange::Bitstamp as usize;
-        //exchange_orderbook_array[index].bids.push(MarketDatSourceLevel {amount: 1.0, price: 1.0});
+        let binance_index = BINANCE.index();
+        let bitstamp_index = BITSTAMP.index();
+        //exchange_orderbook_array[index].bids.push(MarketDatSourceLevel::from_f64(1.0, 1.0));
         let mut expected_result = ArrayVec::<Level, DEFAULT_DEPTH>::new();
 
         let mut binance_best_price = 21.0;
         let mut bitstamp_best_price = 20.0;
         let step = 2.0;
         for _ in 0..DEFAULT_DEPTH {
-            exchange_orderbook_array[binance_index].bids.push(MarketDatSourceLevel {amount: 1.0, price: binance_best_price});
-            exchange_orderbook_array[bitstamp_index].bids.push(MarketDatSourceLevel {amount: 1.0, price: bitstamp_best_price});            
+            exchange_orderbook_array[binance_index].bids.push(MarketDatSourceLevel::from_f64(binance_best_price, 1.0));
+            exchange_orderbook_array[bitstamp_index].bids.push(MarketDatSourceLevel::from_f64(bitstamp_best_price, 1.0));            
             binance_best_price -= step;
             bitstamp_best_price -= step;
         }
 
         let mut index = 0;
         while !expected_result.is_full() {
-            expected_result.push(exchange_orderbook_array[binance_index].bids[index].to_orderbook_level(Exchange::Binance.to_string()));
-            expected_result.push(exchange_orderbook_array[bitstamp_index].bids[index].to_orderbook_level(Exchange::Bitstamp.to_string()));
+            expected_result.push(exchange_orderbook_array[binance_index].bids[index].to_orderbook_level("binance".to_string(), &Scale::default()));
+            expected_result.push(exchange_orderbook_array[bitstamp_index].bids[index].to_orderbook_level("bitstamp".to_string(), &Scale::default()));
             index += 1;
         }
         
-        assert_eq!(Aggregator::merge_bid(&exchange_orderbook_array).expect("Error"), expected_result);            
+        assert_eq!(Aggregator::merge_bid(&registry(), &exchange_orderbook_array, DEFAULT_DEPTH, &Scale::default()).expect("Error").to_vec(), expected_result.to_vec());            
     }    
 
     #[test]
     fn test_merge_ask_invalid_exchange_size_1() {         
-        //Number of exchange is more than defined in the exchange enum, but empty orderbook for each exchange        
+        //Number of exchange is more than registered, but empty orderbook for each exchange        
         let exchange_orderbook_array: [OrderBook; INVALID_SIZE] = std::array::from_fn(|_|             
             OrderBook::new()
         );        
-        assert_eq!(Aggregator::merge_ask(&exchange_orderbook_array).expect("Error").len(), 0);
+        assert_eq!(Aggregator::merge_ask(&registry(), &exchange_orderbook_array, DEFAULT_DEPTH, &Scale::default()).expect("Error").len(), 0);
     }
     #[test]
     fn test_merge_ask_invalid_exchange_size_2() {         
-        //Number of exchange is more than defined in the exchange enum, add one level to each exchange
+        //Number of exchange is more than registered, add one level to each exchange
         let mut exchange_orderbook_array: [OrderBook; INVALID_SIZE] = std::array::from_fn(|_|             
             OrderBook::new()
         );
         for n in &mut exchange_orderbook_array {
-            n.asks.push(MarketDatSourceLevel {amount: 1.0, price: 1.0});
+            n.asks.push(MarketDatSourceLevel::from_f64(1.0, 1.0));
         }
-        assert_eq!(if let Err(e) = Aggregator::merge_ask(&exchange_orderbook_array) {
+        assert_eq!(if let Err(e) = Aggregator::merge_ask(&registry(), &exchange_orderbook_array, DEFAULT_DEPTH, &Scale::default()) {
             e
         } else { "".to_string() },
-         "Cannot get exchange enum from index".to_string());
+         "Cannot get venue from index".to_string());
     }
     #[test]
     fn test_merge_ask_one_order_book() {
         //Only one exchange has order book, others are empty
-        let mut exchange_orderbook_array: [OrderBook; Exchange::VARIANT_COUNT] = std::array::from_fn(|_|             
+        let mut exchange_orderbook_array: [OrderBook; VENUE_COUNT] = std::array::from_fn(|_|             
             OrderBook::new()
         );
-        let index = Exchange::Binance as usize;
-        //exchange_orderbook_array[index].bids.push(MarketDatSourceLevel {amount: 1.0, price: 1.0});
+        let index = BINANCE.index();
+        //exchange_orderbook_array[index].bids.push(MarketDatSourceLevel::from_f64(1.0, 1.0));
         let mut expected_result = ArrayVec::<Level, DEFAULT_DEPTH>::new();
 
         let mut best_price = 10.0;
         let step = 0.1;
         while !expected_result.is_full() {
-            exchange_orderbook_array[index].asks.push(MarketDatSourceLevel {amount: 1.0, price: best_price});
-            expected_result.push(Level{amount: 1.0, price: best_price, exchange: Exchange::Binance.to_string()});
-            assert_eq!(Aggregator::merge_ask(&exchange_orderbook_array).expect("Error"), expected_result);
+            exchange_orderbook_array[index].asks.push(MarketDatSourceLevel::from_f64(best_price, 1.0));
+            expected_result.push(MarketDatSourceLevel::from_f64(best_price, 1.0).to_orderbook_level("binance".to_string(), &Scale::default()));
+            assert_eq!(Aggregator::merge_ask(&registry(), &exchange_orderbook_array, DEFAULT_DEPTH, &Scale::default()).expect("Error").to_vec(), expected_result.to_vec());
             best_price += step;
         }
     }
     #[test]
     fn test_merge_ask_multiple_order_book_1() {
         //Same price but different amount, the entry with the higher amount will go first
-        let mut exchange_orderbook_array: [OrderBook; Exchange::VARIANT_COUNT] = std::array::from_fn(|_|             
+        let mut exchange_orderbook_array: [OrderBook; VENUE_COUNT] = std::array::from_fn(|_|             
             OrderBook::new()
         );
-        let binance_index = Exchange::Binance as usize;
-        let bitstamp_index = Exchange::Bitstamp as usize;
-        //exchange_orderbook_array[index].bids.push(MarketDatSourceLevel {amount: 1.0, price: 1.0});
+        let binance_index = BINANCE.index();
+        let bitstamp_index = BITSTAMP.index();
+        //exchange_orderbook_array[index].bids.push(MarketDatSourceLevel::from_f64(1.0, 1.0));
         let mut expected_result = ArrayVec::<Level, DEFAULT_DEPTH>::new();
 
         let mut binance_best_price = 10.0;
         let mut bitstamp_best_price = 10.0;
         let step = 0.1;
         for _ in 0..DEFAULT_DEPTH {
-            exchange_orderbook_array[binance_index].asks.push(MarketDatSourceLevel {amount: 2.0, price: binance_best_price});
-            exchange_orderbook_array[bitstamp_index].asks.push(MarketDatSourceLevel {amount: 1.0, price: bitstamp_best_price});            
+            exchange_orderbook_array[binance_index].asks.push(MarketDatSourceLevel::from_f64(binance_best_price, 2.0));
+            exchange_orderbook_array[bitstamp_index].asks.push(MarketDatSourceLevel::from_f64(bitstamp_best_price, 1.0));            
             binance_best_price += step;
             bitstamp_best_price += step;
         }
 
         let mut index = 0;
         while !expected_result.is_full() {
-            expected_result.push(exchange_orderbook_array[binance_index].asks[index].to_orderbook_level(Exchange::Binance.to_string()));
-            expected_result.push(exchange_orderbook_array[bitstamp_index].asks[index].to_orderbook_level(Exchange::Bitstamp.to_string()));
+            expected_result.push(exchange_orderbook_array[binance_index].asks[index].to_orderbook_level("binance".to_string(), &Scale::default()));
+            expected_result.push(exchange_orderbook_array[bitstamp_index].asks[index].to_orderbook_level("bitstamp".to_string(), &Scale::default()));
             index += 1;
         }
         
-        assert_eq!(Aggregator::merge_ask(&exchange_orderbook_array).expect("Error"), expected_result);            
+        assert_eq!(Aggregator::merge_ask(&registry(), &exchange_orderbook_array, DEFAULT_DEPTH, &Scale::default()).expect("Error").to_vec(), expected_result.to_vec());            
     }
     #[test]
     fn test_merge_ask_multiple_order_book_2() {
         //All with different price, higher price go first
-        let mut exchange_orderbook_array: [OrderBook; Exchange::VARIANT_COUNT] = std::array::from_fn(|_|             
+        let mut exchange_orderbook_array: [OrderBook; VENUE_COUNT] = std::array::from_fn(|_|             
             OrderBook::new()
         );
-        let binance_index = Exchange::Binance as usize;
-        let bitstamp_index = Exchange::Bitstamp as usize;
-        //exchange_orderbook_array[index].bids.push(MarketDatSourceLevel {amount: 1.0, price: 1.0});
+        let binance_index = BINANCE.index();
+        let bitstamp_index = BITSTAMP.index();
+        //exchange_orderbook_array[index].bids.push(MarketDatSourceLevel::from_f64(1.0, 1.0));
         let mut expected_result = ArrayVec::<Level, DEFAULT_DEPTH>::new();
 
         let mut binance_best_price = 21.0;
         let mut bitstamp_best_price = 20.0;
         let step = 2.0;
         for _ in 0..DEFAULT_DEPTH {
-            exchange_orderbook_array[binance_index].asks.push(MarketDatSourceLevel {amount: 1.0, price: binance_best_price});
-            exchange_orderbook_array[bitstamp_index].asks.push(MarketDatSourceLevel {amount: 1.0, price: bitstamp_best_price});            
+            exchange_orderbook_array[binance_index].asks.push(MarketDatSourceLevel::from_f64(binance_best_price, 1.0));
+            exchange_orderbook_array[bitstamp_index].asks.push(MarketDatSourceLevel::from_f64(bitstamp_best_price, 1.0));            
             binance_best_price += step;
             bitstamp_best_price += step;
         }
 
         let mut index = 0;
         while !expected_result.is_full() {
-            expected_result.push(exchange_orderbook_array[bitstamp_index].asks[index].to_orderbook_level(Exchange::Bitstamp.to_string()));
-            expected_result.push(exchange_orderbook_array[binance_index].asks[index].to_orderbook_level(Exchange::Binance.to_string()));            
+            expected_result.push(exchange_orderbook_array[bitstamp_index].asks[index].to_orderbook_level("bitstamp".to_string(), &Scale::default()));
+            expected_result.push(exchange_orderbook_array[binance_index].asks[index].to_orderbook_level("binance".to_string(), &Scale::default()));            
             index += 1;
         }
         
-        assert_eq!(Aggregator::merge_ask(&exchange_orderbook_array).expect("Error"), expected_result);            
+        assert_eq!(Aggregator::merge_ask(&registry(), &exchange_orderbook_array, DEFAULT_DEPTH, &Scale::default()).expect("Error").to_vec(), expected_result.to_vec());            
+    }
+
+    #[test]
+    fn test_merge_bid_same_exchange_twice() {
+        //Two venues on the same exchange are merged like any other venues
+        let mut registry = registry();
+        let backup = registry.register("binance_backup", Exchange::Binance).expect("Error");
+        let mut exchange_orderbook_array = vec![OrderBook::new(); registry.len()];
+        exchange_orderbook_array[BINANCE.index()].bids.push(MarketDatSourceLevel::from_f64(10.0, 1.0));
+        exchange_orderbook_array[backup.index()].bids.push(MarketDatSourceLevel::from_f64(11.0, 1.0));
+
+        let result = Aggregator::merge_bid(&registry, &exchange_orderbook_array, DEFAULT_DEPTH, &Scale::default()).expect("Error");
+        assert_eq!(result.to_vec(), vec![
+            MarketDatSourceLevel::from_f64(11.0, 1.0).to_orderbook_level("binance_backup".to_string(), &Scale::default()),
+            MarketDatSourceLevel::from_f64(10.0, 1.0).to_orderbook_level("binance".to_string(), &Scale::default()),
+        ]);
+    }
+
+    #[test]
+    fn test_merge_depth() {
+        //More levels than the default depth, up to MAX_DEPTH
+        let registry = registry();
+        let mut books = vec![OrderBook::new(); registry.len()];
+        for n in 0..MAX_DEPTH / 2 + 1 {
+            for venue in [BINANCE, BITSTAMP] {
+                books[venue.index()].bids.push(MarketDatSourceLevel::from_f64(100.0 - n as f64, 1.0));
+                books[venue.index()].asks.push(MarketDatSourceLevel::from_f64(101.0 + n as f64, 1.0));
+            }
+        }
+        assert_eq!(Aggregator::merge_bid(&registry, &books, 3, &Scale::default()).expect("Error").len(), 3);
+        assert_eq!(Aggregator::merge_ask(&registry, &books, 3, &Scale::default()).expect("Error").len(), 3);
+        assert_eq!(Aggregator::merge_bid(&registry, &books, MAX_DEPTH, &Scale::default()).expect("Error").len(), MAX_DEPTH);
+        assert_eq!(Aggregator::merge_ask(&registry, &books, MAX_DEPTH + 1, &Scale::default()).expect("Error").len(), MAX_DEPTH);
+    }
+
+    const ETHBTC: InstrumentId = InstrumentId(0);
+    const BTCUSDT: InstrumentId = InstrumentId(1);
+
+    fn snap(venue: VenueId, instrument: InstrumentId, price: f64) -> OrderBookSnap {
+        let mut order_book_snap = OrderBookSnap::new(venue, instrument);
+        order_book_snap.order_book.bids.push(MarketDatSourceLevel::from_f64(price, 1.0));
+        order_book_snap.order_book.asks.push(MarketDatSourceLevel::from_f64(price + 1.0, 1.0));
+        order_book_snap
+    }
+
+    fn aggregator(registry: Arc<VenueRegistry>) -> Aggregator {
+        let (_tx, rx) = tokio::sync::mpsc::channel(1);
+        let symbols = vec!["ethbtc".to_string(), "btcusdt".to_string()];
+        let mpc_array = symbols.iter().map(|_| Arc::new(MultiReceiverChannel::new(10))).collect();
+        let last_value_cache = Arc::new(LastValueCache::new(symbols.len()));
+        let cross_channel = Arc::new(MultiReceiverChannel::new(10));
+        Aggregator::new(rx, symbols, mpc_array, last_value_cache, cross_channel, registry, Duration::from_secs(1))
+    }
+
+    #[tokio::test]
+    async fn test_cross_events_published() {
+        let mut aggregator = aggregator(Arc::new(registry()));
+        let mut rx = aggregator.cross_channel.create_receiver(SlowConsumerPolicy::DropOldest);
+        let now = Instant::now();
+        aggregator.update(snap(BINANCE, ETHBTC, 10.0), now).expect("Error");
+        aggregator.update(snap(BITSTAMP, ETHBTC, 12.0), now).expect("Error");
+        aggregator.detect_crosses(ETHBTC, now);
+        aggregator.detect_crosses(BTCUSDT, now);
+
+        //The bitstamp bid 12 is above the binance ask 11
+        let event = rx.try_recv().expect("Error");
+        assert_eq!(event.symbol, "ethbtc");
+        assert_eq!(event.state, CrossState::Started as i32);
+        assert_eq!((event.bid_exchange.as_str(), event.ask_exchange.as_str()), ("bitstamp", "binance"));
+        assert!(rx.try_recv().is_none());
+
+        //A stale venue ends the cross
+        aggregator.evict_stale(now + Duration::from_secs(2));
+        aggregator.detect_crosses(ETHBTC, now + Duration::from_secs(2));
+        let event = rx.try_recv().expect("Error");
+        assert_eq!(event.state, CrossState::Ended as i32);
+        assert_eq!(event.duration_us, 2_000_000);
+    }
+
+    #[test]
+    fn test_merge_fee_adjusted() {
+        let mut registry = registry();
+        //10 bps on binance, no fee on bitstamp
+        registry.set_taker_fee(BINANCE, 0.001);
+        let mut books = vec![OrderBook::new(); registry.len()];
+        books[BINANCE.index()].bids.push(MarketDatSourceLevel::from_f64(100.0, 1.0));
+        books[BINANCE.index()].asks.push(MarketDatSourceLevel::from_f64(101.0, 1.0));
+        books[BITSTAMP.index()].bids.push(MarketDatSourceLevel::from_f64(99.95, 2.0));
+        books[BITSTAMP.index()].asks.push(MarketDatSourceLevel::from_f64(101.05, 2.0));
+
+        //Binance has the best raw prices but not after its fee
+        let bids = Aggregator::merge_bid(&registry, &books, DEFAULT_DEPTH, &Scale::default()).expect("Error").to_vec();
+        let asks = Aggregator::merge_ask(&registry, &books, DEFAULT_DEPTH, &Scale::default()).expect("Error").to_vec();
+        assert_eq!(bids.iter().map(|l| l.exchange.as_str()).collect::<Vec<_>>(), vec!["bitstamp", "binance"]);
+        assert_eq!(asks.iter().map(|l| l.exchange.as_str()).collect::<Vec<_>>(), vec!["bitstamp", "binance"]);
+        //Exact once rounded to the ticks of the instrument
+        assert_eq!(bids[1].price, 99.9);
+        assert_eq!(bids[1].price_units, 9_990_000_000);
+        assert_eq!(bids[1].raw_price, 100.0);
+        assert_eq!(bids[1].raw_price_units, 10_000_000_000);
+        assert_eq!(asks[1].price, 101.101);
+        assert_eq!(asks[1].raw_price, 101.0);
+        assert_eq!(bids[0].price, bids[0].raw_price);
+        let summary = Aggregator::summary(&Scale::default(), "ethbtc".to_string(), bids, asks, Vec::new());
+        assert_eq!(summary.spread_units, 110_000_000);
+        assert_eq!(summary.spread, 1.1);
+    }
+
+    #[test]
+    fn test_merge_per_instrument() {
+        let mut aggregator = aggregator(Arc::new(registry()));
+        let now = Instant::now();
+        aggregator.update(snap(BINANCE, ETHBTC, 10.0), now).expect("Error");
+        aggregator.update(snap(BITSTAMP, BTCUSDT, 20000.0), now).expect("Error");
+        aggregator.update(snap(BINANCE, BTCUSDT, 20001.0), now).expect("Error");
+        assert!(aggregator.update(snap(BINANCE, InstrumentId(2), 1.0), now).is_err());
+
+        let summary = aggregator.gen_summary(ETHBTC).expect("Error");
+        assert_eq!(summary.symbol, "ethbtc");
+        assert_eq!(summary.bids, vec![MarketDatSourceLevel::from_f64(10.0, 1.0).to_orderbook_level("binance".to_string(), &Scale::default())]);
+        let summary = aggregator.gen_summary(BTCUSDT).expect("Error");
+        assert_eq!(summary.symbol, "btcusdt");
+        assert_eq!(summary.bids, vec![
+            MarketDatSourceLevel::from_f64(20001.0, 1.0).to_orderbook_level("binance".to_string(), &Scale::default()),
+            MarketDatSourceLevel::from_f64(20000.0, 1.0).to_orderbook_level("bitstamp".to_string(), &Scale::default()),
+        ]);
+        //Each instrument has its own depth
+        aggregator.depths[BTCUSDT.index()] = 1;
+        assert_eq!(aggregator.gen_summary(BTCUSDT).expect("Error").bids.len(), 1);
+
+        //The venue is watched until all its books are empty
+        aggregator.update(OrderBookSnap::new(BINANCE, ETHBTC), now).expect("Error");
+        assert!(aggregator.last_update_array[BINANCE.index()].is_some());
+        aggregator.update(OrderBookSnap::new(BINANCE, BTCUSDT), now).expect("Error");
+        assert!(aggregator.last_update_array[BINANCE.index()].is_none());
+    }
+
+    #[test]
+    fn test_quarantine_invalid_books() {
+        let registry = Arc::new(registry());
+        let mut aggregator = aggregator(registry.clone());
+        let now = Instant::now();
+        aggregator.update(snap(BINANCE, ETHBTC, 10.0), now).expect("Error");
+        aggregator.update(snap(BINANCE, BTCUSDT, 20000.0), now).expect("Error");
+        //The bid of binance above its own ask
+        let crossed = || {
+            let mut order_book_snap = snap(BINANCE, ETHBTC, 10.0);
+            order_book_snap.order_book.asks[0].price = "9".parse().expect("Error");
+            order_book_snap
+        };
+        assert_eq!(aggregator.validate(&crossed()), Validation::Rejected(Rule::Crossed));
+        //The previous book is kept
+        assert_eq!(aggregator.gen_summary(ETHBTC).expect("Error").bids.len(), 1);
+        //The valid books of another instrument don't reset the count
+        assert_eq!(aggregator.validate(&snap(BINANCE, BTCUSDT, 20000.0)), Validation::Valid);
+        assert_eq!(aggregator.validate(&crossed()), Validation::Rejected(Rule::Crossed));
+        assert_eq!(aggregator.validate(&crossed()), Validation::Quarantined(Rule::Crossed));
+        //Not reconnected, the connection of the venue carries its other instruments
+        assert!(registry.get(BINANCE).expect("Error").reconnect.notified().now_or_never().is_none());
+        //Only the book of the instrument is excluded
+        let summary = aggregator.gen_summary(ETHBTC).expect("Error");
+        assert!(summary.bids.is_empty());
+        assert_eq!(summary.excluded_venues, vec!["binance".to_string()]);
+        let summary = aggregator.gen_summary(BTCUSDT).expect("Error");
+        assert_eq!(summary.bids.len(), 1);
+        assert!(summary.excluded_venues.is_empty());
+        assert!(aggregator.last_update_array[BINANCE.index()].is_some());
+        assert_eq!(aggregator.validate(&snap(BINANCE, BTCUSDT, 20000.0)), Validation::Valid);
+        assert!(aggregator.validator.is_quarantined(ETHBTC, BINANCE));
+
+        //Merged again once it sends a valid book
+        assert_eq!(aggregator.validate(&snap(BINANCE, ETHBTC, 10.0)), Validation::Released);
+        aggregator.update(snap(BINANCE, ETHBTC, 10.0), now).expect("Error");
+        let summary = aggregator.gen_summary(ETHBTC).expect("Error");
+        assert_eq!(summary.bids.len(), 1);
+        assert!(summary.excluded_venues.is_empty());
+    }
+
+    #[test]
+    fn test_out_of_order_books() {
+        let mut aggregator = aggregator(Arc::new(registry()));
+        let sequenced = |previous_sequence, sequence| {
+            let mut order_book_snap = snap(BINANCE, ETHBTC, 10.0);
+            order_book_snap.previous_sequence = previous_sequence;
+            order_book_snap.sequence = Some(sequence);
+            order_book_snap
+        };
+        assert!(aggregator.check_sequence(&sequenced(None, 10)));
+        //Dropped, the newer book is kept
+        assert!(!aggregator.check_sequence(&sequenced(None, 9)));
+        assert!(!aggregator.check_sequence(&sequenced(None, 10)));
+        //Merged after a gap
+        assert!(aggregator.check_sequence(&sequenced(Some(15), 16)));
+        //The other venues and instruments are not affected
+        assert!(aggregator.check_sequence(&snap(BITSTAMP, ETHBTC, 10.0)));
+        let metrics = aggregator.sequence_metrics();
+        assert_eq!((metrics.stale(BINANCE), metrics.gaps(BINANCE)), (2, 1));
+        assert_eq!((metrics.stale(BITSTAMP), metrics.gaps(BITSTAMP)), (0, 0));
+
+        //Each instrument has its own sequence of summaries
+        for _ in 0..2 {
+            let summary = aggregator.gen_summary(ETHBTC);
+            aggregator.publish(ETHBTC, summary);
+        }
+        let summary = aggregator.gen_summary(BTCUSDT);
+        aggregator.publish(BTCUSDT, summary);
+        assert_eq!(aggregator.last_value_cache.get(ETHBTC).expect("Error").summary.sequence, 2);
+        assert_eq!(aggregator.last_value_cache.get(BTCUSDT).expect("Error").summary.sequence, 1);
+        //Not increased when nothing is published
+        aggregator.publish(ETHBTC, Err("Error".to_string()));
+        assert_eq!(aggregator.summary_sequences[ETHBTC.index()], 2);
+    }
+
+    #[test]
+    fn test_publish_times() {
+        let mut aggregator = aggregator(Arc::new(registry()));
+        let received_at = SystemTime::now() - Duration::from_millis(5);
+        let mut order_book_snap = snap(BINANCE, ETHBTC, 10.0);
+        order_book_snap.exchange_time = Some(from_unix_us(1_000));
+        order_book_snap.received_at = received_at;
+        aggregator.update(order_book_snap, Instant::now()).expect("Error");
+        aggregator.update(snap(BITSTAMP, BTCUSDT, 20000.0), Instant::now()).expect("Error");
+        let summary = aggregator.gen_summary(ETHBTC);
+        aggregator.publish(ETHBTC, summary);
+
+        let cached = aggregator.last_value_cache.get(ETHBTC).expect("Error");
+        let summary = &cached.summary;
+        assert_eq!(summary.publish_time_us, cached.generation_time_us());
+        //Only the venues with a book of the instrument
+        assert_eq!(summary.venue_times.len(), 1);
+        let time = &summary.venue_times[0];
+        assert_eq!(time.exchange, "binance");
+        assert_eq!(time.exchange_time_us, 1_000);
+        assert_eq!(time.received_time_us, unix_time_us(received_at));
+        assert!(time.book_age_us >= 5_000);
+        assert!((summary.publish_time_us - time.received_time_us).abs_diff(time.book_age_us) <= 1);
+
+        //An empty book has no time
+        aggregator.update(OrderBookSnap::new(BINANCE, ETHBTC), Instant::now()).expect("Error");
+        assert!(aggregator.venue_times(ETHBTC, SystemTime::now()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_publish_to_cache() {
+        let mut aggregator = aggregator(Arc::new(registry()));
+        let mut rx = aggregator.mpc_array[BTCUSDT.index()].create_receiver(SlowConsumerPolicy::DropOldest);
+        assert!(aggregator.last_value_cache.get(BTCUSDT).is_none());
+
+        aggregator.handle(snap(BINANCE, BTCUSDT, 20000.0), Instant::now());
+        let cached = aggregator.last_value_cache.get(BTCUSDT).expect("Error");
+        assert_eq!(cached.summary.bids.len(), 1);
+        assert_eq!(cached.books[BINANCE.index()].bids.len(), 1);
+        assert!(Arc::ptr_eq(&cached, &rx.recv().await.expect("Error")));
+        assert!(aggregator.last_value_cache.get(ETHBTC).is_none());
+
+        //The full book of the source is published along with its top, and only read when needed
+        let mut order_book_snap = snap(BITSTAMP, BTCUSDT, 20000.0);
+        let full_book = FullOrderBook {
+            bids: (0..DEFAULT_DEPTH * 2).map(|n| MarketDatSourceLevel::from_f64(20000.0 - n as f64, 1.0)).collect(),
+            asks: Vec::new(),
+        };
+        let source_books = Arc::new(std::sync::Mutex::new(vec![FullOrderBook::default(), full_book.clone()]));
+        order_book_snap.full_book = Some(FullBookRef::shared(&source_books, BTCUSDT, |book: &FullOrderBook| Some(book.clone())));
+        let previous = aggregator.last_value_cache.get(BTCUSDT).expect("Error");
+        aggregator.handle(order_book_snap, Instant::now());
+        let cached = aggregator.last_value_cache.get(BTCUSDT).expect("Error");
+        //Only the book of bitstamp is replaced, the one of binance is shared rather than copied
+        assert!(Arc::ptr_eq(&cached.books[BINANCE.index()], &previous.books[BINANCE.index()]));
+        assert!(!Arc::ptr_eq(&cached.books[BITSTAMP.index()], &previous.books[BITSTAMP.index()]));
+        assert_eq!(cached.full_book(BITSTAMP.index()), full_book);
+        assert_eq!(cached.full_book(BINANCE.index()).bids.len(), 1);
+        source_books.lock().expect("Error")[BTCUSDT.index()].bids.truncate(1);
+        assert_eq!(cached.full_book(BITSTAMP.index()).bids.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stale_venue_excluded() {
+        let registry = Arc::new(registry());
+        let mut aggregator = aggregator(registry.clone());
+        let reconnect = registry.get(BINANCE).expect("Error").reconnect.clone();
+
+        let start = Instant::now();
+        aggregator.update(snap(BINANCE, ETHBTC, 10.0), start).expect("Error");
+        aggregator.update(snap(BINANCE, BTCUSDT, 20000.0), start).expect("Error");
+        aggregator.update(snap(BITSTAMP, ETHBTC, 9.0), start + Duration::from_millis(600)).expect("Error");
+        assert!(!aggregator.evict_stale(start + Duration::from_millis(900)));
+        assert_eq!(aggregator.gen_summary(ETHBTC).expect("Error").bids.len(), 2);
+
+        //Binance is not updated for 1s, Bitstamp only for 600ms
+        assert!(aggregator.evict_stale(start + Duration::from_millis(1200)));
+        let summary = aggregator.gen_summary(ETHBTC).expect("Error");
+        assert_eq!(summary.bids, vec![MarketDatSourceLevel::from_f64(9.0, 1.0).to_orderbook_level("bitstamp".to_string(), &Scale::default())]);
+        assert_eq!(summary.excluded_venues, vec!["binance".to_string()]);
+        //The books of all the instruments of the venue are excluded
+        assert!(aggregator.gen_summary(BTCUSDT).expect("Error").bids.is_empty());
+        //The signal is kept until the source waits for it
+        tokio::time::timeout(Duration::from_secs(1), reconnect.notified()).await.expect("Not asked to reconnect");
+        //Already excluded
+        assert!(!aggregator.evict_stale(start + Duration::from_millis(1300)));
+
+        aggregator.update(snap(BINANCE, ETHBTC, 10.0), start + Duration::from_millis(1400)).expect("Error");
+        let summary = aggregator.gen_summary(ETHBTC).expect("Error");
+        assert_eq!(summary.bids.len(), 2);
+        assert!(summary.excluded_venues.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stale_venue_excluded_on_message() {
+        let mut aggregator = aggregator(Arc::new(registry()));
+        let mut rx = aggregator.mpc_array[BTCUSDT.index()].create_receiver(SlowConsumerPolicy::DropOldest);
+        let start = Instant::now();
+        aggregator.handle(snap(BINANCE, BTCUSDT, 20000.0), start);
+        aggregator.handle(snap(BINANCE, ETHBTC, 10.0), start);
+        assert_eq!(rx.try_recv().expect("Not published").summary.bids.len(), 1);
+
+        //A book of another instrument excludes binance, the books of btcusdt are published without it
+        aggregator.handle(snap(BITSTAMP, ETHBTC, 9.0), start + Duration::from_millis(1200));
+        let update = rx.try_recv().expect("Not published");
+        assert!(update.summary.bids.is_empty());
+        assert_eq!(update.summary.excluded_venues, vec!["binance".to_string()]);
+        let cached = aggregator.last_value_cache.get(BTCUSDT).expect("Error");
+        assert!(Arc::ptr_eq(&cached, &update));
+        assert!(cached.full_book(BINANCE.index()).bids.is_empty());
+        let summary = &aggregator.last_value_cache.get(ETHBTC).expect("Error").summary;
+        assert_eq!(summary.bids, vec![MarketDatSourceLevel::from_f64(9.0, 1.0).to_orderbook_level("bitstamp".to_string(), &Scale::default())]);
+    }
+
+    //Send the books of 5 venues of 2 instruments through the channel of the sources, then merge and publish them the
+    //way the aggregator does, at depth 10 and MAX_DEPTH.
+    //Run with: cargo test --release -p server bench_merge_and_publish -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_merge_and_publish() {
+        const UPDATES: usize = 100_000;
+        for depth in [DEFAULT_DEPTH, MAX_DEPTH] {
+            let (tx, rx) = tokio::sync::mpsc::channel(1);
+            let symbols = vec!["ethbtc".to_string(), "btcusdt".to_string()];
+            let mpc_array = symbols.iter().map(|_| Arc::new(MultiReceiverChannel::new(10))).collect();
+            let last_value_cache = Arc::new(LastValueCache::new(symbols.len()));
+            let cross_channel = Arc::new(MultiReceiverChannel::new(10));
+            let mut aggregator = Aggregator::new(rx, symbols, mpc_array, last_value_cache, cross_channel, Arc::new(registry()), Duration::ZERO)
+                .with_depths(vec![depth; 2]);
+            //The book of a venue, a source sends the top of it with each update
+            let mut book = OrderBook::new();
+            for n in 0..depth {
+                book.bids.push(MarketDatSourceLevel::from_f64(1000.0 - n as f64, 1.0));
+                book.asks.push(MarketDatSourceLevel::from_f64(1001.0 + n as f64, 1.0));
+            }
+
+            let start = Instant::now();
+            for n in 0..UPDATES {
+                let mut order_book_snap = OrderBookSnap::new(VenueId((n % VENUE_COUNT) as u16), InstrumentId((n / VENUE_COUNT % 2) as u16));
+                order_book_snap.order_book = book.top(depth);
+                tx.try_send(order_book_snap).expect("Error");
+                let order_book_snap = aggregator.rx.try_recv().expect("Error");
+                aggregator.handle(order_book_snap, Instant::now());
+            }
+            let elapsed = start.elapsed();
+            println!("depth {:>3}: {:?} per update, {} bytes per book sent", depth, elapsed / UPDATES as u32, std::mem::size_of::<OrderBookSnap>());
+        }
     }
 }
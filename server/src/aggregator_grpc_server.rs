@@ -1,49 +1,349 @@
-use crate::{orderbook::{Summary, orderbook_aggregator_server::{OrderbookAggregator}, Empty}};
-use tokio::sync::{mpsc, Mutex};
+use crate::{orderbook::{orderbook_aggregator_server::{OrderbookAggregator}, CostToFillQuote, CostToFillRequest, CrossEvent, CrossEventsRequest, SummaryRequest, SummarySnapshot}};
+use crate::encoded_summary::EncodedSummary;
+use tokio::sync::mpsc::{self, Sender};
+use tokio::time::Instant;
 use tonic::{Request, Response, Status};
 use tokio_stream::wrappers::ReceiverStream;
 use std::sync::Arc;
-use crate::multi_receiver_channels::MultiReceiverChannel;
+use crate::aggregator::{BookChannel, BookUpdate, CrossChannel};
+use crate::last_value_cache::LastValueCache;
+use crate::multi_receiver_channels::{Receiver, SlowConsumerPolicy};
+use crate::cost_to_fill::cost_to_fill;
+use crate::summary_view::{instrument_id, SummaryView};
+use crate::venue_registry::VenueRegistry;
 
-const GRPC_BUFFER_SIZE: usize = 1000;
+//The updates waiting to be sent to a grpc client. A client slower than the updates is left behind in its
+//receiver, where the slow consumer policy applies, rather than in this buffer
+const FORWARD_CAPACITY: usize = 1;
 
 pub struct OrderBookAggregatorService {
-    mpc: Arc<Mutex<MultiReceiverChannel<Summary>>>,
+    //The symbol of each instrument, indexed by instrument id
+    symbols: Vec<String>,
+    //The number of levels merged for each instrument, indexed by instrument id
+    depths: Vec<usize>,
+    //The channel of the merged book of each instrument, indexed by instrument id
+    mpc_array: Vec<BookChannel>,
+    last_value_cache: Arc<LastValueCache>,
+    //The crossed markets of all the instruments
+    cross_channel: CrossChannel,
+    registry: Arc<VenueRegistry>,
+    //Used when the client doesn't ask for a policy
+    default_policy: SlowConsumerPolicy,
 }
 
 impl OrderBookAggregatorService {
-    pub fn new(mpc: Arc<Mutex<MultiReceiverChannel<Summary>>>) -> OrderBookAggregatorService {
-        OrderBookAggregatorService { mpc }
+    pub fn new(symbols: Vec<String>, depths: Vec<usize>, mpc_array: Vec<BookChannel>, last_value_cache: Arc<LastValueCache>, cross_channel: CrossChannel,
+        registry: Arc<VenueRegistry>, default_policy: SlowConsumerPolicy) -> OrderBookAggregatorService {
+        OrderBookAggregatorService { symbols, depths, mpc_array, last_value_cache, cross_channel, registry, default_policy }
+    }
+}
+
+//Forward the updates of an instrument to a client. The latest update is sent first, so the client
+//doesn't have to wait for the next update
+async fn stream_summaries(view: SummaryView, registry: Arc<VenueRegistry>, latest: Option<Arc<BookUpdate>>,
+    mut mpc_rx: Receiver<Arc<BookUpdate>>, tx: Sender<Result<EncodedSummary, Status>>) {
+    let mut next_send = Instant::now();
+    if let Some(latest) = &latest {
+        if !send_summary(&view, &registry, latest, &tx).await {
+            return;
+        }
+        if let Some(min_interval) = view.min_interval {
+            next_send += min_interval;
+        }
+    }
+    while let Some(mut update) = mpc_rx.recv().await {
+        let gaps = mpc_rx.take_gaps();
+        if gaps > 0 {
+            log::warn!("Subscriber {} lost {} updates", mpc_rx.id(), gaps);
+        }
+        if let Some(min_interval) = view.min_interval {
+            tokio::time::sleep_until(next_send).await;
+            //Only the latest of the updates received in the meantime is sent
+            while let Some(latest) = mpc_rx.try_recv() {
+                update = latest;
+            }
+            next_send = Instant::now() + min_interval;
+        }
+        //The latest update is received again when it was cached right before the receiver was created
+        if latest.as_ref().is_some_and(|latest| Arc::ptr_eq(latest, &update)) {
+            continue;
+        }
+        if !send_summary(&view, &registry, &update, &tx).await {
+            return;
+        }
+    }
+    if mpc_rx.is_disconnected() {
+        let _ = tx.send(Err(Status::resource_exhausted("Too slow, disconnected"))).await;
+    }
+}
+
+//Returns false when the client is gone
+async fn send_summary(view: &SummaryView, registry: &VenueRegistry, update: &BookUpdate, tx: &Sender<Result<EncodedSummary, Status>>) -> bool {
+    let summary = match view.render_encoded(update, registry) {
+        Ok(summary) => summary,
+        Err(e) => {
+            log::error!("Fail to render summary: {}", e);
+            return true;
+        }
+    };
+    match tx.send(Ok(summary)).await {
+        Ok(_) => true,
+        Err(e) => {
+            log::error!("Fail to send summary: {:?}", e.to_string());
+            false
+        }
+    }
+}
+
+//Forward the crossed markets of an instrument, or of all the instruments when symbol is None
+async fn stream_cross_events(symbol: Option<String>, mut cross_rx: Receiver<CrossEvent>, tx: Sender<Result<CrossEvent, Status>>) {
+    while let Some(event) = cross_rx.recv().await {
+        let gaps = cross_rx.take_gaps();
+        if gaps > 0 {
+            log::warn!("Subscriber {} lost {} cross events", cross_rx.id(), gaps);
+        }
+        if symbol.as_ref().is_some_and(|symbol| *symbol != event.symbol) {
+            continue;
+        }
+        if let Err(e) = tx.send(Ok(event)).await {
+            log::error!("Fail to send cross event: {:?}", e.to_string());
+            return;
+        }
+    }
+    if cross_rx.is_disconnected() {
+        let _ = tx.send(Err(Status::resource_exhausted("Too slow, disconnected"))).await;
     }
 }
 
 #[tonic::async_trait]
 impl OrderbookAggregator for OrderBookAggregatorService {
 
-    type BookSummaryStream = ReceiverStream<Result<Summary, Status>>;
-
-    async fn book_summary(&self, _request: Request<Empty>) -> Result<Response<Self::BookSummaryStream>, Status> { 
-        let (tx, rx) = mpsc::channel(100);
-        let mpc = self.mpc.clone();
-        //todo!() 
-        let mut mpc = mpc.lock().await;
-        let mut mpc_rx = mpc.create_receiver(GRPC_BUFFER_SIZE);
-        
-        tokio::spawn( async move {
-            while let Some(msg) = mpc_rx.recv().await {                    
-                    match tx.send(Ok(msg)).await {
-                        Ok(_) => {},
-                        Err(e) => { 
-                            log::error!("Fail to send summary: {:?}", e.to_string()); 
-                            drop(mpc_rx);
-                            break;
+    type BookSummaryStream = ReceiverStream<Result<EncodedSummary, Status>>;
+    type CrossEventsStream = ReceiverStream<Result<CrossEvent, Status>>;
+
+    async fn book_summary(&self, request: Request<SummaryRequest>) -> Result<Response<Self::BookSummaryStream>, Status> {
+        let view = SummaryView::from_request(request.get_ref(), &self.symbols, &self.depths, &self.registry)?;
+        let (tx, rx) = mpsc::channel(FORWARD_CAPACITY);
+        let policy = view.policy.unwrap_or(self.default_policy);
+        let mpc_rx = self.mpc_array[view.instrument.index()].create_receiver(policy);
+        log::info!("Subscriber {} streams {} with {:?}", mpc_rx.id(), self.symbols[view.instrument.index()], policy);
+        //The aggregator caches an update before publishing it, so reading the cache after subscribing
+        //misses no update
+        let latest = self.last_value_cache.get(view.instrument);
+        tokio::spawn(stream_summaries(view, self.registry.clone(), latest, mpc_rx, tx));
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn book_snapshot(&self, request: Request<SummaryRequest>) -> Result<Response<SummarySnapshot>, Status> {
+        let view = SummaryView::from_request(request.get_ref(), &self.symbols, &self.depths, &self.registry)?;
+        let update = match self.last_value_cache.get(view.instrument) {
+            Some(update) => update,
+            None => { return Err(Status::unavailable("No summary generated yet")); }
+        };
+        let summary = view.render(&update, &self.registry).map_err(Status::internal)?;
+        Ok(Response::new(SummarySnapshot { summary: Some(summary), generation_time_us: update.generation_time_us() }))
+    }
+
+    async fn cost_to_fill(&self, request: Request<CostToFillRequest>) -> Result<Response<CostToFillQuote>, Status> {
+        let request = request.get_ref();
+        let instrument = instrument_id(&request.symbol, &self.symbols)?;
+        //The books of the latest update are the ones the aggregator merged last
+        let update = match self.last_value_cache.get(instrument) {
+            Some(update) => update,
+            None => { return Err(Status::unavailable("No book received yet")); }
+        };
+        Ok(Response::new(cost_to_fill(request, &update, &self.registry)?))
+    }
+
+    async fn cross_events(&self, request: Request<CrossEventsRequest>) -> Result<Response<Self::CrossEventsStream>, Status> {
+        //An empty symbol asks for all the instruments, not the first one
+        let symbol = match request.get_ref().symbol.as_str() {
+            "" => None,
+            symbol => Some(self.symbols[instrument_id(symbol, &self.symbols)?.index()].clone()),
+        };
+        let (tx, rx) = mpsc::channel(FORWARD_CAPACITY);
+        let cross_rx = self.cross_channel.create_receiver(self.default_policy);
+        log::info!("Subscriber {} streams the cross events of {}", cross_rx.id(), symbol.as_deref().unwrap_or("all the instruments"));
+        tokio::spawn(stream_cross_events(symbol, cross_rx, tx));
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::market_data_source::{Exchange, InstrumentId, MarketDatSourceLevel, OrderBook};
+    use crate::multi_receiver_channels::MultiReceiverChannel;
+    use crate::orderbook::Summary;
+    use crate::scale::Scale;
+    use prost::Message;
+    use std::time::SystemTime;
+
+    fn update(spread: f64) -> Arc<BookUpdate> {
+        let summary = Summary { spread, ..Default::default() };
+        Arc::new(BookUpdate {
+            encoded: EncodedSummary::new(&summary),
+            summary,
+            books: vec![Arc::new(OrderBook::new())],
+            full_books: Vec::new(),
+            scale: Scale::default(),
+            generated_at: SystemTime::now(),
+        })
+    }
+
+    fn registry() -> Arc<VenueRegistry> {
+        let mut registry = VenueRegistry::new();
+        registry.register("binance", Exchange::Binance).expect("Error");
+        Arc::new(registry)
+    }
+
+    fn spread(encoded: EncodedSummary) -> f64 {
+        Summary::decode(encoded.encode_to_vec().as_slice()).expect("Error").spread
+    }
+
+    fn view() -> SummaryView {
+        SummaryView { instrument: InstrumentId(0), depth: 10, venues: None, min_interval: None, policy: None, consolidated: false }
+    }
+
+    #[tokio::test]
+    async fn test_stream_starts_with_latest() {
+        let mpc = MultiReceiverChannel::new(10);
+        let mpc_rx = mpc.create_receiver(SlowConsumerPolicy::DropOldest);
+        let (tx, mut rx) = mpsc::channel(10);
+        let latest = update(1.0);
+        //The latest update was also published to the new receiver
+        mpc.send(latest.clone());
+        mpc.send(update(2.0));
+        drop(mpc);
+        stream_summaries(view(), registry(), Some(latest), mpc_rx, tx).await;
+
+        let mut spreads = Vec::new();
+        while let Some(summary) = rx.recv().await {
+            spreads.push(spread(summary.expect("Error")));
+        }
+        assert_eq!(spreads, vec![1.0, 2.0]);
+    }
+
+    #[tokio::test]
+    async fn test_slow_client_left_to_policy() {
+        let mpc = MultiReceiverChannel::new(10);
+        let mpc_rx = mpc.create_receiver(SlowConsumerPolicy::Conflate);
+        let (tx, mut rx) = mpsc::channel(FORWARD_CAPACITY);
+        let handle = tokio::spawn(stream_summaries(view(), registry(), None, mpc_rx, tx));
+        //The client doesn't read: one update waits in the buffer, one in the send, the others are conflated
+        for n in 1..=5 {
+            mpc.send(update(n as f64));
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+        }
+        drop(mpc);
+
+        let mut spreads = Vec::new();
+        while let Some(summary) = rx.recv().await {
+            spreads.push(spread(summary.expect("Error")));
+        }
+        handle.await.expect("Error");
+        assert_eq!(spreads, vec![1.0, 2.0, 5.0]);
+    }
+
+    //Connect 1, 100 and 1000 clients streaming the merged book as it is, the way the grpc server does, then
+    //measure the throughput with a burst of updates and the latency with an update every millisecond.
+    //Run with: cargo test --release -p server bench_fan_out -- --ignored --nocapture
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_fan_out() {
+        const UPDATES: usize = 2000;
+        let level = MarketDatSourceLevel::from_f64(0.06, 1.5).to_orderbook_level("binance".to_string(), &Scale::default());
+        let summary = Summary { spread: 0.01, bids: vec![level.clone(); 10], asks: vec![level; 10], ..Default::default() };
+        let update = Arc::new(BookUpdate {
+            encoded: EncodedSummary::new(&summary),
+            summary,
+            books: vec![Arc::new(OrderBook::new())],
+            full_books: Vec::new(),
+            scale: Scale::default(),
+            generated_at: SystemTime::now(),
+        });
+
+        for clients in [1, 100, 1000] {
+            for paced in [false, true] {
+                let mpc = MultiReceiverChannel::new(UPDATES);
+                let mut handles = Vec::new();
+                for _ in 0..clients {
+                    let mpc_rx = mpc.create_receiver(SlowConsumerPolicy::DropOldest);
+                    let (tx, mut rx) = mpsc::channel(FORWARD_CAPACITY);
+                    tokio::spawn(stream_summaries(view(), registry(), None, mpc_rx, tx));
+                    //Stands for the grpc stream of the client
+                    handles.push(tokio::spawn(async move {
+                        let mut received = Vec::with_capacity(UPDATES);
+                        while let Some(Ok(_)) = rx.recv().await {
+                            received.push(std::time::Instant::now());
                         }
+                        received
+                    }));
+                }
+
+                let start = std::time::Instant::now();
+                let mut published = Vec::with_capacity(UPDATES);
+                let mut interval = tokio::time::interval(std::time::Duration::from_millis(1));
+                for _ in 0..UPDATES {
+                    if paced {
+                        interval.tick().await;
                     }
+                    published.push(std::time::Instant::now());
+                    mpc.send(update.clone());
+                    tokio::task::yield_now().await;
                 }
-            });
-                
-        
+                let metrics = mpc.metrics();
+                drop(mpc);
 
-        Ok(Response::new(ReceiverStream::new(rx)))
+                let mut latencies = Vec::with_capacity(UPDATES * clients);
+                for handle in handles {
+                    let received = handle.await.expect("Error");
+                    latencies.extend(received.iter().zip(&published).map(|(r, p)| r.duration_since(*p)));
+                }
+                let elapsed = start.elapsed();
+                latencies.sort();
+                if paced {
+                    println!("{:>4} clients, 1000 updates/s: latency p50 {:?}, p99 {:?}, max {:?} ({})", clients,
+                        latencies[latencies.len() / 2], latencies[latencies.len() * 99 / 100], latencies[latencies.len() - 1], metrics);
+                } else {
+                    println!("{:>4} clients, burst: {:.0} summaries/s ({})", clients, latencies.len() as f64 / elapsed.as_secs_f64(), metrics);
+                }
+            }
+        }
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_stream_disconnected() {
+        let mpc = MultiReceiverChannel::new(1);
+        let mpc_rx = mpc.create_receiver(SlowConsumerPolicy::Disconnect);
+        let (tx, mut rx) = mpsc::channel(10);
+        mpc.send(update(1.0));
+        mpc.send(update(2.0));
+        stream_summaries(view(), registry(), None, mpc_rx, tx).await;
+
+        let status = rx.recv().await.expect("Error").expect_err("Not disconnected");
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    }
+
+    #[tokio::test]
+    async fn test_cross_events_filtered() {
+        let channel = MultiReceiverChannel::new(10);
+        let cross_rx = channel.create_receiver(SlowConsumerPolicy::DropOldest);
+        let (tx, mut rx) = mpsc::channel(10);
+        for symbol in ["ethbtc", "btcusdt", "ethbtc"] {
+            channel.send(CrossEvent { symbol: symbol.to_string(), ..Default::default() });
+        }
+        drop(channel);
+        stream_cross_events(Some("ethbtc".to_string()), cross_rx, tx).await;
+
+        let mut symbols = Vec::new();
+        while let Some(event) = rx.recv().await {
+            symbols.push(event.expect("Error").symbol);
+        }
+        assert_eq!(symbols, vec!["ethbtc", "ethbtc"]);
+    }
+}
@@ -0,0 +1,86 @@
+use rand::Rng;
+use std::time::Duration;
+
+const DEFAULT_INITIAL_DELAY: Duration = Duration::from_millis(500);
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+//Exponential backoff with jitter, used by the market data sources to space out reconnect attempts.
+//The delay doubles on each attempt up to max_delay, and a random value between half and the full
+//delay is returned so that several sources dropped at the same time don't reconnect in lockstep
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial_delay: Duration,
+    max_delay: Duration,
+    attempt: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new(DEFAULT_INITIAL_DELAY, DEFAULT_MAX_DELAY)
+    }
+}
+
+impl Backoff {
+    pub fn new(initial_delay: Duration, max_delay: Duration) -> Backoff {
+        Backoff {
+            initial_delay,
+            max_delay,
+            attempt: 0,
+        }
+    }
+
+    //Number of consecutive attempts since the last reset
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    //Called once a connection is healthy again, so the next failure starts from initial_delay
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.ceiling();
+        self.attempt = self.attempt.saturating_add(1);
+        let half = delay / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+    }
+
+    //The delay before jitter is applied for the current attempt
+    fn ceiling(&self) -> Duration {
+        let factor = 1u32.checked_shl(self.attempt).unwrap_or(u32::MAX);
+        self.initial_delay
+            .checked_mul(factor)
+            .map_or(self.max_delay, |d| d.min(self.max_delay))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_and_caps() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1));
+        let mut ceilings = Vec::new();
+        for _ in 0..40 {
+            let ceiling = backoff.ceiling();
+            let delay = backoff.next_delay();
+            assert!(delay >= ceiling / 2 && delay <= ceiling);
+            ceilings.push(ceiling.as_millis());
+        }
+        assert_eq!(ceilings[..5], [100, 200, 400, 800, 1000]);
+        assert!(ceilings[5..].iter().all(|c| *c == 1000));
+        assert_eq!(backoff.attempt(), 40);
+    }
+
+    #[test]
+    fn test_backoff_reset() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1));
+        backoff.next_delay();
+        backoff.next_delay();
+        backoff.reset();
+        assert_eq!(backoff.attempt(), 0);
+        assert!(backoff.next_delay() <= Duration::from_millis(100));
+    }
+}
@@ -1,45 +1,351 @@
+use crate::local_order_book::LocalOrderBook;
 use crate::market_data_source::*;
+use crate::venue_registry::VenueId;
 use async_trait::async_trait;
-use futures_util::{SinkExt, StreamExt};
 use serde::Deserialize;
+use std::sync::{Arc, Mutex};
 use tokio::sync::mpsc::Sender;
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
+
+pub const ADDRESS: &str = "wss://stream.binance.com:9443/stream?streams=";
+pub const REST_ADDRESS: &str = "https://api.binance.com/api/v3/depth";
+const DIFF_METADATA: &str = "@depth@100ms";
+//The depths of the partial book depth stream
+const SNAPSHOT_DEPTHS: [usize; 3] = [5, 10, 20];
+const SNAPSHOT_LIMIT: usize = 1000;
+const MAX_RESYNC_ATTEMPTS: usize = 3;
+
+#[derive(Debug, Default, Deserialize, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BinanceMode {
+    //Partial book depth stream, the top levels are pushed every 100ms
+    #[default]
+    Snapshot,
+    //Diff depth stream applied to a local book which is bootstrapped from the REST snapshot
+    Diff,
+}
 
 #[derive(Debug, Deserialize)]
 struct BinanceJson {
     stream: String,
-    data: MarketDataSourceData,
+    data: BinanceBook,
+}
+
+#[derive(Debug, Deserialize)]
+struct BinanceBook {
+    #[serde(flatten)]
+    book: MarketDataSourceData,
+    #[serde(rename = "lastUpdateId")]
+    last_update_id: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct BinanceDiffJson {
+    stream: String,
+    data: DepthUpdate,
+}
+
+#[derive(Debug, Deserialize)]
+struct DepthUpdate {
+    //In milliseconds since the unix epoch
+    #[serde(rename = "E")]
+    event_time: u64,
+    #[serde(rename = "U")]
+    first_update_id: u64,
+    #[serde(rename = "u")]
+    final_update_id: u64,
+    #[serde(rename = "b")]
+    bids: Vec<MarketDatSourceLevel>,
+    #[serde(rename = "a")]
+    asks: Vec<MarketDatSourceLevel>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DepthSnapshot {
+    #[serde(rename = "lastUpdateId")]
+    last_update_id: u64,
+    bids: Vec<MarketDatSourceLevel>,
+    asks: Vec<MarketDatSourceLevel>,
+}
+
+#[derive(Debug, PartialEq)]
+enum DiffOutcome {
+    Applied,
+    //The update is already contained in the snapshot
+    Stale,
+    //Some updates were missed, the book has to be rebuilt from a new snapshot
+    Gap,
+}
+
+//The local book of the diff mode. last_update_id is None until a snapshot is loaded
+#[derive(Debug, Default)]
+struct DiffBook {
+    book: LocalOrderBook,
+    last_update_id: Option<u64>,
+}
+
+impl DiffBook {
+    fn reset(&mut self) {
+        self.book.clear();
+        self.last_update_id = None;
+    }
+
+    fn load_snapshot(&mut self, snapshot: &DepthSnapshot) {
+        self.book.clear();
+        snapshot.bids.iter().for_each(|l| self.book.update_bid(l));
+        snapshot.asks.iter().for_each(|l| self.book.update_ask(l));
+        self.last_update_id = Some(snapshot.last_update_id);
+    }
+
+    //An update is applied if it continues right after the last applied id, i.e. U <= lastUpdateId + 1 <= u
+    fn apply(&mut self, update: &DepthUpdate) -> DiffOutcome {
+        let last_update_id = match self.last_update_id {
+            Some(id) => id,
+            None => return DiffOutcome::Gap,
+        };
+        if update.final_update_id <= last_update_id {
+            return DiffOutcome::Stale;
+        }
+        if update.first_update_id > last_update_id + 1 {
+            self.last_update_id = None;
+            return DiffOutcome::Gap;
+        }
+
+        update.bids.iter().for_each(|l| self.book.update_bid(l));
+        update.asks.iter().for_each(|l| self.book.update_ask(l));
+        self.last_update_id = Some(update.final_update_id);
+        DiffOutcome::Applied
+    }
 }
 
 #[derive(Clone)]
 pub struct Binance {
     info: MarketDataSourceInfo,
-    metadata: String,
+    mode: BinanceMode,
+    rest_address: String,
+    http_client: reqwest::Client,
+    //One book per instrument in diff mode
+    diff_books: Arc<Mutex<Vec<DiffBook>>>,
 }
 
 impl Binance {
     pub fn new(
-        address: &'static str,
-        currency: &str,
+        address: &str,
+        currencies: &[String],
+        sender: Sender<OrderBookSnap>,
+        venue: VenueId,
+        name: &str,
+    ) -> Self {
+        Binance {
+            info: MarketDataSourceInfo::new(address, currencies, sender, venue, name),
+            mode: BinanceMode::Snapshot,
+            rest_address: String::new(),
+            http_client: reqwest::Client::new(),
+            diff_books: Arc::new(Mutex::new(
+                currencies.iter().map(|_| DiffBook::default()).collect(),
+            )),
+        }
+    }
+
+    //Binance source in diff mode, rest_address is the depth snapshot endpoint, e.g. https://api.binance.com/api/v3/depth
+    pub fn new_diff(
+        address: &str,
+        rest_address: &str,
+        currencies: &[String],
         sender: Sender<OrderBookSnap>,
-        name: &'static str,
+        venue: VenueId,
+        name: &str,
     ) -> Self {
         Binance {
-            info: MarketDataSourceInfo {
-                address,
-                currency: currency.to_string(),
-                sender,
-                name,
-            },
-            //metadata: format!("@depth{}@100ms", DEFAULT_DEPTH.to_string())
-            metadata: format!("@depth{}@100ms", "5"),
+            mode: BinanceMode::Diff,
+            rest_address: rest_address.to_string(),
+            ..Binance::new(address, currencies, sender, venue, name)
+        }
+    }
+
+    //The number of levels sent for each currency, DEFAULT_DEPTH for the ones not given. The partial
+    //book depth stream has 20 levels at most, the diff mode is needed for more
+    pub fn with_depths(mut self, depths: &[usize]) -> Self {
+        self.info.depths = depths.to_vec();
+        if self.mode == BinanceMode::Snapshot {
+            for (currency, depth) in self.info.currencies.iter().zip(depths) {
+                if *depth > SNAPSHOT_DEPTHS[SNAPSHOT_DEPTHS.len() - 1] {
+                    log::warn!(
+                        "{} only streams 20 levels of {} in snapshot mode, use the diff mode for {}",
+                        self.info.name,
+                        currency,
+                        depth
+                    );
+                }
+            }
+        }
+        self
+    }
+
+    //Offer the permessage-deflate extension when connecting
+    pub fn with_permessage_deflate(mut self, permessage_deflate: bool) -> Self {
+        self.info.permessage_deflate = permessage_deflate;
+        self
+    }
+
+    //The stream of a currency, e.g. ethbtc@depth5@100ms. In snapshot mode, the smallest stream holding
+    //the depth of the instrument
+    fn stream(&self, instrument: InstrumentId) -> String {
+        let currency = &self.info.currencies[instrument.index()];
+        match self.mode {
+            BinanceMode::Snapshot => {
+                let depth = self.info.depth(instrument);
+                let stream_depth = SNAPSHOT_DEPTHS
+                    .into_iter()
+                    .find(|stream_depth| *stream_depth >= depth)
+                    .unwrap_or(SNAPSHOT_DEPTHS[SNAPSHOT_DEPTHS.len() - 1]);
+                format!("{}@depth{}@100ms", currency, stream_depth)
+            }
+            BinanceMode::Diff => format!("{}{}", currency, DIFF_METADATA),
+        }
+    }
+
+    //The combined stream name starts with the symbol, e.g. ethbtc@depth5@100ms
+    fn instrument(&self, stream: &str) -> Result<InstrumentId, String> {
+        self.info
+            .instrument(stream.split('@').next().unwrap_or_default())
+    }
+
+    fn normalize_diff(&self, msg: &str) -> Result<OrderBookSnap, String> {
+        let json_msg: BinanceDiffJson = serde_json::from_str(msg).map_err(|e| e.to_string())?;
+        let instrument = self.instrument(&json_msg.stream)?;
+
+        let mut diff_books = self.diff_books.lock().map_err(|e| e.to_string())?;
+        let diff_book = &mut diff_books[instrument.index()];
+        match diff_book.apply(&json_msg.data) {
+            DiffOutcome::Applied => {
+                let mut order_book_snap = OrderBookSnap::new(self.info.venue, instrument);
+                order_book_snap.order_book = diff_book.book.top(self.info.depth(instrument));
+                order_book_snap.full_book = Some(FullBookRef::shared(
+                    &self.diff_books,
+                    instrument,
+                    |diff_book: &DiffBook| diff_book.last_update_id.map(|_| diff_book.book.full()),
+                ));
+                order_book_snap.exchange_time = Some(from_unix_us(json_msg.data.event_time * 1000));
+                order_book_snap.sequence = Some(json_msg.data.final_update_id);
+                order_book_snap.previous_sequence =
+                    Some(json_msg.data.first_update_id.saturating_sub(1));
+                Ok(order_book_snap)
+            }
+            DiffOutcome::Stale => Err(format!(
+                "Stale depth update {}",
+                json_msg.data.final_update_id
+            )),
+            DiffOutcome::Gap => Err(format!(
+                "Gap before depth update {}, resync needed",
+                json_msg.data.first_update_id
+            )),
+        }
+    }
+
+    async fn fetch_snapshot(&self, currency: &str) -> Result<DepthSnapshot, String> {
+        let url = format!(
+            "{}?symbol={}&limit={}",
+            self.rest_address,
+            currency.to_uppercase(),
+            SNAPSHOT_LIMIT
+        );
+        self.http_client
+            .get(url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| e.to_string())?
+            .json::<DepthSnapshot>()
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    //In diff mode, load a REST snapshot when the local book of the instrument is not synced yet (first
+    //message or after a gap). The snapshot must not be older than the update that triggered it, otherwise
+    //it is fetched again
+    async fn sync_diff_book(&self, msg: &str) -> Result<(), String> {
+        if self.mode != BinanceMode::Diff {
+            return Ok(());
+        }
+        //A message that doesn't parse is reported by normalize
+        let json_msg: BinanceDiffJson = match serde_json::from_str(msg) {
+            Ok(m) => m,
+            Err(_) => return Ok(()),
+        };
+        let instrument = match self.instrument(&json_msg.stream) {
+            Ok(instrument) => instrument,
+            Err(_) => return Ok(()),
+        };
+        if self.is_synced(instrument) {
+            return Ok(());
+        }
+
+        let currency = &self.info.currencies[instrument.index()];
+        for attempt in 1..=MAX_RESYNC_ATTEMPTS {
+            let snapshot = self.fetch_snapshot(currency).await?;
+            if snapshot.last_update_id + 1 >= json_msg.data.first_update_id {
+                log::info!(
+                    "{} loaded {} depth snapshot {} after {} attempt(s)",
+                    self.info.name,
+                    currency,
+                    snapshot.last_update_id,
+                    attempt
+                );
+                self.diff_books.lock().map_err(|e| e.to_string())?[instrument.index()]
+                    .load_snapshot(&snapshot);
+                return Ok(());
+            }
+            log::warn!(
+                "{} {} depth snapshot {} is older than update {}",
+                self.info.name,
+                currency,
+                snapshot.last_update_id,
+                json_msg.data.first_update_id
+            );
+        }
+        Err("Cannot get a recent enough depth snapshot".to_string())
+    }
+
+    fn is_synced(&self, instrument: InstrumentId) -> bool {
+        self.diff_books
+            .lock()
+            .map(|b| b[instrument.index()].last_update_id.is_some())
+            .unwrap_or(false)
+    }
+
+    fn reset_diff_books(&self) {
+        if let Ok(mut diff_books) = self.diff_books.lock() {
+            diff_books.iter_mut().for_each(|b| b.reset());
         }
     }
 }
 
 #[async_trait]
-impl MarketDataSource for Binance {
+impl Venue for Binance {
+    fn info(&self) -> &MarketDataSourceInfo {
+        &self.info
+    }
+
+    //The subscriptions are part of the combined stream url, e.g. ethbtc@depth10@100ms/btcusdt@depth20@100ms,
+    //so connecting again is enough to resubscribe
+    fn url(&self) -> String {
+        let streams: Vec<String> = self
+            .info
+            .instruments()
+            .map(|instrument| self.stream(instrument))
+            .collect();
+        format!("{}{}", self.info.address, streams.join("/"))
+    }
+
+    fn subscribe_messages(&self) -> Vec<String> {
+        Vec::new()
+    }
+
     fn normalize(&self, msg: &str) -> Result<OrderBookSnap, String> {
+        if self.mode == BinanceMode::Diff {
+            return self.normalize_diff(msg);
+        }
+
         let json_msg: BinanceJson = match serde_json::from_str(msg) {
             Ok(msg) => msg,
             Err(e) => {
@@ -47,76 +353,150 @@ impl MarketDataSource for Binance {
             }
         };
 
-        let currency = json_msg.stream.trim_end_matches(&self.metadata);
-        if currency != self.info.currency {
-            return Err("Receive depth for incorrect currency".to_string());
-        }
-        let mut order_book_snap = OrderBookSnap::new(Exchange::Binance);
-        order_book_snap.order_book.bids = json_msg.data.bids;
-        order_book_snap.order_book.asks = json_msg.data.asks;
+        let instrument = self.instrument(&json_msg.stream)?;
+        let mut order_book_snap = OrderBookSnap::new(self.info.venue, instrument);
+        order_book_snap.order_book.bids = json_msg.data.book.bids;
+        order_book_snap.order_book.asks = json_msg.data.book.asks;
+        //The ids skip the updates between two pushes, so there is no previous one
+        order_book_snap.sequence = Some(json_msg.data.last_update_id);
+        order_book_snap
+            .order_book
+            .truncate(self.info.depth(instrument));
 
         Ok(order_book_snap)
     }
 
-    async fn run(&self) {
-        let final_address = format!(
-            "{}{}{}",
-            self.info.address, self.info.currency, self.metadata
+    fn on_connect(&self) {
+        self.reset_diff_books();
+    }
+
+    async fn prepare(&self, msg: &str) -> Result<(), String> {
+        self.sync_diff_book(msg).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_stand_in::{recv, start_rest_stand_in, start_ws_stand_in};
+    use tokio::sync::mpsc;
+
+    fn depth_update(first: u64, last: u64, bids: &str, asks: &str) -> String {
+        format!(
+            r#"{{"stream":"ethbtc@depth@100ms","data":{{"e":"depthUpdate","E":1,"s":"ETHBTC","U":{first},"u":{last},"b":{bids},"a":{asks}}}}}"#
+        )
+    }
+
+    fn level(price: f64, amount: f64) -> MarketDatSourceLevel {
+        MarketDatSourceLevel::from_f64(price, amount)
+    }
+
+    #[test]
+    fn test_normalize_snapshot_mode() {
+        let (tx, _rx) = mpsc::channel(1);
+        let binance = Binance::new(
+            "wss://stream.binance.com:9443/stream?streams=",
+            &["ethbtc".to_string(), "btcusdt".to_string()],
+            tx,
+            VenueId(0),
+            "binance",
         );
+        assert_eq!(
+            binance.url(),
+            "wss://stream.binance.com:9443/stream?streams=ethbtc@depth10@100ms/btcusdt@depth10@100ms"
+        );
+        let msg = r#"{"stream":"ethbtc@depth5@100ms","data":{"lastUpdateId":1,"bids":[["0.065","1.5"]],"asks":[["0.066","2"]]}}"#;
+        let snap = binance.normalize(msg).expect("Error");
+        assert_eq!(snap.instrument, InstrumentId(0));
+        assert_eq!(snap.order_book.bids.to_vec(), vec![level(0.065, 1.5)]);
+        assert_eq!((snap.previous_sequence, snap.sequence), (None, Some(1)));
+        assert_eq!(snap.order_book.asks.to_vec(), vec![level(0.066, 2.0)]);
+        //The partial book depth stream has no event time
+        assert_eq!(snap.exchange_time, None);
 
-        let url = match url::Url::parse(&final_address) {
-            Ok(u) => u,
-            Err(e) => {
-                log::error!("Failed to parse address for {}: {:?}", self.info.name, e);
-                return;
-            }
-        };
+        let msg =
+            r#"{"stream":"btcusdt@depth5@100ms","data":{"lastUpdateId":1,"bids":[],"asks":[]}}"#;
+        assert_eq!(
+            binance.normalize(msg).expect("Error").instrument,
+            InstrumentId(1)
+        );
 
-        let (ws_stream, _response) = match connect_async(url).await {
-            Ok((s, r)) => (s, r),
-            Err(e) => {
-                log::error!("Failed to connect to {}: {:?}", self.info.name, e);
-                return;
-            }
-        };
+        let msg =
+            r#"{"stream":"bnbbtc@depth5@100ms","data":{"lastUpdateId":1,"bids":[],"asks":[]}}"#;
+        assert!(binance.normalize(msg).is_err());
+    }
 
-        let (mut write, mut read) = ws_stream.split();
+    #[test]
+    fn test_stream_depth() {
+        let (tx, _rx) = mpsc::channel(1);
+        let currencies = ["ethbtc".to_string(), "btcusdt".to_string()];
+        let binance =
+            Binance::new("", &currencies, tx.clone(), VenueId(0), "binance").with_depths(&[3, 50]);
+        assert_eq!(binance.url(), "ethbtc@depth5@100ms/btcusdt@depth20@100ms");
 
-        while let Some(msg) = read.next().await {
-            let message = match msg {
-                Ok(m) => m,
-                Err(e) => {
-                    log::error!("{} recv msg err: {:#?}", self.info.name, e);
-                    continue;
-                }
-            };
-            match message {
-                Message::Text(msg) => match self.normalize(&msg) {
-                    Ok(orderbook) => {
-                        if let Err(msg) = self.info.sender.send(orderbook).await {
-                            log::error!("Failed to send orderbook snap: {msg}");
-                        };
-                    }
-                    Err(e) => {
-                        log::error!("Failed to normalize msg for {}: {}", self.info.name, e)
-                    }
-                },
-
-                Message::Ping(m) => {
-                    match write.send(Message::Pong(m)).await {
-                        Ok(_) => {}
-                        Err(e) => {
-                            println!("Cannot send pong for {}: {}", self.info.name, e);
-                        }
-                    };
-                }
-                Message::Pong(_) | Message::Frame(_) => {}
-                Message::Binary(_) => (),
-                Message::Close(e) => {
-                    log::error!("Disconnected {:?}", e);
-                    return;
-                }
-            }
-        }
+        let msg = r#"{"stream":"ethbtc@depth5@100ms","data":{"lastUpdateId":1,"bids":[["5","1"],["4","1"],["3","1"],["2","1"]],"asks":[]}}"#;
+        let snap = binance.normalize(msg).expect("Error");
+        assert_eq!(snap.order_book.bids.len(), 3);
+
+        let binance =
+            Binance::new_diff("", "", &currencies, tx, VenueId(0), "binance").with_depths(&[3, 50]);
+        assert_eq!(binance.url(), "ethbtc@depth@100ms/btcusdt@depth@100ms");
+    }
+
+    #[tokio::test]
+    async fn test_diff_mode_sync_and_resync() {
+        let rest_address = start_rest_stand_in("/api/v3/depth", vec![
+            r#"{"lastUpdateId":100,"bids":[["1.0","1"],["0.9","2"]],"asks":[["1.1","1"],["1.2","1"]]}"#,
+            r#"{"lastUpdateId":111,"bids":[["1.0","5"]],"asks":[["1.1","2"],["1.3","1"]]}"#,
+        ])
+        .await;
+        let ws_address = start_ws_stand_in(
+            "/stream?streams=",
+            vec![
+                //Already in the first snapshot
+                depth_update(99, 100, "[]", "[]"),
+                depth_update(101, 102, r#"[["1.0","0"],["0.95","3"]]"#, "[]"),
+                //Updates 103 to 109 are missing
+                depth_update(110, 111, r#"[["0.5","1"]]"#, "[]"),
+                depth_update(112, 113, "[]", r#"[["1.1","0"]]"#),
+            ],
+        )
+        .await;
+
+        let (tx, mut rx) = mpsc::channel(10);
+        let binance = Binance::new_diff(
+            ws_address,
+            rest_address,
+            &["ethbtc".to_string()],
+            tx,
+            VenueId(0),
+            "binance",
+        );
+        tokio::spawn(async move { binance.run().await });
+
+        let snap = recv(&mut rx).await;
+        assert_eq!(
+            snap.order_book.bids.to_vec(),
+            vec![level(0.95, 3.0), level(0.9, 2.0)]
+        );
+        assert_eq!(
+            snap.order_book.asks.to_vec(),
+            vec![level(1.1, 1.0), level(1.2, 1.0)]
+        );
+        //The event time is in milliseconds
+        assert_eq!(snap.exchange_time, Some(from_unix_us(1000)));
+        assert_eq!(
+            (snap.previous_sequence, snap.sequence),
+            (Some(100), Some(102))
+        );
+
+        let snap = recv(&mut rx).await;
+        assert_eq!(snap.order_book.bids.to_vec(), vec![level(1.0, 5.0)]);
+        assert_eq!(snap.order_book.asks.to_vec(), vec![level(1.3, 1.0)]);
+        //After the new snapshot, the updates 103 to 111 are missing from the sequence
+        assert_eq!(
+            (snap.previous_sequence, snap.sequence),
+            (Some(111), Some(113))
+        );
     }
 }
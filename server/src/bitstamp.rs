@@ -1,59 +1,292 @@
+use crate::local_order_book::LocalOrderBook;
 use crate::market_data_source::*;
+use crate::venue_registry::VenueId;
 use async_trait::async_trait;
-use futures_util::{SinkExt, StreamExt};
 use serde::Deserialize;
 use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
 use tokio::sync::mpsc::Sender;
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
 
+pub const ADDRESS: &str = "wss://ws.bitstamp.net";
+pub const REST_ADDRESS: &str = "https://www.bitstamp.net/api/v2/order_book/";
 const SUCCESSFULLY_CONNECTED: &str = "bts:subscription_succeeded";
+const REQUEST_RECONNECT: &str = "bts:request_reconnect";
+const ERROR: &str = "bts:error";
 const CHANNEL_PREFIX: &str = "order_book_";
+const DIFF_CHANNEL_PREFIX: &str = "diff_order_book_";
+
+#[derive(Debug, Default, Deserialize, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BitstampMode {
+    //order_book channel, the top 100 levels are pushed on every change
+    #[default]
+    Snapshot,
+    //diff_order_book channel applied to a local book which is seeded from the REST order book
+    Diff,
+}
 
 #[derive(Debug, Deserialize)]
 struct BitstampJson {
-    data: MarketDataSourceData,
+    data: BitstampBook,
     channel: String,
 }
 
+#[derive(Debug, Deserialize)]
+struct BitstampBook {
+    #[serde(flatten)]
+    book: MarketDataSourceData,
+    //In microseconds since the unix epoch, 0 if not sent
+    #[serde(default, deserialize_with = "de_u64_or_string_as_u64")]
+    microtimestamp: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct BitstampDiffJson {
+    data: BookUpdate,
+    channel: String,
+}
+
+//Used for both the diff_order_book messages and the REST order book
+#[derive(Debug, Deserialize)]
+struct BookUpdate {
+    #[serde(deserialize_with = "de_u64_or_string_as_u64")]
+    microtimestamp: u64,
+    bids: Vec<MarketDatSourceLevel>,
+    asks: Vec<MarketDatSourceLevel>,
+}
+
+//The local book of the diff mode. last_microtimestamp is None until the REST order book is loaded
+#[derive(Debug, Default)]
+struct DiffBook {
+    book: LocalOrderBook,
+    last_microtimestamp: Option<u64>,
+}
+
+impl DiffBook {
+    fn reset(&mut self) {
+        self.book.clear();
+        self.last_microtimestamp = None;
+    }
+
+    fn load_snapshot(&mut self, snapshot: &BookUpdate) {
+        self.book.clear();
+        self.apply_levels(snapshot);
+    }
+
+    //Updates that are not newer than the last applied one are already part of the book, returns false for them
+    fn apply(&mut self, update: &BookUpdate) -> bool {
+        match self.last_microtimestamp {
+            Some(last) if update.microtimestamp > last => {
+                self.apply_levels(update);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn apply_levels(&mut self, update: &BookUpdate) {
+        update.bids.iter().for_each(|l| self.book.update_bid(l));
+        update.asks.iter().for_each(|l| self.book.update_ask(l));
+        self.last_microtimestamp = Some(update.microtimestamp);
+    }
+}
+
 #[derive(Clone)]
 pub struct Bitstamp {
     info: MarketDataSourceInfo,
+    mode: BitstampMode,
+    rest_address: String,
+    http_client: reqwest::Client,
+    //One book per instrument in diff mode
+    diff_books: Arc<Mutex<Vec<DiffBook>>>,
 }
 
 impl Bitstamp {
     pub fn new(
-        address: &'static str,
-        currency: &str,
+        address: &str,
+        currencies: &[String],
         sender: Sender<OrderBookSnap>,
-        name: &'static str,
+        venue: VenueId,
+        name: &str,
     ) -> Self {
-        println!("Create bitstamp instance for {}", currency.to_string());
+        println!("Create bitstamp instance for {}", currencies.join(","));
         Bitstamp {
-            info: MarketDataSourceInfo {
-                address,
-                currency: currency.to_string(),
-                sender,
-                name,
-            },
+            info: MarketDataSourceInfo::new(address, currencies, sender, venue, name),
+            mode: BitstampMode::Snapshot,
+            rest_address: String::new(),
+            http_client: reqwest::Client::new(),
+            diff_books: Arc::new(Mutex::new(
+                currencies.iter().map(|_| DiffBook::default()).collect(),
+            )),
         }
     }
 
-    pub fn is_successful(&self, msg: &str) -> bool {
-        let json_msg: Value = match serde_json::from_str(&msg) {
-            Ok(msg) => msg,
-            Err(e) => {
-                log::error!("Invalid response: {:?} ,{msg}", e);
-                return false;
-            }
+    //Bitstamp source in diff mode, rest_address is the order book endpoint without the pair, e.g. https://www.bitstamp.net/api/v2/order_book/
+    pub fn new_diff(
+        address: &str,
+        rest_address: &str,
+        currencies: &[String],
+        sender: Sender<OrderBookSnap>,
+        venue: VenueId,
+        name: &str,
+    ) -> Self {
+        Bitstamp {
+            mode: BitstampMode::Diff,
+            rest_address: rest_address.to_string(),
+            ..Bitstamp::new(address, currencies, sender, venue, name)
+        }
+    }
+
+    //The number of levels sent for each currency, DEFAULT_DEPTH for the ones not given
+    pub fn with_depths(mut self, depths: &[usize]) -> Self {
+        self.info.depths = depths.to_vec();
+        self
+    }
+
+    //Offer the permessage-deflate extension when connecting
+    pub fn with_permessage_deflate(mut self, permessage_deflate: bool) -> Self {
+        self.info.permessage_deflate = permessage_deflate;
+        self
+    }
+
+    fn channel_prefix(&self) -> &'static str {
+        match self.mode {
+            BitstampMode::Snapshot => CHANNEL_PREFIX,
+            BitstampMode::Diff => DIFF_CHANNEL_PREFIX,
+        }
+    }
+
+    fn instrument(&self, channel: &str) -> Result<InstrumentId, String> {
+        self.info
+            .instrument(channel.trim_start_matches(self.channel_prefix()))
+    }
+
+    fn normalize_diff(&self, msg: &str) -> Result<OrderBookSnap, String> {
+        let json_msg: BitstampDiffJson = serde_json::from_str(msg).map_err(|e| e.to_string())?;
+        let instrument = self.instrument(&json_msg.channel)?;
+
+        let mut diff_books = self.diff_books.lock().map_err(|e| e.to_string())?;
+        let diff_book = &mut diff_books[instrument.index()];
+        if !diff_book.apply(&json_msg.data) {
+            return Err(format!(
+                "Stale order book update {}",
+                json_msg.data.microtimestamp
+            ));
+        }
+        let mut order_book_snap = OrderBookSnap::new(self.info.venue, instrument);
+        order_book_snap.order_book = diff_book.book.top(self.info.depth(instrument));
+        order_book_snap.full_book = Some(FullBookRef::shared(
+            &self.diff_books,
+            instrument,
+            |diff_book: &DiffBook| diff_book.last_microtimestamp.map(|_| diff_book.book.full()),
+        ));
+        order_book_snap.exchange_time = Some(from_unix_us(json_msg.data.microtimestamp));
+        order_book_snap.sequence = Some(json_msg.data.microtimestamp);
+        Ok(order_book_snap)
+    }
+
+    //In diff mode, seed the local book of the instrument from the REST order book before its first update
+    //is applied. The updates that are received while fetching it are queued in the socket and the older
+    //ones are skipped
+    async fn sync_diff_book(&self, msg: &str) -> Result<(), String> {
+        if self.mode != BitstampMode::Diff {
+            return Ok(());
+        }
+        //A message that doesn't parse is reported by normalize
+        let instrument = match serde_json::from_str::<BitstampDiffJson>(msg) {
+            Ok(json_msg) => match self.instrument(&json_msg.channel) {
+                Ok(instrument) => instrument,
+                Err(_) => return Ok(()),
+            },
+            Err(_) => return Ok(()),
         };
+        if self.is_synced(instrument) {
+            return Ok(());
+        }
+        let currency = &self.info.currencies[instrument.index()];
+        let snapshot = self
+            .http_client
+            .get(format!("{}{}/", self.rest_address, currency))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| e.to_string())?
+            .json::<BookUpdate>()
+            .await
+            .map_err(|e| e.to_string())?;
+        log::info!(
+            "{} loaded {} order book snapshot {}",
+            self.info.name,
+            currency,
+            snapshot.microtimestamp
+        );
+        self.diff_books.lock().map_err(|e| e.to_string())?[instrument.index()]
+            .load_snapshot(&snapshot);
+        Ok(())
+    }
 
-        json_msg["event"] == SUCCESSFULLY_CONNECTED
+    fn is_synced(&self, instrument: InstrumentId) -> bool {
+        self.diff_books
+            .lock()
+            .map(|b| b[instrument.index()].last_microtimestamp.is_some())
+            .unwrap_or(false)
+    }
+
+    fn reset_diff_books(&self) {
+        if let Ok(mut diff_books) = self.diff_books.lock() {
+            diff_books.iter_mut().for_each(|b| b.reset());
+        }
+    }
+
+    //Checked on every message, so the data messages are not parsed for it
+    fn is_event(&self, msg: &str, event: &str) -> bool {
+        if !msg.contains(event) {
+            return false;
+        }
+        match serde_json::from_str::<Value>(msg) {
+            Ok(json_msg) => json_msg["event"] == event,
+            Err(_) => false,
+        }
+    }
+
+    //Bitstamp asks the clients to reconnect before a maintenance of its websocket servers
+    pub fn is_reconnect_request(&self, msg: &str) -> bool {
+        self.is_event(msg, REQUEST_RECONNECT)
     }
 }
 
 #[async_trait]
-impl MarketDataSource for Bitstamp {
+impl Venue for Bitstamp {
+    fn info(&self) -> &MarketDataSourceInfo {
+        &self.info
+    }
+
+    fn url(&self) -> String {
+        self.info.address.clone()
+    }
+
+    //One channel per instrument, all on the same connection
+    fn subscribe_messages(&self) -> Vec<String> {
+        self.info
+            .currencies
+            .iter()
+            .map(|currency| {
+                json!({
+                    "event": "bts:subscribe",
+                    "data": {
+                        "channel": format!("{}{}", self.channel_prefix(), currency)
+                    }
+                })
+                .to_string()
+            })
+            .collect()
+    }
+
     fn normalize(&self, msg: &str) -> Result<OrderBookSnap, String> {
+        if self.mode == BitstampMode::Diff {
+            return self.normalize_diff(msg);
+        }
+
         let json_msg: BitstampJson = match serde_json::from_str(msg) {
             Ok(msg) => msg,
             Err(e) => {
@@ -61,102 +294,202 @@ impl MarketDataSource for Bitstamp {
             }
         };
 
-        let currency = json_msg.channel.trim_start_matches(CHANNEL_PREFIX);
-        if currency != self.info.currency {
-            return Err("Receive depth for incorrect currency".to_string());
-        }
-        let mut order_book_snap = OrderBookSnap::new(Exchange::Bitstamp);
-        order_book_snap.order_book.bids = json_msg.data.bids;
-        order_book_snap.order_book.asks = json_msg.data.asks;
+        let instrument = self.instrument(&json_msg.channel)?;
+        let mut order_book_snap = OrderBookSnap::new(self.info.venue, instrument);
+        let full_book = OrderBook {
+            bids: json_msg.data.book.bids,
+            asks: json_msg.data.book.asks,
+        };
+        //The channel has 100 levels, the ones beyond the depth are only used by the cost to fill quotes
+        order_book_snap.order_book = full_book.top(self.info.depth(instrument));
+        order_book_snap.full_book = Some(FullBookRef::from(full_book));
+        //The microtimestamp is also the sequence of the books, which are not contiguous
+        (order_book_snap.exchange_time, order_book_snap.sequence) =
+            match json_msg.data.microtimestamp {
+                0 => (None, None),
+                microtimestamp => (Some(from_unix_us(microtimestamp)), Some(microtimestamp)),
+            };
 
         Ok(order_book_snap)
     }
 
-    async fn run(&self) {
-        let url = match url::Url::parse(&self.info.address) {
-            Ok(u) => u,
-            Err(e) => {
-                log::error!("Failed to parse address for {}: {:?}", self.info.name, e);
-                return;
-            }
-        };
+    fn requires_ack(&self) -> bool {
+        true
+    }
 
-        let (ws_stream, _response) = match connect_async(url).await {
-            Ok((s, r)) => (s, r),
+    fn is_successful(&self, msg: &str) -> bool {
+        let json_msg: Value = match serde_json::from_str(msg) {
+            Ok(msg) => msg,
             Err(e) => {
-                log::error!("Failed to connect to {}: {:?}", self.info.name, e);
-                return;
+                log::error!("Invalid response: {:?} ,{msg}", e);
+                return false;
             }
         };
 
-        let (mut write, mut read) = ws_stream.split();
+        json_msg["event"] == SUCCESSFULLY_CONNECTED
+    }
 
-        let msg = json!({
-            "event": "bts:subscribe",
-            "data": {
-                "channel": format!("order_book_{}", self.info.currency)
-            }
-        });
+    //The first subscription is acknowledged by is_successful, the following ones come here
+    fn handle_control(&self, msg: &str) -> Option<Control> {
+        if self.is_reconnect_request(msg) {
+            return Some(Control::Reconnect(
+                "reconnect requested by server".to_string(),
+            ));
+        }
+        if self.is_event(msg, SUCCESSFULLY_CONNECTED) {
+            return Some(Control::Ignore);
+        }
+        if self.is_event(msg, ERROR) {
+            return Some(Control::Reconnect(format!("subscription rejected: {msg}")));
+        }
+        None
+    }
 
-        log::info!("Bitstamp sub message: {}", msg.to_string());
+    fn on_connect(&self) {
+        self.reset_diff_books();
+    }
 
-        if let Err(e) = write.send(Message::Text(msg.to_string())).await {
-            log::error!("Failed to subscribe for {}: {:?}", self.info.name, e);
-            return;
-        }
+    async fn prepare(&self, msg: &str) -> Result<(), String> {
+        self.sync_diff_book(msg).await
+    }
+}
 
-        let mut got_first_message = false;
-        while let Some(msg) = read.next().await {
-            let message = match msg {
-                Ok(m) => m,
-                Err(e) => {
-                    log::error!("{} recv msg err: {:#?}", self.info.name, e);
-                    continue;
-                }
-            };
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_stand_in::{recv, start_rest_stand_in, start_ws_stand_in};
+    use tokio::sync::mpsc;
 
-            match message {
-                Message::Text(msg) => {
-                    if !got_first_message {
-                        if self.is_successful(&msg) {
-                            got_first_message = true;
-                        } else {
-                            log::error!("Fail to subscribe to {}", self.info.name);
-                            return;
-                        }
-                    } else {
-                        //let data = msg.unwrap().into_text().unwrap();
-                        match self.normalize(&msg) {
-                            Ok(orderbook) => {
-                                if let Err(msg) = self.info.sender.send(orderbook).await {
-                                    log::error!("Failed to send orderbook snap: {msg}");
-                                };
-                            }
-                            Err(e) => {
-                                log::error!(
-                                    "Failed to normalize msg for {}: {:?}",
-                                    self.info.name,
-                                    e
-                                );
-                            }
-                        }
-                    }
-                }
-                Message::Ping(m) => {
-                    match write.send(Message::Pong(m)).await {
-                        Ok(_) => {}
-                        Err(e) => {
-                            println!("Cannot send pong for {}: {}", self.info.name, e);
-                        }
-                    };
-                }
-                Message::Pong(_) | Message::Frame(_) => {}
-                Message::Binary(_) => (),
-                Message::Close(e) => {
-                    log::error!("Disconnected {:?}", e);
-                    return;
-                }
-            }
-        }
+    const SUBSCRIBED: &str =
+        r#"{"event":"bts:subscription_succeeded","channel":"diff_order_book_ethbtc","data":{}}"#;
+
+    fn diff_update(microtimestamp: u64, bids: &str, asks: &str) -> String {
+        format!(
+            r#"{{"data":{{"timestamp":"1","microtimestamp":"{microtimestamp}","bids":{bids},"asks":{asks}}},"channel":"diff_order_book_ethbtc","event":"data"}}"#
+        )
+    }
+
+    fn level(price: f64, amount: f64) -> MarketDatSourceLevel {
+        MarketDatSourceLevel::from_f64(price, amount)
+    }
+
+    #[test]
+    fn test_normalize_snapshot_mode() {
+        let (tx, _rx) = mpsc::channel(1);
+        let bitstamp = Bitstamp::new(
+            "",
+            &["ethbtc".to_string(), "btcusd".to_string()],
+            tx,
+            VenueId(0),
+            "bitstamp",
+        );
+        let msg = r#"{"data":{"timestamp":"1","microtimestamp":"1","bids":[["0.065","1.5"]],"asks":[["0.066","2"]]},"channel":"order_book_ethbtc","event":"data"}"#;
+        let snap = bitstamp.normalize(msg).expect("Error");
+        assert_eq!(snap.instrument, InstrumentId(0));
+        assert_eq!(snap.order_book.bids.to_vec(), vec![level(0.065, 1.5)]);
+        assert_eq!(snap.order_book.asks.to_vec(), vec![level(0.066, 2.0)]);
+        assert_eq!(snap.exchange_time, Some(from_unix_us(1)));
+        assert_eq!(snap.sequence, Some(1));
+
+        let msg = r#"{"data":{"bids":[],"asks":[]},"channel":"order_book_btcusd","event":"data"}"#;
+        assert_eq!(
+            bitstamp.normalize(msg).expect("Error").instrument,
+            InstrumentId(1)
+        );
+
+        let msg = r#"{"data":{"bids":[],"asks":[]},"channel":"order_book_ltcbtc","event":"data"}"#;
+        assert!(bitstamp.normalize(msg).is_err());
+
+        //Only the depth of the instrument is merged, the full book keeps all the levels
+        let bitstamp = bitstamp.with_depths(&[1, 1]);
+        let msg = r#"{"data":{"bids":[["0.065","1.5"],["0.064","1"]],"asks":[["0.066","2"]]},"channel":"order_book_ethbtc","event":"data"}"#;
+        let snap = bitstamp.normalize(msg).expect("Error");
+        assert_eq!(snap.order_book.bids.to_vec(), vec![level(0.065, 1.5)]);
+        assert_eq!(snap.full_book.expect("Error").read().bids.len(), 2);
+    }
+
+    #[test]
+    fn test_subscribe_several_channels() {
+        let (tx, _rx) = mpsc::channel(1);
+        let bitstamp = Bitstamp::new(
+            "",
+            &["ethbtc".to_string(), "btcusd".to_string()],
+            tx,
+            VenueId(0),
+            "bitstamp",
+        );
+        let messages = bitstamp.subscribe_messages();
+        assert_eq!(messages.len(), 2);
+        assert!(messages[1].contains("order_book_btcusd"));
+
+        //The acknowledgement of the second subscription
+        assert!(matches!(
+            bitstamp.handle_control(
+                r#"{"event":"bts:subscription_succeeded","channel":"order_book_btcusd","data":{}}"#
+            ),
+            Some(Control::Ignore)
+        ));
+        assert!(matches!(
+            bitstamp.handle_control(
+                r#"{"event":"bts:error","channel":"","data":{"code":null,"message":"Bad subscription string."}}"#
+            ),
+            Some(Control::Reconnect(_))
+        ));
+        assert!(matches!(
+            bitstamp.handle_control(r#"{"event":"bts:request_reconnect","channel":"","data":""}"#),
+            Some(Control::Reconnect(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_diff_mode() {
+        let rest_address = start_rest_stand_in(
+            "/api/v2/order_book/",
+            vec![r#"{"timestamp":"1","microtimestamp":"1000","bids":[["1.0","1"],["0.9","2"]],"asks":[["1.1","1"],["1.2","1"]]}"#],
+        )
+        .await;
+        let ws_address = start_ws_stand_in(
+            "/",
+            vec![
+                SUBSCRIBED.to_string(),
+                //Older than the REST order book
+                diff_update(900, r#"[["0.5","1"]]"#, "[]"),
+                diff_update(1001, r#"[["1.0","0"],["0.95","3"]]"#, "[]"),
+                diff_update(1002, "[]", r#"[["1.1","0"],["1.15","4"]]"#),
+            ],
+        )
+        .await;
+
+        let (tx, mut rx) = mpsc::channel(10);
+        let bitstamp = Bitstamp::new_diff(
+            ws_address,
+            rest_address,
+            &["ethbtc".to_string()],
+            tx,
+            VenueId(0),
+            "bitstamp",
+        );
+        tokio::spawn(async move { bitstamp.run().await });
+
+        let snap = recv(&mut rx).await;
+        assert_eq!(
+            snap.order_book.bids.to_vec(),
+            vec![level(0.95, 3.0), level(0.9, 2.0)]
+        );
+        assert_eq!(
+            snap.order_book.asks.to_vec(),
+            vec![level(1.1, 1.0), level(1.2, 1.0)]
+        );
+
+        let snap = recv(&mut rx).await;
+        assert_eq!(
+            snap.order_book.bids.to_vec(),
+            vec![level(0.95, 3.0), level(0.9, 2.0)]
+        );
+        assert_eq!(
+            snap.order_book.asks.to_vec(),
+            vec![level(1.15, 4.0), level(1.2, 1.0)]
+        );
+        assert_eq!((snap.previous_sequence, snap.sequence), (None, Some(1002)));
     }
 }
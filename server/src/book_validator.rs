@@ -0,0 +1,453 @@
+use crate::market_data_source::{InstrumentId, MarketDatSourceLevel, OrderBookSnap};
+use crate::scale::Scale;
+use crate::venue_registry::{VenueId, VenueRegistry};
+use serde::Deserialize;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+const DEFAULT_QUARANTINE_AFTER: u32 = 3;
+const RULE_COUNT: usize = 5;
+
+//A check of the books sent by the sources, before they are merged
+#[derive(Debug, Deserialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    //A price which is not a positive number
+    InvalidPrice,
+    //An amount which is not a positive number, a level with no amount should have been removed
+    InvalidAmount,
+    //A price or an amount which is not on the ticks of the instrument, or too large for them. Without
+    //this rule the values are rounded to the nearest tick
+    OffTick,
+    //The bids not in strictly decreasing price or the asks not in strictly increasing price, which
+    //the merge assumes
+    Unsorted,
+    //The best bid at or above the best ask of the same venue
+    Crossed,
+}
+
+impl Rule {
+    //In the order they are checked, the first rule failed is the one a book is rejected for
+    pub const ALL: [Rule; RULE_COUNT] = [
+        Rule::InvalidPrice,
+        Rule::InvalidAmount,
+        Rule::OffTick,
+        Rule::Unsorted,
+        Rule::Crossed,
+    ];
+
+    fn passes(
+        self,
+        bids: &[MarketDatSourceLevel],
+        asks: &[MarketDatSourceLevel],
+        scale: &Scale,
+    ) -> bool {
+        let mut levels = bids.iter().chain(asks);
+        match self {
+            Rule::InvalidPrice => levels.all(|level| level.price.is_positive()),
+            Rule::InvalidAmount => levels.all(|level| level.amount.is_positive()),
+            Rule::OffTick => levels.all(|level| scale.check(level.price, level.amount).is_ok()),
+            Rule::Unsorted => {
+                bids.windows(2).all(|w| w[0].price > w[1].price)
+                    && asks.windows(2).all(|w| w[0].price < w[1].price)
+            }
+            Rule::Crossed => match (bids.first(), asks.first()) {
+                (Some(bid), Some(ask)) => bid.price < ask.price,
+                _ => true,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Rule::InvalidPrice => "invalid_price",
+            Rule::InvalidAmount => "invalid_amount",
+            Rule::OffTick => "off_tick",
+            Rule::Unsorted => "unsorted",
+            Rule::Crossed => "crossed",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ValidationConfig {
+    //The rules the books are checked with, all of them by default
+    pub rules: Vec<Rule>,
+    //A venue is quarantined for an instrument after this many invalid books of the instrument in a
+    //row, 0 never quarantines
+    pub quarantine_after: u32,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        ValidationConfig {
+            rules: Rule::ALL.to_vec(),
+            quarantine_after: DEFAULT_QUARANTINE_AFTER,
+        }
+    }
+}
+
+//Counters of the books rejected from each venue, by rule, and of the times it was quarantined
+#[derive(Debug)]
+pub struct ValidationMetrics {
+    //The name of each venue, indexed by venue id
+    venues: Vec<String>,
+    //Indexed by venue id then by the position of the rule in Rule::ALL
+    rejected: Vec<[AtomicU64; RULE_COUNT]>,
+    quarantined: Vec<AtomicU64>,
+}
+
+impl ValidationMetrics {
+    fn new(registry: &VenueRegistry) -> ValidationMetrics {
+        ValidationMetrics {
+            venues: registry
+                .iter()
+                .map(|(_, venue)| venue.name.clone())
+                .collect(),
+            rejected: registry.iter().map(|_| Default::default()).collect(),
+            quarantined: registry.iter().map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    pub fn rejected(&self, venue: VenueId, rule: Rule) -> u64 {
+        let position = Rule::ALL
+            .iter()
+            .position(|r| *r == rule)
+            .unwrap_or_default();
+        self.rejected
+            .get(venue.index())
+            .map_or(0, |counters| counters[position].load(Ordering::Relaxed))
+    }
+
+    pub fn quarantined(&self, venue: VenueId) -> u64 {
+        self.quarantined
+            .get(venue.index())
+            .map_or(0, |counter| counter.load(Ordering::Relaxed))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rejected
+            .iter()
+            .flatten()
+            .all(|counter| counter.load(Ordering::Relaxed) == 0)
+    }
+}
+
+impl fmt::Display for ValidationMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut venues = Vec::new();
+        for (index, name) in self.venues.iter().enumerate() {
+            let venue = VenueId(index as u16);
+            let mut counters: Vec<String> = Rule::ALL
+                .iter()
+                .map(|rule| (rule, self.rejected(venue, *rule)))
+                .filter(|(_, count)| *count > 0)
+                .map(|(rule, count)| format!("{rule}: {count}"))
+                .collect();
+            if counters.is_empty() {
+                continue;
+            }
+            counters.push(format!("quarantined: {}", self.quarantined(venue)));
+            venues.push(format!("{name} ({})", counters.join(", ")));
+        }
+        write!(f, "{}", venues.join(", "))
+    }
+}
+
+//What is done with a book
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Validation {
+    //The book is merged
+    Valid,
+    //The book is merged and its venue is out of quarantine
+    Released,
+    //The book is dropped, the previous book of the venue is kept
+    Rejected(Rule),
+    //The book is dropped and its venue failed too many times in a row for the instrument, the book of
+    //the venue is excluded from the merge of the instrument until it sends a valid one
+    Quarantined(Rule),
+}
+
+//Checks the books of the sources before the aggregator merges them. The books of a source which are
+//empty, e.g. when it disconnects, are always valid but don't end a quarantine. A venue is quarantined
+//per instrument, so the valid books of its other instruments don't release it
+#[derive(Debug)]
+pub struct BookValidator {
+    rules: Vec<Rule>,
+    quarantine_after: u32,
+    //The invalid books received in a row from each venue, indexed by instrument id then venue id
+    failures: Vec<Vec<u32>>,
+    quarantined: Vec<Vec<bool>>,
+    metrics: Arc<ValidationMetrics>,
+}
+
+impl BookValidator {
+    pub fn new(
+        config: &ValidationConfig,
+        instruments: usize,
+        registry: &VenueRegistry,
+    ) -> BookValidator {
+        //Checked in the order of Rule::ALL whatever the order of the config
+        let rules = Rule::ALL
+            .into_iter()
+            .filter(|rule| config.rules.contains(rule))
+            .collect();
+        BookValidator {
+            rules,
+            quarantine_after: config.quarantine_after,
+            failures: vec![vec![0; registry.len()]; instruments],
+            quarantined: vec![vec![false; registry.len()]; instruments],
+            metrics: Arc::new(ValidationMetrics::new(registry)),
+        }
+    }
+
+    pub fn metrics(&self) -> Arc<ValidationMetrics> {
+        self.metrics.clone()
+    }
+
+    pub fn is_quarantined(&self, instrument: InstrumentId, venue: VenueId) -> bool {
+        self.quarantined
+            .get(instrument.index())
+            .and_then(|quarantined| quarantined.get(venue.index()))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    //Only the top of the book, which is merged, is checked. The full book is not read on the hot path,
+    //the local books of the sources are kept sorted by price. The scale is the one of the instrument
+    pub fn check(&mut self, order_book_snap: &OrderBookSnap, scale: &Scale) -> Validation {
+        let index = order_book_snap.venue.index();
+        let instrument = order_book_snap.instrument.index();
+        let (failures, quarantined) = match (
+            self.failures.get_mut(instrument),
+            self.quarantined.get_mut(instrument),
+        ) {
+            (Some(failures), Some(quarantined)) if index < failures.len() => {
+                (&mut failures[index], &mut quarantined[index])
+            }
+            //Unknown to the registry, the aggregator rejects it
+            _ => {
+                return Validation::Valid;
+            }
+        };
+        let order_book = &order_book_snap.order_book;
+        let failed = self
+            .rules
+            .iter()
+            .copied()
+            .find(|rule| !rule.passes(&order_book.bids, &order_book.asks, scale));
+        let rule = match failed {
+            Some(rule) => rule,
+            None => {
+                *failures = 0;
+                let empty = order_book.bids.is_empty() && order_book.asks.is_empty();
+                if *quarantined && !empty {
+                    *quarantined = false;
+                    return Validation::Released;
+                }
+                return Validation::Valid;
+            }
+        };
+
+        let position = Rule::ALL
+            .iter()
+            .position(|r| *r == rule)
+            .unwrap_or_default();
+        self.metrics.rejected[index][position].fetch_add(1, Ordering::Relaxed);
+        *failures += 1;
+        if self.quarantine_after > 0 && *failures >= self.quarantine_after && !*quarantined {
+            *quarantined = true;
+            self.metrics.quarantined[index].fetch_add(1, Ordering::Relaxed);
+            return Validation::Quarantined(rule);
+        }
+        Validation::Rejected(rule)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::market_data_source::{Exchange, FullBookRef, OrderBook};
+
+    const BINANCE: VenueId = VenueId(0);
+    const BITSTAMP: VenueId = VenueId(1);
+    const ETHBTC: InstrumentId = InstrumentId(0);
+    const BTCUSDT: InstrumentId = InstrumentId(1);
+
+    fn registry() -> VenueRegistry {
+        let mut registry = VenueRegistry::new();
+        for exchange in [Exchange::Binance, Exchange::Bitstamp] {
+            registry
+                .register(&exchange.to_string(), exchange)
+                .expect("Error");
+        }
+        registry
+    }
+
+    fn snap(venue: VenueId, bids: &[(f64, f64)], asks: &[(f64, f64)]) -> OrderBookSnap {
+        let mut order_book_snap = OrderBookSnap::new(venue, InstrumentId(0));
+        for (price, amount) in bids {
+            order_book_snap
+                .order_book
+                .bids
+                .push(MarketDatSourceLevel::from_f64(*price, *amount));
+        }
+        for (price, amount) in asks {
+            order_book_snap
+                .order_book
+                .asks
+                .push(MarketDatSourceLevel::from_f64(*price, *amount));
+        }
+        order_book_snap
+    }
+
+    #[test]
+    fn test_rules() {
+        let registry = registry();
+        let scale = Scale::default();
+        let mut validator = BookValidator::new(
+            &ValidationConfig {
+                quarantine_after: 0,
+                ..Default::default()
+            },
+            1,
+            &registry,
+        );
+        let valid = snap(BINANCE, &[(100.0, 1.0), (99.0, 1.0)], &[(101.0, 1.0)]);
+        assert_eq!(validator.check(&valid, &scale), Validation::Valid);
+        assert_eq!(
+            validator.check(&OrderBookSnap::new(BINANCE, InstrumentId(0)), &scale),
+            Validation::Valid
+        );
+
+        for (order_book_snap, rule) in [
+            (snap(BINANCE, &[(0.0, 1.0)], &[]), Rule::InvalidPrice),
+            (snap(BINANCE, &[], &[(-1.0, 1.0)]), Rule::InvalidPrice),
+            (snap(BINANCE, &[(100.0, 0.0)], &[]), Rule::InvalidAmount),
+            (snap(BINANCE, &[(100.000000001, 1.0)], &[]), Rule::OffTick),
+            (snap(BINANCE, &[], &[(101.0, 0.5e-8)]), Rule::OffTick),
+            //10^12 is 10^20 ticks of 10^-8, more than an i64 holds
+            (snap(BINANCE, &[], &[(1e12, 1.0)]), Rule::OffTick),
+            (
+                snap(BINANCE, &[(99.0, 1.0), (100.0, 1.0)], &[]),
+                Rule::Unsorted,
+            ),
+            (
+                snap(BINANCE, &[], &[(101.0, 1.0), (101.0, 2.0)]),
+                Rule::Unsorted,
+            ),
+            (
+                snap(BINANCE, &[(101.0, 1.0)], &[(101.0, 1.0)]),
+                Rule::Crossed,
+            ),
+        ] {
+            assert_eq!(
+                validator.check(&order_book_snap, &scale),
+                Validation::Rejected(rule)
+            );
+        }
+        assert_eq!(validator.metrics().rejected(BINANCE, Rule::InvalidPrice), 2);
+        assert_eq!(validator.metrics().rejected(BINANCE, Rule::Crossed), 1);
+        assert_eq!(validator.metrics().rejected(BITSTAMP, Rule::Unsorted), 0);
+
+        //The full book is not read, only the top of the book which is merged
+        let mut order_book_snap = snap(BINANCE, &[(100.0, 1.0)], &[(101.0, 1.0)]);
+        let mut full_book = OrderBook::new();
+        full_book
+            .bids
+            .push(MarketDatSourceLevel::from_f64(100.0, 0.0));
+        order_book_snap.full_book = Some(FullBookRef::from(full_book));
+        assert_eq!(validator.check(&order_book_snap, &scale), Validation::Valid);
+
+        //Only the configured rules are checked
+        let mut validator = BookValidator::new(
+            &ValidationConfig {
+                rules: vec![Rule::Unsorted],
+                ..Default::default()
+            },
+            1,
+            &registry,
+        );
+        let crossed = snap(BINANCE, &[(101.0, 1.0)], &[(100.0, 1.0)]);
+        assert_eq!(validator.check(&crossed, &scale), Validation::Valid);
+    }
+
+    #[test]
+    fn test_quarantine() {
+        let registry = registry();
+        let scale = Scale::default();
+        let mut validator = BookValidator::new(&ValidationConfig::default(), 2, &registry);
+        let crossed = snap(BINANCE, &[(101.0, 1.0)], &[(100.0, 1.0)]);
+        let valid = snap(BINANCE, &[(100.0, 1.0)], &[(101.0, 1.0)]);
+
+        //A valid book in between resets the count
+        for _ in 1..DEFAULT_QUARANTINE_AFTER {
+            assert_eq!(
+                validator.check(&crossed, &scale),
+                Validation::Rejected(Rule::Crossed)
+            );
+        }
+        assert_eq!(validator.check(&valid, &scale), Validation::Valid);
+        for _ in 1..DEFAULT_QUARANTINE_AFTER {
+            validator.check(&crossed, &scale);
+        }
+        assert_eq!(
+            validator.check(&crossed, &scale),
+            Validation::Quarantined(Rule::Crossed)
+        );
+        assert!(validator.is_quarantined(ETHBTC, BINANCE));
+        assert!(!validator.is_quarantined(ETHBTC, BITSTAMP));
+        assert_eq!(
+            validator.check(&crossed, &scale),
+            Validation::Rejected(Rule::Crossed)
+        );
+
+        //An empty book doesn't end the quarantine, a valid book does
+        let empty = OrderBookSnap::new(BINANCE, InstrumentId(0));
+        assert_eq!(validator.check(&empty, &scale), Validation::Valid);
+        assert!(validator.is_quarantined(ETHBTC, BINANCE));
+        assert_eq!(validator.check(&valid, &scale), Validation::Released);
+        assert!(!validator.is_quarantined(ETHBTC, BINANCE));
+
+        let metrics = validator.metrics();
+        assert_eq!(metrics.rejected(BINANCE, Rule::Crossed), 6);
+        assert_eq!(metrics.quarantined(BINANCE), 1);
+        assert_eq!(metrics.to_string(), "binance (crossed: 6, quarantined: 1)");
+    }
+
+    #[test]
+    fn test_quarantine_per_instrument() {
+        let registry = registry();
+        let scale = Scale::default();
+        let mut validator = BookValidator::new(&ValidationConfig::default(), 2, &registry);
+        let crossed = snap(BINANCE, &[(101.0, 1.0)], &[(100.0, 1.0)]);
+        let mut valid = snap(BINANCE, &[(100.0, 1.0)], &[(101.0, 1.0)]);
+        valid.instrument = BTCUSDT;
+
+        //The valid books of another instrument neither reset the count nor end the quarantine
+        for _ in 1..DEFAULT_QUARANTINE_AFTER {
+            assert_eq!(
+                validator.check(&crossed, &scale),
+                Validation::Rejected(Rule::Crossed)
+            );
+            assert_eq!(validator.check(&valid, &scale), Validation::Valid);
+        }
+        assert_eq!(
+            validator.check(&crossed, &scale),
+            Validation::Quarantined(Rule::Crossed)
+        );
+        assert_eq!(validator.check(&valid, &scale), Validation::Valid);
+        assert!(validator.is_quarantined(ETHBTC, BINANCE));
+        assert!(!validator.is_quarantined(BTCUSDT, BINANCE));
+        assert_eq!(
+            validator.check(&crossed, &scale),
+            Validation::Rejected(Rule::Crossed)
+        );
+        assert!(validator.is_quarantined(ETHBTC, BINANCE));
+    }
+}
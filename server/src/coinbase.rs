@@ -0,0 +1,287 @@
+use crate::local_order_book::LocalOrderBook;
+use crate::market_data_source::*;
+use crate::scale::Decimal;
+use crate::venue_registry::VenueId;
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::Sender;
+
+pub const ADDRESS: &str = "wss://ws-feed.exchange.coinbase.com";
+//level2 carries the same messages but requires an authenticated subscription
+const CHANNEL: &str = "level2_batch";
+const SUCCESSFULLY_CONNECTED: &str = "subscriptions";
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum CoinbaseJson {
+    Snapshot {
+        product_id: String,
+        bids: Vec<MarketDatSourceLevel>,
+        asks: Vec<MarketDatSourceLevel>,
+        //RFC 3339, e.g. 2023-10-06T17:35:55.440295Z
+        #[serde(default)]
+        time: Option<String>,
+    },
+    L2update {
+        product_id: String,
+        changes: Vec<CoinbaseChange>,
+        #[serde(default)]
+        time: Option<String>,
+    },
+}
+
+//["buy", "0.05312", "1.5"], a size of 0 removes the level
+#[derive(Debug, Deserialize)]
+struct CoinbaseChange(CoinbaseSide, Decimal, Decimal);
+
+//An unknown side fails the parsing of the whole message, so none of its changes is applied
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum CoinbaseSide {
+    Buy,
+    Sell,
+}
+
+#[derive(Debug, Default)]
+struct CoinbaseBook {
+    book: LocalOrderBook,
+    //Set once the snapshot is received, updates before that are ignored
+    synced: bool,
+}
+
+#[derive(Clone)]
+pub struct Coinbase {
+    info: MarketDataSourceInfo,
+    //Coinbase product ids, e.g. ETH-BTC for ethbtc, in the order of the currencies
+    product_ids: Vec<String>,
+    //One book per instrument
+    state: Arc<Mutex<Vec<CoinbaseBook>>>,
+}
+
+impl Coinbase {
+    pub fn new(
+        address: &str,
+        currencies: &[String],
+        sender: Sender<OrderBookSnap>,
+        venue: VenueId,
+        name: &str,
+    ) -> Self {
+        let product_ids = currencies
+            .iter()
+            .map(|currency| match split_currency(currency) {
+                Some((base, quote)) => format!("{}-{}", base, quote).to_uppercase(),
+                None => currency.to_uppercase(),
+            })
+            .collect();
+        Coinbase {
+            info: MarketDataSourceInfo::new(address, currencies, sender, venue, name),
+            product_ids,
+            state: Arc::new(Mutex::new(
+                currencies.iter().map(|_| CoinbaseBook::default()).collect(),
+            )),
+        }
+    }
+
+    //The number of levels sent for each currency, DEFAULT_DEPTH for the ones not given
+    pub fn with_depths(mut self, depths: &[usize]) -> Self {
+        self.info.depths = depths.to_vec();
+        self
+    }
+
+    //Offer the permessage-deflate extension when connecting
+    pub fn with_permessage_deflate(mut self, permessage_deflate: bool) -> Self {
+        self.info.permessage_deflate = permessage_deflate;
+        self
+    }
+
+    fn reset(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state
+                .iter_mut()
+                .for_each(|book| *book = CoinbaseBook::default());
+        }
+    }
+
+    fn instrument(&self, product_id: &str) -> Result<InstrumentId, String> {
+        self.product_ids
+            .iter()
+            .position(|p| p == product_id)
+            .map(|index| InstrumentId(index as u16))
+            .ok_or_else(|| "Receive depth for incorrect currency".to_string())
+    }
+}
+
+#[async_trait]
+impl Venue for Coinbase {
+    fn info(&self) -> &MarketDataSourceInfo {
+        &self.info
+    }
+
+    fn url(&self) -> String {
+        self.info.address.clone()
+    }
+
+    fn subscribe_messages(&self) -> Vec<String> {
+        let msg = json!({
+            "type": "subscribe",
+            "product_ids": self.product_ids,
+            "channels": [CHANNEL]
+        });
+        vec![msg.to_string()]
+    }
+
+    fn normalize(&self, msg: &str) -> Result<OrderBookSnap, String> {
+        let json_msg: CoinbaseJson = serde_json::from_str(msg).map_err(|e| e.to_string())?;
+
+        let mut state = self.state.lock().map_err(|e| e.to_string())?;
+        let (instrument, time) = match json_msg {
+            CoinbaseJson::Snapshot {
+                product_id,
+                bids,
+                asks,
+                time,
+            } => {
+                let instrument = self.instrument(&product_id)?;
+                let state = &mut state[instrument.index()];
+                state.book.clear();
+                bids.iter().for_each(|l| state.book.update_bid(l));
+                asks.iter().for_each(|l| state.book.update_ask(l));
+                state.synced = true;
+                (instrument, time)
+            }
+            CoinbaseJson::L2update {
+                product_id,
+                changes,
+                time,
+            } => {
+                let instrument = self.instrument(&product_id)?;
+                let state = &mut state[instrument.index()];
+                if !state.synced {
+                    return Err("Update received before the snapshot".to_string());
+                }
+                for CoinbaseChange(side, price, amount) in changes {
+                    let level = MarketDatSourceLevel { price, amount };
+                    match side {
+                        CoinbaseSide::Buy => state.book.update_bid(&level),
+                        CoinbaseSide::Sell => state.book.update_ask(&level),
+                    }
+                }
+                (instrument, time)
+            }
+        };
+
+        let mut order_book_snap = OrderBookSnap::new(self.info.venue, instrument);
+        order_book_snap.order_book = state[instrument.index()]
+            .book
+            .top(self.info.depth(instrument));
+        order_book_snap.full_book = Some(FullBookRef::shared(
+            &self.state,
+            instrument,
+            |state: &CoinbaseBook| state.synced.then(|| state.book.full()),
+        ));
+        order_book_snap.exchange_time = time.as_deref().and_then(parse_rfc3339);
+        Ok(order_book_snap)
+    }
+
+    fn requires_ack(&self) -> bool {
+        true
+    }
+
+    fn is_successful(&self, msg: &str) -> bool {
+        let json_msg: Value = match serde_json::from_str(msg) {
+            Ok(msg) => msg,
+            Err(e) => {
+                log::error!("Invalid response: {:?} ,{msg}", e);
+                return false;
+            }
+        };
+
+        json_msg["type"] == SUCCESSFULLY_CONNECTED
+    }
+
+    fn on_connect(&self) {
+        self.reset();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    //Hand written in the format of the level2 channel, Coinbase sends no checksum to check them against
+    const SNAPSHOT: &str = r#"{"type":"snapshot","product_id":"BTC-USD","asks":[["10102.55","0.57753524"],["10103.00","1.20000000"]],"bids":[["10101.10","0.45054140"],["10100.95","2.00000000"]]}"#;
+    const UPDATE: &str = r#"{"type":"l2update","product_id":"BTC-USD","changes":[["buy","10101.80000000","0.162567"],["buy","10101.10","0.00000000"],["sell","10102.55","0.25"]],"time":"2019-08-14T20:42:27.265Z"}"#;
+
+    fn coinbase() -> Coinbase {
+        let (tx, _rx) = mpsc::channel(1);
+        Coinbase::new("", &["btcusd".to_string()], tx, VenueId(0), "coinbase")
+    }
+
+    fn level(price: f64, amount: f64) -> MarketDatSourceLevel {
+        MarketDatSourceLevel::from_f64(price, amount)
+    }
+
+    #[test]
+    fn test_product_mapping() {
+        assert_eq!(coinbase().product_ids, vec!["BTC-USD".to_string()]);
+    }
+
+    #[test]
+    fn test_normalize_snapshot_and_update() {
+        let coinbase = coinbase();
+        assert!(coinbase.normalize(UPDATE).is_err());
+
+        let snap = coinbase.normalize(SNAPSHOT).expect("Error");
+        assert_eq!(snap.venue, VenueId(0));
+        assert_eq!(
+            snap.order_book.bids.to_vec(),
+            vec![level(10101.1, 0.4505414), level(10100.95, 2.0)]
+        );
+        assert_eq!(snap.order_book.asks[0], level(10102.55, 0.57753524));
+
+        let snap = coinbase.normalize(UPDATE).expect("Error");
+        assert_eq!(
+            snap.order_book.bids.to_vec(),
+            vec![level(10101.8, 0.162567), level(10100.95, 2.0)]
+        );
+        assert_eq!(snap.order_book.asks[0], level(10102.55, 0.25));
+        assert_eq!(
+            snap.exchange_time,
+            Some(from_unix_us(1_565_815_347_265_000))
+        );
+    }
+
+    #[test]
+    fn test_normalize_unknown_side() {
+        let coinbase = coinbase();
+        coinbase.normalize(SNAPSHOT).expect("Error");
+        let update = UPDATE.replace(r#"["sell""#, r#"["short""#);
+        assert!(coinbase.normalize(&update).is_err());
+        //The changes before the unknown side are not applied either
+        assert_eq!(
+            coinbase.state.lock().expect("Error")[0].book.full().bids,
+            vec![level(10101.1, 0.4505414), level(10100.95, 2.0)]
+        );
+    }
+
+    #[test]
+    fn test_normalize_incorrect_product() {
+        let coinbase = coinbase();
+        let snapshot = SNAPSHOT.replace("BTC-USD", "ETH-BTC");
+        assert!(coinbase.normalize(&snapshot).is_err());
+    }
+
+    #[test]
+    fn test_is_successful() {
+        let coinbase = coinbase();
+        assert!(coinbase.is_successful(
+            r#"{"type":"subscriptions","channels":[{"name":"level2_batch","product_ids":["BTC-USD"]}]}"#
+        ));
+        assert!(!coinbase.is_successful(
+            r#"{"type":"error","message":"Failed to subscribe","reason":"ETH-XYZ is not a valid product"}"#
+        ));
+    }
+}
@@ -0,0 +1,302 @@
+use crate::binance::BinanceMode;
+use crate::bitstamp::BitstampMode;
+use crate::book_validator::ValidationConfig;
+use crate::market_data_source::{Exchange, DEFAULT_DEPTH, MAX_DEPTH};
+use crate::multi_receiver_channels::SlowConsumerPolicy;
+use crate::scale::Scale;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+const DEFAULT_MAX_BOOK_AGE_MS: u64 = 30000;
+const DEFAULT_MAX_SUBSCRIBER_LAG: usize = 1000;
+
+//Optional settings read from a json file given on the command line.
+//Every field has a default so the file only needs to contain what differs
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub binance_mode: BinanceMode,
+    pub bitstamp_mode: BitstampMode,
+    //The venues to connect to, each one gets its id in this order
+    pub venues: Vec<VenueConfig>,
+    //A venue whose book is not updated for this long is excluded from the merge and reconnected,
+    //0 disables the check
+    pub max_book_age_ms: u64,
+    //What to do with a grpc client whose buffer is full, unless the client asks for another policy
+    pub slow_consumer_policy: SlowConsumerPolicy,
+    //The number of updates buffered for a grpc client
+    pub max_subscriber_lag: usize,
+    //Rank and publish the merged levels by their price after the taker fee of their venue
+    pub fee_adjusted: bool,
+    //The number of levels merged and published for each side of an instrument, from 1 to MAX_DEPTH
+    pub depth: usize,
+    //The depth of the instruments which differ from depth, by symbol
+    pub depths: HashMap<String, usize>,
+    //The decimals the prices and amounts of an instrument are held and published with
+    pub scale: Scale,
+    //The scale of the instruments which differ from scale, by symbol
+    pub scales: HashMap<String, Scale>,
+    //The checks of the books of the venues before they are merged
+    pub validation: ValidationConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            binance_mode: BinanceMode::default(),
+            bitstamp_mode: BitstampMode::default(),
+            venues: [
+                Exchange::Binance,
+                Exchange::Bitstamp,
+                Exchange::Kraken,
+                Exchange::Coinbase,
+                Exchange::Okx,
+            ]
+            .into_iter()
+            .map(VenueConfig::new)
+            .collect(),
+            max_book_age_ms: DEFAULT_MAX_BOOK_AGE_MS,
+            slow_consumer_policy: SlowConsumerPolicy::default(),
+            max_subscriber_lag: DEFAULT_MAX_SUBSCRIBER_LAG,
+            fee_adjusted: false,
+            depth: DEFAULT_DEPTH,
+            depths: HashMap::new(),
+            scale: Scale::default(),
+            scales: HashMap::new(),
+            validation: ValidationConfig::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn load(path: &str) -> Result<ServerConfig, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read config file {path}: {e}"))?;
+        let config: ServerConfig = serde_json::from_str(&content)
+            .map_err(|e| format!("Invalid config file {path}: {e}"))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    //The depth of an instrument, the symbols are case insensitive
+    pub fn depth(&self, symbol: &str) -> usize {
+        self.depths
+            .iter()
+            .find(|(s, _)| s.eq_ignore_ascii_case(symbol))
+            .map_or(self.depth, |(_, depth)| *depth)
+    }
+
+    //The scale of an instrument, the symbols are case insensitive
+    pub fn scale(&self, symbol: &str) -> Scale {
+        self.scales
+            .iter()
+            .find(|(s, _)| s.eq_ignore_ascii_case(symbol))
+            .map_or(self.scale, |(_, scale)| *scale)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let depths = self.depths.iter().map(|(s, d)| (s.as_str(), *d));
+        for (symbol, depth) in std::iter::once(("default", self.depth)).chain(depths) {
+            if !(1..=MAX_DEPTH).contains(&depth) {
+                return Err(format!(
+                    "Invalid depth {depth} for {symbol}, it must be from 1 to {MAX_DEPTH}"
+                ));
+            }
+        }
+        let scales = self.scales.iter().map(|(s, scale)| (s.as_str(), scale));
+        for (symbol, scale) in std::iter::once(("default", &self.scale)).chain(scales) {
+            scale
+                .validate()
+                .map_err(|e| format!("Invalid scale for {symbol}: {e}"))?;
+        }
+        for venue in &self.venues {
+            if !(0.0..10_000.0).contains(&venue.taker_fee_bps) {
+                return Err(format!(
+                    "Invalid taker fee {} for {}",
+                    venue.taker_fee_bps,
+                    venue.name()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+//A connection to an exchange. The name has to be unique, so a second connection to the same
+//exchange (e.g. a backup endpoint) needs its own name
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct VenueConfig {
+    pub exchange: Exchange,
+    //Defaults to the exchange name
+    #[serde(default)]
+    pub name: Option<String>,
+    //Default to the public endpoints of the exchange
+    #[serde(default)]
+    pub address: Option<String>,
+    #[serde(default)]
+    pub rest_address: Option<String>,
+    //The taker fee of the venue in basis points, used when the merge is fee adjusted
+    #[serde(default)]
+    pub taker_fee_bps: f64,
+    //Offer the permessage-deflate extension when connecting, the venue compresses its frames when
+    //it accepts it
+    #[serde(default)]
+    pub permessage_deflate: bool,
+}
+
+impl VenueConfig {
+    pub fn new(exchange: Exchange) -> VenueConfig {
+        VenueConfig {
+            exchange,
+            name: None,
+            address: None,
+            rest_address: None,
+            taker_fee_bps: 0.0,
+            permessage_deflate: false,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => self.exchange.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::book_validator::Rule;
+
+    #[test]
+    fn test_parse_config() {
+        let config: ServerConfig = serde_json::from_str("{}").expect("Error");
+        assert_eq!(config.binance_mode, BinanceMode::Snapshot);
+        assert_eq!(config.bitstamp_mode, BitstampMode::Snapshot);
+        assert_eq!(config.venues.len(), 5);
+        assert_eq!(config.max_book_age_ms, DEFAULT_MAX_BOOK_AGE_MS);
+        assert_eq!(config.slow_consumer_policy, SlowConsumerPolicy::DropOldest);
+        assert_eq!(config.max_subscriber_lag, DEFAULT_MAX_SUBSCRIBER_LAG);
+        assert!(!config.fee_adjusted);
+        assert_eq!(config.depth("ethbtc"), DEFAULT_DEPTH);
+
+        let config: ServerConfig =
+            serde_json::from_str(r#"{"binance_mode": "diff", "bitstamp_mode": "diff"}"#)
+                .expect("Error");
+        assert_eq!(config.binance_mode, BinanceMode::Diff);
+        assert_eq!(config.bitstamp_mode, BitstampMode::Diff);
+
+        let config: ServerConfig = serde_json::from_str(
+            r#"{"slow_consumer_policy": "disconnect", "max_subscriber_lag": 10}"#,
+        )
+        .expect("Error");
+        assert_eq!(config.slow_consumer_policy, SlowConsumerPolicy::Disconnect);
+        assert_eq!(config.max_subscriber_lag, 10);
+
+        assert!(serde_json::from_str::<ServerConfig>(r#"{"binance": "diff"}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_depths() {
+        let config: ServerConfig =
+            serde_json::from_str(r#"{"depth": 20, "depths": {"BTCUSDT": 100, "ethbtc": 5}}"#)
+                .expect("Error");
+        assert!(config.validate().is_ok());
+        assert_eq!(config.depth("btcusdt"), 100);
+        assert_eq!(config.depth("ethbtc"), 5);
+        assert_eq!(config.depth("bnbbtc"), 20);
+
+        for json in [r#"{"depth": 0}"#, r#"{"depths": {"ethbtc": 101}}"#] {
+            let config: ServerConfig = serde_json::from_str(json).expect("Error");
+            assert!(config.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_parse_scales() {
+        let config: ServerConfig = serde_json::from_str(
+            r#"{"scale": {"price_decimals": 6}, "scales": {"BTCUSDT": {"price_decimals": 2, "amount_decimals": 5}}}"#,
+        )
+        .expect("Error");
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.scale("btcusdt"),
+            Scale {
+                price_decimals: 2,
+                amount_decimals: 5
+            }
+        );
+        assert_eq!(config.scale("ethbtc").price_decimals, 6);
+        assert_eq!(
+            config.scale("ethbtc").amount_decimals,
+            Scale::default().amount_decimals
+        );
+
+        let config: ServerConfig =
+            serde_json::from_str(r#"{"scales": {"ethbtc": {"price_decimals": 13}}}"#)
+                .expect("Error");
+        assert!(config.validate().is_err());
+        assert!(serde_json::from_str::<ServerConfig>(r#"{"scale": {"decimals": 2}}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_validation() {
+        let config: ServerConfig = serde_json::from_str("{}").expect("Error");
+        assert_eq!(config.validation, ValidationConfig::default());
+        assert_eq!(config.validation.rules, Rule::ALL.to_vec());
+
+        let config: ServerConfig = serde_json::from_str(
+            r#"{"validation": {"rules": ["unsorted", "crossed"], "quarantine_after": 0}}"#,
+        )
+        .expect("Error");
+        assert_eq!(config.validation.rules, vec![Rule::Unsorted, Rule::Crossed]);
+        assert_eq!(config.validation.quarantine_after, 0);
+        assert!(
+            serde_json::from_str::<ServerConfig>(r#"{"validation": {"rules": ["sorted"]}}"#)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_venues() {
+        let config: ServerConfig = serde_json::from_str(
+            r#"{"venues": [
+                {"exchange": "binance"},
+                {"exchange": "binance", "name": "binance_us", "address": "wss://stream.binance.us:9443/stream?streams="}
+            ]}"#,
+        )
+        .expect("Error");
+        assert_eq!(config.venues.len(), 2);
+        assert_eq!(config.venues[0].name(), "binance");
+        assert_eq!(config.venues[1].name(), "binance_us");
+        assert_eq!(config.venues[1].exchange, Exchange::Binance);
+        assert_eq!(
+            config.venues[1].address.as_deref(),
+            Some("wss://stream.binance.us:9443/stream?streams=")
+        );
+
+        assert!(
+            serde_json::from_str::<ServerConfig>(r#"{"venues": [{"exchange": "ftx"}]}"#).is_err()
+        );
+
+        let config: ServerConfig = serde_json::from_str(
+            r#"{"fee_adjusted": true, "venues": [
+                {"exchange": "binance", "taker_fee_bps": 10, "permessage_deflate": true},
+                {"exchange": "bitstamp"}
+            ]}"#,
+        )
+        .expect("Error");
+        assert!(config.fee_adjusted);
+        assert_eq!(config.venues[0].taker_fee_bps, 10.0);
+        assert!(config.venues[0].permessage_deflate);
+        assert_eq!(config.venues[1].taker_fee_bps, 0.0);
+        assert!(!config.venues[1].permessage_deflate);
+        assert!(config.validate().is_ok());
+        let config: ServerConfig =
+            serde_json::from_str(r#"{"venues": [{"exchange": "binance", "taker_fee_bps": -1}]}"#)
+                .expect("Error");
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,377 @@
+use crate::backoff::Backoff;
+use crate::market_data_source::{Control, Venue};
+use crate::payload::decode_binary;
+use crate::permessage_deflate;
+use futures_util::{FutureExt, Sink, SinkExt, Stream, StreamExt};
+use std::pin::Pin;
+use std::time::{Duration, SystemTime};
+use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
+
+//The two halves of a connection, whichever client opened it
+pub type WsSink = Pin<Box<dyn Sink<Message, Error = String> + Send>>;
+pub type WsStream = Pin<Box<dyn Stream<Item = Result<Message, String>> + Send>>;
+
+//Run a venue forever: connect, subscribe, forward the order books to the aggregator, and reconnect
+//with backoff when the connection is lost
+pub async fn run<V: Venue>(venue: &V) {
+    let info = venue.info();
+    let url = match url::Url::parse(&venue.url()) {
+        Ok(u) => u,
+        Err(e) => {
+            log::error!("Failed to parse address for {}: {:?}", info.name, e);
+            return;
+        }
+    };
+
+    let mut backoff = Backoff::default();
+    loop {
+        let reason = stream(venue, &url, &mut backoff).await;
+        info.prepare_reconnect(&mut backoff, &reason).await;
+    }
+}
+
+//Wait for the next message of the stream. None when the heartbeat interval elapsed first
+async fn next_message<S: Stream + Unpin>(
+    read: &mut S,
+    heartbeat: Option<(Duration, &'static str)>,
+) -> Option<Option<S::Item>> {
+    match heartbeat {
+        Some((interval, _)) => tokio::time::timeout(interval, read.next()).await.ok(),
+        None => Some(read.next().await),
+    }
+}
+
+//Open the connection with the client the venue is configured for. Only the one of the venues
+//configured with permessage_deflate offers the extension
+async fn connect(url: &url::Url, permessage_deflate: bool) -> Result<(WsSink, WsStream), String> {
+    if permessage_deflate {
+        return permessage_deflate::connect(url).await;
+    }
+    let (ws_stream, _response) = connect_async(url).await.map_err(|e| e.to_string())?;
+    let (write, read) = ws_stream.split();
+    Ok((
+        Box::pin(write.sink_map_err(|e| e.to_string())),
+        Box::pin(read.map(|message| message.map_err(|e| e.to_string()))),
+    ))
+}
+
+//Connect, subscribe and forward the order books until the connection is lost, returns the reason
+async fn stream<V: Venue>(venue: &V, url: &url::Url, backoff: &mut Backoff) -> String {
+    let info = venue.info();
+    let (mut write, mut read) = match connect(url, info.permessage_deflate).await {
+        Ok(halves) => halves,
+        Err(e) => {
+            log::error!("Failed to connect to {}: {}", info.name, e);
+            return format!("connect failed: {e}");
+        }
+    };
+
+    //The signal is stored when the source is not waiting for it, a signal sent for the previous
+    //connection would drop this one
+    let _ = info.reconnect.notified().now_or_never();
+    venue.on_connect();
+
+    for msg in venue.subscribe_messages() {
+        log::info!("{} sub message: {}", info.name, msg);
+        if let Err(e) = write.send(Message::Text(msg)).await {
+            log::error!("Failed to subscribe for {}: {:?}", info.name, e);
+            return format!("subscribe failed: {e}");
+        }
+    }
+
+    let mut got_first_message = !venue.requires_ack();
+    let heartbeat = venue.heartbeat();
+    loop {
+        let msg = tokio::select! {
+            msg = next_message(&mut read, heartbeat) => msg,
+            _ = info.reconnect.notified() => {
+                log::warn!("{} is asked to reconnect", info.name);
+                return "reconnect requested".to_string();
+            }
+        };
+        let msg = match (msg, heartbeat) {
+            (Some(msg), _) => msg,
+            //Nothing received for the heartbeat interval
+            (None, Some((_, heartbeat_msg))) => {
+                if let Err(e) = write.send(Message::Text(heartbeat_msg.to_string())).await {
+                    return format!("heartbeat failed: {e}");
+                }
+                continue;
+            }
+            (None, None) => continue,
+        };
+        let message = match msg {
+            Some(Ok(m)) => m,
+            Some(Err(e)) => {
+                log::error!("{} recv msg err: {:#?}", info.name, e);
+                continue;
+            }
+            None => break,
+        };
+        //Before the message is decoded and normalized, so the age of a book includes them
+        let received_at = SystemTime::now();
+        //Compressed or plain binary frames are handled like text frames. The frames compressed by the
+        //permessage-deflate extension are already inflated by the client
+        let message = match message {
+            Message::Binary(data) => match decode_binary(&data) {
+                Ok(text) => Message::Text(text),
+                Err(e) => {
+                    log::error!("Failed to decode binary msg for {}: {}", info.name, e);
+                    continue;
+                }
+            },
+            message => message,
+        };
+
+        match message {
+            Message::Text(msg) => {
+                if !got_first_message {
+                    if venue.is_successful(&msg) {
+                        got_first_message = true;
+                        continue;
+                    }
+                    log::error!("Fail to subscribe to {}", info.name);
+                    return format!("subscription rejected: {msg}");
+                }
+
+                let requests = match venue.handle_control(&msg) {
+                    Some(Control::Ignore) => continue,
+                    Some(Control::Send(requests)) => requests,
+                    Some(Control::Reconnect(reason)) => {
+                        log::error!("{} has to reconnect: {}", info.name, reason);
+                        return reason;
+                    }
+                    None => {
+                        if let Err(e) = venue.prepare(&msg).await {
+                            log::error!("Failed to sync the book of {}: {}", info.name, e);
+                            return format!("resync failed: {e}");
+                        }
+                        match venue.normalize(&msg) {
+                            Ok(mut orderbook) => {
+                                orderbook.received_at = received_at;
+                                backoff.reset();
+                                if let Err(msg) = info.sender.send(orderbook).await {
+                                    log::error!("Failed to send orderbook snap: {msg}");
+                                };
+                            }
+                            Err(e) => {
+                                log::error!("Failed to normalize msg for {}: {}", info.name, e)
+                            }
+                        }
+                        venue.pending_requests()
+                    }
+                };
+
+                for request in requests {
+                    log::info!("{} request: {}", info.name, request);
+                    if let Err(e) = write.send(Message::Text(request)).await {
+                        return format!("request failed: {e}");
+                    }
+                }
+            }
+            Message::Ping(m) => {
+                match write.send(Message::Pong(m)).await {
+                    Ok(_) => {}
+                    Err(e) => {
+                        log::error!("Cannot send pong for {}: {}", info.name, e);
+                    }
+                };
+            }
+            Message::Pong(_) | Message::Frame(_) | Message::Binary(_) => {}
+            Message::Close(e) => {
+                log::error!("Disconnected {:?}", e);
+                return format!("closed by server: {e:?}");
+            }
+        }
+    }
+
+    "stream ended".to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::market_data_source::*;
+    use crate::test_stand_in::{
+        recv, start_ws_deflate_stand_in, start_ws_stand_in, start_ws_stand_in_frames,
+    };
+    use crate::venue_registry::VenueId;
+    use async_trait::async_trait;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+    use tokio::sync::mpsc;
+
+    //Venue where the messages are plain text: "ok" is the ack, "heartbeat" is ignored,
+    //"reconnect" restarts the connection and any other message is the best bid price
+    struct TestVenue {
+        info: MarketDataSourceInfo,
+    }
+
+    #[async_trait]
+    impl Venue for TestVenue {
+        fn info(&self) -> &MarketDataSourceInfo {
+            &self.info
+        }
+
+        fn url(&self) -> String {
+            self.info.address.clone()
+        }
+
+        fn subscribe_messages(&self) -> Vec<String> {
+            vec!["subscribe".to_string()]
+        }
+
+        fn normalize(&self, msg: &str) -> Result<OrderBookSnap, String> {
+            let price = msg.parse::<f64>().map_err(|e| e.to_string())?;
+            let mut order_book_snap = OrderBookSnap::new(self.info.venue, InstrumentId(0));
+            order_book_snap
+                .order_book
+                .bids
+                .push(MarketDatSourceLevel::from_f64(price, 1.0));
+            Ok(order_book_snap)
+        }
+
+        fn requires_ack(&self) -> bool {
+            true
+        }
+
+        fn is_successful(&self, msg: &str) -> bool {
+            msg == "ok"
+        }
+
+        fn handle_control(&self, msg: &str) -> Option<Control> {
+            match msg {
+                "heartbeat" => Some(Control::Ignore),
+                "reconnect" => Some(Control::Reconnect("requested".to_string())),
+                _ => None,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forward_and_reconnect() {
+        let address = start_ws_stand_in(
+            "/",
+            vec![
+                "ok".to_string(),
+                "heartbeat".to_string(),
+                "not a price".to_string(),
+                "1.5".to_string(),
+                "reconnect".to_string(),
+                "2.5".to_string(),
+            ],
+        )
+        .await;
+
+        let (tx, mut rx) = mpsc::channel(10);
+        let venue = TestVenue {
+            info: MarketDataSourceInfo::new(
+                address,
+                &["ethbtc".to_string()],
+                tx,
+                VenueId(0),
+                "test",
+            ),
+        };
+        tokio::spawn(async move { venue.run().await });
+
+        let snap = recv(&mut rx).await;
+        assert_eq!(snap.order_book.bids[0].price.to_f64(), 1.5);
+        //The book is cleared when the connection is restarted, the message after it is not read
+        let snap = recv(&mut rx).await;
+        assert!(snap.order_book.bids.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_subscription_rejected() {
+        let address = start_ws_stand_in("/", vec!["error".to_string(), "1.5".to_string()]).await;
+
+        let (tx, mut rx) = mpsc::channel(10);
+        let venue = TestVenue {
+            info: MarketDataSourceInfo::new(
+                address,
+                &["ethbtc".to_string()],
+                tx,
+                VenueId(0),
+                "test",
+            ),
+        };
+        tokio::spawn(async move { venue.run().await });
+
+        let snap = recv(&mut rx).await;
+        assert!(snap.order_book.bids.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_signal() {
+        let address = start_ws_stand_in("/", vec!["ok".to_string(), "1.5".to_string()]).await;
+
+        let (tx, mut rx) = mpsc::channel(10);
+        let venue = TestVenue {
+            info: MarketDataSourceInfo::new(
+                address,
+                &["ethbtc".to_string()],
+                tx,
+                VenueId(0),
+                "test",
+            ),
+        };
+        let reconnect = venue.reconnect_signal();
+        tokio::spawn(async move { venue.run().await });
+
+        let snap = recv(&mut rx).await;
+        assert_eq!(snap.order_book.bids[0].price.to_f64(), 1.5);
+        //The connection stays open but silent, the watchdog repeats the signal until it is handled
+        let snap = loop {
+            reconnect.notify_one();
+            if let Ok(Some(snap)) = tokio::time::timeout(Duration::from_millis(50), rx.recv()).await
+            {
+                break snap;
+            }
+        };
+        assert!(snap.order_book.bids.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_compressed_binary_frame() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"1.5").expect("Error");
+        let frames = vec![
+            Message::Text("ok".to_string()),
+            Message::Binary(encoder.finish().expect("Error")),
+        ];
+        let address = start_ws_stand_in_frames("/", frames).await;
+
+        let (tx, mut rx) = mpsc::channel(10);
+        let venue = TestVenue {
+            info: MarketDataSourceInfo::new(
+                address,
+                &["ethbtc".to_string()],
+                tx,
+                VenueId(0),
+                "test",
+            ),
+        };
+        tokio::spawn(async move { venue.run().await });
+
+        let snap = recv(&mut rx).await;
+        assert_eq!(snap.order_book.bids[0].price.to_f64(), 1.5);
+    }
+
+    #[tokio::test]
+    async fn test_permessage_deflate() {
+        let address =
+            start_ws_deflate_stand_in("/", vec!["ok".to_string(), "1.5".to_string()]).await;
+
+        let (tx, mut rx) = mpsc::channel(10);
+        let mut info =
+            MarketDataSourceInfo::new(address, &["ethbtc".to_string()], tx, VenueId(0), "test");
+        info.permessage_deflate = true;
+        let venue = TestVenue { info };
+        tokio::spawn(async move { venue.run().await });
+
+        let snap = recv(&mut rx).await;
+        assert_eq!(snap.order_book.bids[0].price.to_f64(), 1.5);
+    }
+}
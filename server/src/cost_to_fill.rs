@@ -0,0 +1,269 @@
+use crate::aggregator::BookUpdate;
+use crate::market_data_source::{FullOrderBook, MarketDatSourceLevel};
+use crate::orderbook::{CostToFillQuote, CostToFillRequest, Side, VenueAllocation};
+use crate::scale::Decimal;
+use crate::summary_view::RequestError;
+use crate::venue_registry::{VenueId, VenueRegistry};
+
+//The quantity and notional filled on a venue
+struct Allocation {
+    venue: usize,
+    amount: f64,
+    notional: f64,
+}
+
+//Sweep the levels of all the venues on one side of the book, the best price first, until the quantity
+//is filled. All the levels the sources hold are used, not only the top of the book merged in the
+//summary. At the same price, the venues are swept in venue id order
+pub fn cost_to_fill(
+    request: &CostToFillRequest,
+    update: &BookUpdate,
+    registry: &VenueRegistry,
+) -> Result<CostToFillQuote, RequestError> {
+    let quantity = request.quantity;
+    if !quantity.is_finite() || quantity <= 0.0 {
+        return Err(RequestError::InvalidArgument(format!(
+            "The quantity must be positive, got {quantity}"
+        )));
+    }
+    let side = match Side::from_i32(request.side) {
+        Some(Side::Buy) => Side::Buy,
+        Some(Side::Sell) => Side::Sell,
+        _ => {
+            return Err(RequestError::InvalidArgument(format!(
+                "The side must be BUY or SELL, got {}",
+                request.side
+            )));
+        }
+    };
+    //Read from the sources now, so the books can be ahead of the summary
+    let full_books: Vec<FullOrderBook> = (0..update.books.len())
+        .map(|index| update.full_book(index))
+        .collect();
+    let levels: Vec<&[MarketDatSourceLevel]> = full_books
+        .iter()
+        .map(|book| match side {
+            Side::Sell => book.bids.as_slice(),
+            _ => book.asks.as_slice(),
+        })
+        .collect();
+    //Whether a price is better than another on the swept side
+    let better = |a: Decimal, b: Decimal| match side {
+        Side::Sell => a > b,
+        _ => a < b,
+    };
+
+    //The index of the next level of each venue
+    let mut next = vec![0; levels.len()];
+    let mut allocations: Vec<Allocation> = Vec::new();
+    let mut remaining = quantity;
+    let mut notional = 0.0;
+    let mut best_price = None;
+    let mut worst_price = 0.0;
+    while remaining > 0.0 {
+        let venue = (0..levels.len())
+            .filter(|venue| next[*venue] < levels[*venue].len())
+            .reduce(|best, venue| {
+                match better(
+                    levels[venue][next[venue]].price,
+                    levels[best][next[best]].price,
+                ) {
+                    true => venue,
+                    false => best,
+                }
+            });
+        let venue = match venue {
+            Some(venue) => venue,
+            None => {
+                return Err(RequestError::NotEnoughDepth(format!(
+                    "Only {} of {} available",
+                    quantity - remaining,
+                    quantity
+                )));
+            }
+        };
+        let level = &levels[venue][next[venue]];
+        next[venue] += 1;
+
+        let price = level.price.to_f64();
+        let amount = level.amount.to_f64().min(remaining);
+        remaining -= amount;
+        notional += amount * price;
+        best_price.get_or_insert(price);
+        worst_price = price;
+        match allocations.iter_mut().find(|a| a.venue == venue) {
+            Some(allocation) => {
+                allocation.amount += amount;
+                allocation.notional += amount * price;
+            }
+            None => allocations.push(Allocation {
+                venue,
+                amount,
+                notional: amount * price,
+            }),
+        }
+    }
+
+    let best_price = best_price.unwrap_or_default();
+    let vwap = notional / quantity;
+    let slippage = match side {
+        Side::Sell => best_price - vwap,
+        _ => vwap - best_price,
+    };
+    let allocations = allocations
+        .into_iter()
+        .map(|allocation| VenueAllocation {
+            exchange: registry
+                .name(VenueId(allocation.venue as u16))
+                .unwrap_or_default()
+                .to_string(),
+            amount: allocation.amount,
+            vwap: allocation.notional / allocation.amount,
+        })
+        .collect();
+    Ok(CostToFillQuote {
+        symbol: update.summary.symbol.clone(),
+        side: side as i32,
+        filled: quantity,
+        vwap,
+        best_price,
+        worst_price,
+        slippage,
+        slippage_bps: slippage / best_price * 10_000.0,
+        allocations,
+        generation_time_us: update.generation_time_us(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::encoded_summary::EncodedSummary;
+    use crate::market_data_source::{Exchange, FullBookRef, InstrumentId};
+    use crate::orderbook::Summary;
+    use crate::scale::Scale;
+    use std::sync::{Arc, Mutex};
+    use std::time::SystemTime;
+
+    fn level(price: f64, amount: f64) -> MarketDatSourceLevel {
+        MarketDatSourceLevel::from_f64(price, amount)
+    }
+
+    fn registry() -> VenueRegistry {
+        let mut registry = VenueRegistry::new();
+        for exchange in [Exchange::Binance, Exchange::Bitstamp] {
+            registry
+                .register(&exchange.to_string(), exchange)
+                .expect("Error");
+        }
+        registry
+    }
+
+    fn update(full_books: Vec<FullOrderBook>) -> BookUpdate {
+        let summary = Summary {
+            symbol: "ethbtc".to_string(),
+            ..Default::default()
+        };
+        BookUpdate {
+            encoded: EncodedSummary::new(&summary),
+            summary,
+            books: full_books.iter().map(|_| Arc::default()).collect(),
+            full_books: full_books
+                .into_iter()
+                .map(|full_book| {
+                    let books = Arc::new(Mutex::new(vec![full_book]));
+                    Some(FullBookRef::shared(
+                        &books,
+                        InstrumentId(0),
+                        |book: &FullOrderBook| Some(book.clone()),
+                    ))
+                })
+                .collect(),
+            scale: Scale::default(),
+            generated_at: SystemTime::now(),
+        }
+    }
+
+    fn request(side: Side, quantity: f64) -> CostToFillRequest {
+        CostToFillRequest {
+            symbol: "ethbtc".to_string(),
+            side: side as i32,
+            quantity,
+        }
+    }
+
+    #[test]
+    fn test_cost_to_fill() {
+        let registry = registry();
+        //More levels than the top of the book merged in the summary
+        let binance = FullOrderBook {
+            bids: (0..20).map(|n| level(99.0 - n as f64, 1.0)).collect(),
+            asks: (0..20).map(|n| level(101.0 + n as f64, 1.0)).collect(),
+        };
+        let bitstamp = FullOrderBook {
+            bids: vec![level(99.5, 2.0)],
+            asks: vec![level(100.0, 2.0), level(101.0, 2.0)],
+        };
+        let update = update(vec![binance, bitstamp]);
+
+        let quote = cost_to_fill(&request(Side::Buy, 5.0), &update, &registry).expect("Error");
+        //2 at 100 on bitstamp, then 1 at 101 on binance and 2 at 101 on bitstamp
+        assert_eq!(quote.filled, 5.0);
+        assert_eq!(quote.best_price, 100.0);
+        assert_eq!(quote.worst_price, 101.0);
+        assert_eq!(quote.vwap, 100.6);
+        assert!((quote.slippage - 0.6).abs() < 1e-9);
+        assert!((quote.slippage_bps - 60.0).abs() < 1e-6);
+        assert_eq!(
+            quote.allocations,
+            vec![
+                VenueAllocation {
+                    exchange: "bitstamp".to_string(),
+                    amount: 4.0,
+                    vwap: 100.5
+                },
+                VenueAllocation {
+                    exchange: "binance".to_string(),
+                    amount: 1.0,
+                    vwap: 101.0
+                }
+            ]
+        );
+        assert_eq!(quote.symbol, "ethbtc");
+
+        let quote = cost_to_fill(&request(Side::Sell, 20.5), &update, &registry).expect("Error");
+        assert_eq!(quote.best_price, 99.5);
+        assert_eq!(quote.worst_price, 99.0 - 18.0);
+        assert_eq!(quote.allocations[0].amount, 2.0);
+        assert_eq!(quote.allocations[1].amount, 18.5);
+        assert!(quote.slippage > 0.0);
+    }
+
+    #[test]
+    fn test_cost_to_fill_rejected() {
+        let registry = registry();
+        let update = update(vec![
+            FullOrderBook {
+                bids: vec![level(99.0, 1.0)],
+                asks: vec![level(101.0, 1.0)],
+            },
+            FullOrderBook::default(),
+        ]);
+        assert_eq!(
+            cost_to_fill(&request(Side::Buy, 1.5), &update, &registry),
+            Err(RequestError::NotEnoughDepth(
+                "Only 1 of 1.5 available".to_string()
+            ))
+        );
+        for request in [
+            request(Side::Buy, 0.0),
+            request(Side::Sell, f64::NAN),
+            request(Side::Unspecified, 1.0),
+        ] {
+            assert!(matches!(
+                cost_to_fill(&request, &update, &registry),
+                Err(RequestError::InvalidArgument(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,297 @@
+use crate::market_data_source::{MarketDatSourceLevel, OrderBook};
+use crate::orderbook::{CrossEvent, CrossState};
+use crate::scale::Scale;
+use crate::venue_registry::{VenueId, VenueRegistry};
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+//A bid of a venue at or above the ask of another venue
+#[derive(Debug, Clone)]
+struct ActiveCross {
+    started: Instant,
+    //Whether the bid was above the ask at any time, not only equal
+    crossed: bool,
+    bid_price: f64,
+    ask_price: f64,
+    //The largest quantity available while crossed
+    available: f64,
+}
+
+//Detects the venues whose best bid is at or above the best ask of another venue. The prices are
+//adjusted for the taker fees of the registry, which are only set when the merge is fee adjusted, and
+//compared in ticks of the instrument like in the merge. A venue is never compared with itself
+#[derive(Debug)]
+pub struct CrossDetector {
+    //The crosses in progress of each instrument, by bid venue and ask venue
+    active: Vec<HashMap<(VenueId, VenueId), ActiveCross>>,
+}
+
+impl CrossDetector {
+    pub fn new(instruments: usize) -> CrossDetector {
+        CrossDetector {
+            active: vec![HashMap::new(); instruments],
+        }
+    }
+
+    //Compare the books of the venues of an instrument, returns an event for each cross that started
+    //or ended since the last call
+    pub fn detect<B: Borrow<OrderBook>>(
+        &mut self,
+        instrument: usize,
+        symbol: &str,
+        books: &[B],
+        registry: &VenueRegistry,
+        scale: &Scale,
+        now: Instant,
+    ) -> Vec<CrossEvent> {
+        let active = match self.active.get_mut(instrument) {
+            Some(active) => active,
+            None => {
+                return Vec::new();
+            }
+        };
+        let mut events = Vec::new();
+        for (bid_venue, bid_entry) in registry.iter() {
+            for (ask_venue, ask_entry) in registry.iter() {
+                if bid_venue == ask_venue {
+                    continue;
+                }
+                let key = (bid_venue, ask_venue);
+                let bid_fee = 1.0 - bid_entry.taker_fee;
+                let ask_fee = 1.0 + ask_entry.taker_fee;
+                let (bids, asks) =
+                    match (books.get(bid_venue.index()), books.get(ask_venue.index())) {
+                        (Some(bid_book), Some(ask_book)) => {
+                            (&bid_book.borrow().bids[..], &ask_book.borrow().asks[..])
+                        }
+                        _ => (&[][..], &[][..]),
+                    };
+                let bid_units =
+                    |level: &MarketDatSourceLevel| scale.adjusted_price_units(level.price, bid_fee);
+                let ask_units =
+                    |level: &MarketDatSourceLevel| scale.adjusted_price_units(level.price, ask_fee);
+                let top = match (bids.first(), asks.first()) {
+                    (Some(bid), Some(ask)) if bid_units(bid) >= ask_units(ask) => {
+                        Some((scale.price(bid_units(bid)), scale.price(ask_units(ask))))
+                    }
+                    _ => None,
+                };
+
+                match (top, active.get_mut(&key)) {
+                    (Some((bid_price, ask_price)), Some(cross)) => {
+                        cross.crossed |= bid_price > ask_price;
+                        cross.bid_price = bid_price;
+                        cross.ask_price = ask_price;
+                        cross.available = cross
+                            .available
+                            .max(Self::available(bids, &bid_units, asks, &ask_units));
+                    }
+                    (Some((bid_price, ask_price)), None) => {
+                        let cross = ActiveCross {
+                            started: now,
+                            crossed: bid_price > ask_price,
+                            bid_price,
+                            ask_price,
+                            available: Self::available(bids, &bid_units, asks, &ask_units),
+                        };
+                        events.push(Self::event(
+                            symbol,
+                            key,
+                            registry,
+                            &cross,
+                            CrossState::Started,
+                            Duration::ZERO,
+                        ));
+                        active.insert(key, cross);
+                    }
+                    (None, Some(_)) => {
+                        if let Some(cross) = active.remove(&key) {
+                            events.push(Self::event(
+                                symbol,
+                                key,
+                                registry,
+                                &cross,
+                                CrossState::Ended,
+                                now.saturating_duration_since(cross.started),
+                            ));
+                        }
+                    }
+                    (None, None) => {}
+                }
+            }
+        }
+        events
+    }
+
+    //The quantity that can be bought from the asks and sold to the bids without a loss, walking the
+    //levels of both books while the bid is at or above the ask
+    fn available(
+        bids: &[MarketDatSourceLevel],
+        bid_units: &dyn Fn(&MarketDatSourceLevel) -> i64,
+        asks: &[MarketDatSourceLevel],
+        ask_units: &dyn Fn(&MarketDatSourceLevel) -> i64,
+    ) -> f64 {
+        let mut available = 0.0;
+        let (mut bid, mut ask) = (0, 0);
+        let (mut bid_left, mut ask_left) = match (bids.first(), asks.first()) {
+            (Some(b), Some(a)) => (b.amount.to_f64(), a.amount.to_f64()),
+            _ => {
+                return 0.0;
+            }
+        };
+        while bid < bids.len() && ask < asks.len() && bid_units(&bids[bid]) >= ask_units(&asks[ask])
+        {
+            let amount = bid_left.min(ask_left);
+            available += amount;
+            bid_left -= amount;
+            ask_left -= amount;
+            if bid_left <= 0.0 {
+                bid += 1;
+                bid_left = bids.get(bid).map(|l| l.amount.to_f64()).unwrap_or_default();
+            }
+            if ask_left <= 0.0 {
+                ask += 1;
+                ask_left = asks.get(ask).map(|l| l.amount.to_f64()).unwrap_or_default();
+            }
+        }
+        available
+    }
+
+    fn event(
+        symbol: &str,
+        (bid_venue, ask_venue): (VenueId, VenueId),
+        registry: &VenueRegistry,
+        cross: &ActiveCross,
+        state: CrossState,
+        duration: Duration,
+    ) -> CrossEvent {
+        CrossEvent {
+            symbol: symbol.to_string(),
+            state: state as i32,
+            crossed: cross.crossed,
+            bid_exchange: registry.name(bid_venue).unwrap_or_default().to_string(),
+            ask_exchange: registry.name(ask_venue).unwrap_or_default().to_string(),
+            bid_price: cross.bid_price,
+            ask_price: cross.ask_price,
+            available: cross.available,
+            duration_us: duration.as_micros() as u64,
+            time_us: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_micros() as u64)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::market_data_source::Exchange;
+
+    const BINANCE: VenueId = VenueId(0);
+    const BITSTAMP: VenueId = VenueId(1);
+
+    fn registry() -> VenueRegistry {
+        let mut registry = VenueRegistry::new();
+        for exchange in [Exchange::Binance, Exchange::Bitstamp, Exchange::Kraken] {
+            registry
+                .register(&exchange.to_string(), exchange)
+                .expect("Error");
+        }
+        registry
+    }
+
+    fn book(bid: f64, ask: f64) -> OrderBook {
+        let mut book = OrderBook::new();
+        for n in 0..3 {
+            book.bids
+                .push(MarketDatSourceLevel::from_f64(bid - n as f64, 1.0));
+            book.asks
+                .push(MarketDatSourceLevel::from_f64(ask + n as f64, 1.0));
+        }
+        book
+    }
+
+    #[test]
+    fn test_detect_cross() {
+        let registry = registry();
+        let mut detector = CrossDetector::new(1);
+        let start = Instant::now();
+        let mut books = vec![book(100.0, 101.0), book(99.0, 102.0), OrderBook::new()];
+        assert!(detector
+            .detect(0, "ethbtc", &books, &registry, &Scale::default(), start)
+            .is_empty());
+
+        //The binance bid 100 is above the bitstamp ask 99, the next ones don't cross
+        books[BITSTAMP.index()] = book(98.0, 99.0);
+        let events = detector.detect(0, "ethbtc", &books, &registry, &Scale::default(), start);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].state, CrossState::Started as i32);
+        assert!(events[0].crossed);
+        assert_eq!(events[0].bid_exchange, "binance");
+        assert_eq!(events[0].ask_exchange, "bitstamp");
+        assert_eq!(events[0].bid_price, 100.0);
+        assert_eq!(events[0].ask_price, 99.0);
+        assert_eq!(events[0].available, 1.0);
+        assert_eq!(events[0].duration_us, 0);
+
+        //Still crossed, no event
+        let later = start + Duration::from_millis(300);
+        assert!(detector
+            .detect(0, "ethbtc", &books, &registry, &Scale::default(), later)
+            .is_empty());
+
+        books[BITSTAMP.index()] = book(98.0, 100.5);
+        let events = detector.detect(0, "ethbtc", &books, &registry, &Scale::default(), later);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].state, CrossState::Ended as i32);
+        assert_eq!(events[0].duration_us, 300_000);
+
+        //Deeper crosses are available from several levels
+        books[BITSTAMP.index()] = book(98.0, 97.0);
+        let events = detector.detect(0, "ethbtc", &books, &registry, &Scale::default(), later);
+        assert_eq!(events[0].available, 2.0);
+    }
+
+    #[test]
+    fn test_detect_locked_after_fees() {
+        let mut registry = registry();
+        let mut detector = CrossDetector::new(1);
+        let now = Instant::now();
+        //The bid of binance equals the ask of bitstamp
+        let mut books = vec![book(100.0, 101.0), book(99.0, 100.0), OrderBook::new()];
+        let events = detector.detect(0, "ethbtc", &books, &registry, &Scale::default(), now);
+        assert_eq!(events.len(), 1);
+        assert!(!events[0].crossed);
+        assert_eq!(events[0].available, 1.0);
+
+        //Not crossed anymore once the fee of binance is paid
+        registry.set_taker_fee(BINANCE, 0.001);
+        let events = detector.detect(0, "ethbtc", &books, &registry, &Scale::default(), now);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].state, CrossState::Ended as i32);
+
+        //Locked again at exactly the fee adjusted bid, 99.9 in ticks
+        books[BITSTAMP.index()] = book(98.0, 99.9);
+        let events = detector.detect(0, "ethbtc", &books, &registry, &Scale::default(), now);
+        assert_eq!(events.len(), 1);
+        assert!(!events[0].crossed);
+        assert_eq!((events[0].bid_price, events[0].ask_price), (99.9, 99.9));
+        books[BITSTAMP.index()] = book(99.0, 100.0);
+        detector.detect(0, "ethbtc", &books, &registry, &Scale::default(), now);
+
+        //A stale venue has an empty book, which ends its crosses
+        books[BINANCE.index()] = book(110.0, 111.0);
+        assert_eq!(
+            detector
+                .detect(0, "ethbtc", &books, &registry, &Scale::default(), now)
+                .len(),
+            1
+        );
+        books[BINANCE.index()] = OrderBook::new();
+        let events = detector.detect(0, "ethbtc", &books, &registry, &Scale::default(), now);
+        assert_eq!(events[0].state, CrossState::Ended as i32);
+        assert!(events[0].crossed);
+    }
+}
@@ -0,0 +1,70 @@
+use crate::orderbook::Summary;
+use prost::bytes::{Buf, BufMut, Bytes};
+use prost::encoding::{DecodeContext, WireType};
+use prost::{DecodeError, Message};
+
+//A Summary encoded once and shared by all the grpc clients. Its bytes are written as they are, so
+//sending it to a client neither clones nor encodes the levels again. The clients decode it as a Summary
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EncodedSummary(Bytes);
+
+impl EncodedSummary {
+    pub fn new(summary: &Summary) -> EncodedSummary {
+        EncodedSummary(Bytes::from(summary.encode_to_vec()))
+    }
+}
+
+impl Message for EncodedSummary {
+    fn encode_raw<B: BufMut>(&self, buf: &mut B) {
+        buf.put_slice(&self.0);
+    }
+
+    //Only sent by the server, nothing to decode
+    fn merge_field<B: Buf>(
+        &mut self,
+        tag: u32,
+        wire_type: WireType,
+        buf: &mut B,
+        ctx: DecodeContext,
+    ) -> Result<(), DecodeError> {
+        prost::encoding::skip_field(wire_type, tag, buf, ctx)
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn clear(&mut self) {
+        self.0 = Bytes::new();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::orderbook::Level;
+
+    #[test]
+    fn test_decoded_as_summary() {
+        let summary = Summary {
+            spread: 1.0,
+            bids: vec![Level {
+                exchange: "binance".to_string(),
+                price: 10.0,
+                raw_price: 10.0,
+                amount: 2.0,
+                venues: Vec::new(),
+                price_units: 1_000_000_000,
+                amount_units: 200_000_000,
+                raw_price_units: 1_000_000_000,
+            }],
+            spread_units: 100_000_000,
+            price_decimals: 8,
+            amount_decimals: 8,
+            symbol: "ethbtc".to_string(),
+            ..Default::default()
+        };
+        let encoded = EncodedSummary::new(&summary).encode_to_vec();
+        assert_eq!(Summary::decode(encoded.as_slice()).expect("Error"), summary);
+    }
+}
@@ -0,0 +1,509 @@
+use crate::local_order_book::LocalOrderBook;
+use crate::market_data_source::*;
+use crate::scale::Decimal;
+use crate::venue_registry::VenueId;
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::Sender;
+
+pub const ADDRESS: &str = "wss://ws.kraken.com/v2";
+const BOOK_CHANNEL_TAG: &str = r#""channel":"book""#;
+//The depths Kraken accepts, the checksum always covers the top 10 levels
+const BOOK_DEPTHS: [usize; 5] = [10, 25, 100, 500, 1000];
+const CHECKSUM_DEPTH: usize = 10;
+
+#[derive(Debug, Deserialize)]
+struct KrakenBookJson {
+    #[serde(rename = "type")]
+    kind: String,
+    data: Vec<KrakenBookData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KrakenBookData {
+    symbol: String,
+    bids: Vec<KrakenLevel>,
+    asks: Vec<KrakenLevel>,
+    checksum: u32,
+    //RFC 3339, e.g. 2023-10-06T17:35:55.440295Z
+    #[serde(default)]
+    timestamp: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KrakenLevel {
+    price: Decimal,
+    qty: Decimal,
+}
+
+impl KrakenLevel {
+    fn to_level(&self) -> MarketDatSourceLevel {
+        MarketDatSourceLevel {
+            price: self.price,
+            amount: self.qty,
+        }
+    }
+}
+
+//Number of decimals of the instrument, needed to build the checksum strings
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Precision {
+    price: usize,
+    qty: usize,
+}
+
+#[derive(Debug, Default)]
+struct KrakenBook {
+    book: LocalOrderBook,
+    precision: Option<Precision>,
+    //Set once the book snapshot is received, updates before that are ignored
+    synced: bool,
+    //Set when the checksum doesn't match, the book channel has to be subscribed again
+    resubscribe: bool,
+}
+
+impl KrakenBook {
+    //Kraken checksum: for the top 10 asks then the top 10 bids, the price and qty formatted with the
+    //instrument precision, without the decimal point and the leading zeros, concatenated and CRC32'ed
+    fn checksum(&self, precision: Precision) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        let levels = self
+            .book
+            .asks()
+            .take(CHECKSUM_DEPTH)
+            .chain(self.book.bids().take(CHECKSUM_DEPTH));
+        for level in levels {
+            hasher.update(checksum_field(level.price, precision.price).as_bytes());
+            hasher.update(checksum_field(level.amount, precision.qty).as_bytes());
+        }
+        hasher.finalize()
+    }
+}
+
+fn checksum_field(value: Decimal, precision: usize) -> String {
+    match value.round_units(precision as u32) {
+        0 => String::new(),
+        units => units.to_string(),
+    }
+}
+
+#[derive(Clone)]
+pub struct Kraken {
+    info: MarketDataSourceInfo,
+    //Kraken symbols, e.g. ETH/BTC for ethbtc, in the order of the currencies
+    symbols: Vec<String>,
+    //One book per instrument
+    state: Arc<Mutex<Vec<KrakenBook>>>,
+}
+
+impl Kraken {
+    pub fn new(
+        address: &str,
+        currencies: &[String],
+        sender: Sender<OrderBookSnap>,
+        venue: VenueId,
+        name: &str,
+    ) -> Self {
+        let symbols = currencies
+            .iter()
+            .map(|currency| match split_currency(currency) {
+                Some((base, quote)) => format!("{}/{}", base, quote).to_uppercase(),
+                None => currency.to_uppercase(),
+            })
+            .collect();
+        Kraken {
+            info: MarketDataSourceInfo::new(address, currencies, sender, venue, name),
+            symbols,
+            state: Arc::new(Mutex::new(
+                currencies.iter().map(|_| KrakenBook::default()).collect(),
+            )),
+        }
+    }
+
+    //The number of levels sent for each currency, DEFAULT_DEPTH for the ones not given
+    pub fn with_depths(mut self, depths: &[usize]) -> Self {
+        self.info.depths = depths.to_vec();
+        self
+    }
+
+    //Offer the permessage-deflate extension when connecting
+    pub fn with_permessage_deflate(mut self, permessage_deflate: bool) -> Self {
+        self.info.permessage_deflate = permessage_deflate;
+        self
+    }
+
+    //The smallest depth Kraken accepts holding the depth of the instrument. The local book is kept
+    //at this depth, as the updates and the checksum assume it
+    fn book_depth(&self, instrument: InstrumentId) -> usize {
+        let depth = self.info.depth(instrument);
+        BOOK_DEPTHS
+            .into_iter()
+            .find(|book_depth| *book_depth >= depth)
+            .unwrap_or(BOOK_DEPTHS[BOOK_DEPTHS.len() - 1])
+    }
+
+    fn subscribe_message(&self, method: &str, channel: &str) -> String {
+        json!({
+            "method": method,
+            "params": {
+                "channel": channel,
+            }
+        })
+        .to_string()
+    }
+
+    //The depth is set per subscription, so the symbols are grouped by depth
+    fn book_messages(&self, method: &str, symbols: &[&String]) -> Vec<String> {
+        let mut groups: Vec<(usize, Vec<&String>)> = Vec::new();
+        for symbol in symbols {
+            let depth = match self.symbols.iter().position(|s| s == *symbol) {
+                Some(index) => self.book_depth(InstrumentId(index as u16)),
+                None => BOOK_DEPTHS[0],
+            };
+            match groups.iter_mut().find(|(d, _)| *d == depth) {
+                Some((_, group)) => group.push(symbol),
+                None => groups.push((depth, vec![symbol])),
+            }
+        }
+        groups
+            .into_iter()
+            .map(|(depth, symbols)| {
+                json!({
+                    "method": method,
+                    "params": {
+                        "channel": "book",
+                        "symbol": symbols,
+                        "depth": depth,
+                    }
+                })
+                .to_string()
+            })
+            .collect()
+    }
+
+    //Handle the responses and the other channels. Returns Err when the connection has to be restarted,
+    //Ok(true) when the book channel can be subscribed
+    fn handle_response(&self, msg: &str) -> Result<bool, String> {
+        let json_msg: Value = serde_json::from_str(msg).map_err(|e| e.to_string())?;
+        if json_msg["success"] == false {
+            return Err(format!("request rejected: {}", json_msg["error"]));
+        }
+        if json_msg["channel"] != "instrument" || json_msg["type"] != "snapshot" {
+            return Ok(false);
+        }
+
+        let mut precisions = Vec::with_capacity(self.symbols.len());
+        for symbol in &self.symbols {
+            let pair = json_msg["data"]["pairs"]
+                .as_array()
+                .and_then(|pairs| pairs.iter().find(|p| p["symbol"] == symbol.as_str()))
+                .ok_or_else(|| format!("{} is not listed", symbol))?;
+            let precision = match (
+                pair["price_precision"].as_u64(),
+                pair["qty_precision"].as_u64(),
+            ) {
+                (Some(price), Some(qty)) => Precision {
+                    price: price as usize,
+                    qty: qty as usize,
+                },
+                _ => return Err(format!("no precision for {}", symbol)),
+            };
+            log::info!(
+                "{} precision of {}: {:?}",
+                self.info.name,
+                symbol,
+                precision
+            );
+            precisions.push(precision);
+        }
+
+        let mut state = self.state.lock().map_err(|e| e.to_string())?;
+        let first_time = state.iter().any(|book| book.precision.is_none());
+        for (book, precision) in state.iter_mut().zip(precisions) {
+            book.precision = Some(precision);
+        }
+        Ok(first_time)
+    }
+
+    fn reset(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state
+                .iter_mut()
+                .for_each(|book| *book = KrakenBook::default());
+        }
+    }
+
+    //The symbols whose book has to be subscribed again
+    fn take_resubscribe(&self) -> Vec<&String> {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return Vec::new(),
+        };
+        state
+            .iter_mut()
+            .zip(&self.symbols)
+            .filter(|(book, _)| book.resubscribe)
+            .map(|(book, symbol)| {
+                book.resubscribe = false;
+                symbol
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl Venue for Kraken {
+    fn info(&self) -> &MarketDataSourceInfo {
+        &self.info
+    }
+
+    fn url(&self) -> String {
+        self.info.address.clone()
+    }
+
+    //The instrument snapshot gives the precision needed by the checksum, the book is subscribed after it
+    fn subscribe_messages(&self) -> Vec<String> {
+        vec![self.subscribe_message("subscribe", "instrument")]
+    }
+
+    fn normalize(&self, msg: &str) -> Result<OrderBookSnap, String> {
+        let json_msg: KrakenBookJson = serde_json::from_str(msg).map_err(|e| e.to_string())?;
+        //Kraken sends the books of the symbols in separate messages
+        let data = json_msg
+            .data
+            .first()
+            .ok_or_else(|| "Book message without data".to_string())?;
+        let instrument = self
+            .symbols
+            .iter()
+            .position(|symbol| *symbol == data.symbol)
+            .map(|index| InstrumentId(index as u16))
+            .ok_or_else(|| "Receive depth for incorrect currency".to_string())?;
+
+        let mut state = self.state.lock().map_err(|e| e.to_string())?;
+        let state = &mut state[instrument.index()];
+        match json_msg.kind.as_str() {
+            "snapshot" => {
+                state.book.clear();
+                state.synced = true;
+            }
+            "update" if state.synced => {}
+            "update" => return Err("Update received before the snapshot".to_string()),
+            kind => return Err(format!("Unknown book message type {kind}")),
+        }
+        data.bids
+            .iter()
+            .for_each(|l| state.book.update_bid(&l.to_level()));
+        data.asks
+            .iter()
+            .for_each(|l| state.book.update_ask(&l.to_level()));
+        state.book.truncate(self.book_depth(instrument));
+
+        if let Some(precision) = state.precision {
+            let checksum = state.checksum(precision);
+            if checksum != data.checksum {
+                state.synced = false;
+                state.resubscribe = true;
+                return Err(format!(
+                    "Checksum mismatch, expected {} but got {}",
+                    data.checksum, checksum
+                ));
+            }
+        }
+
+        let mut order_book_snap = OrderBookSnap::new(self.info.venue, instrument);
+        order_book_snap.order_book = state.book.top(self.info.depth(instrument));
+        order_book_snap.full_book = Some(FullBookRef::shared(
+            &self.state,
+            instrument,
+            |state: &KrakenBook| state.synced.then(|| state.book.full()),
+        ));
+        order_book_snap.exchange_time = data.timestamp.as_deref().and_then(parse_rfc3339);
+        Ok(order_book_snap)
+    }
+
+    fn handle_control(&self, msg: &str) -> Option<Control> {
+        if msg.contains(BOOK_CHANNEL_TAG) {
+            return None;
+        }
+        Some(match self.handle_response(msg) {
+            Ok(true) => {
+                let symbols: Vec<&String> = self.symbols.iter().collect();
+                Control::Send(self.book_messages("subscribe", &symbols))
+            }
+            Ok(false) => Control::Ignore,
+            Err(e) => Control::Reconnect(e),
+        })
+    }
+
+    fn on_connect(&self) {
+        self.reset();
+    }
+
+    fn pending_requests(&self) -> Vec<String> {
+        let symbols = self.take_resubscribe();
+        if symbols.is_empty() {
+            return Vec::new();
+        }
+        log::warn!(
+            "Resubscribe the book of {:?} on {}",
+            symbols,
+            self.info.name
+        );
+        ["unsubscribe", "subscribe"]
+            .iter()
+            .flat_map(|method| self.book_messages(method, &symbols))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    //The book snapshot of the checksum guide of the Kraken v2 websocket documentation, with its checksum
+    const SNAPSHOT: &str = r#"{"channel":"book","type":"snapshot","data":[{"symbol":"BTC/USD","bids":[{"price":45283.5,"qty":0.10000000},{"price":45283.4,"qty":1.54582015},{"price":45282.1,"qty":0.10000000},{"price":45281.0,"qty":0.10000000},{"price":45280.3,"qty":1.54592586},{"price":45279.0,"qty":0.07990000},{"price":45277.6,"qty":0.03310103},{"price":45277.5,"qty":0.30000000},{"price":45277.3,"qty":1.54602737},{"price":45276.6,"qty":0.15445238}],"asks":[{"price":45285.2,"qty":0.00100000},{"price":45286.4,"qty":1.54571953},{"price":45286.6,"qty":1.54571109},{"price":45289.6,"qty":1.54560911},{"price":45290.2,"qty":0.15890660},{"price":45291.8,"qty":1.54553491},{"price":45294.7,"qty":0.04454749},{"price":45296.1,"qty":0.35380000},{"price":45297.5,"qty":0.09945542},{"price":45299.5,"qty":0.18772827}],"checksum":3310070434}]}"#;
+    //Hand written on top of the snapshot, its checksum computed with a separate CRC32 implementation
+    const UPDATE: &str = r#"{"channel":"book","type":"update","data":[{"symbol":"BTC/USD","bids":[{"price":45283.5,"qty":0.0},{"price":45284.0,"qty":0.25}],"asks":[{"price":45285.2,"qty":0.5}],"checksum":2914067378,"timestamp":"2023-10-06T17:35:55.440295Z"}]}"#;
+
+    fn kraken() -> Kraken {
+        let (tx, _rx) = mpsc::channel(1);
+        let kraken = Kraken::new("", &["btcusd".to_string()], tx, VenueId(0), "kraken");
+        kraken.state.lock().expect("Error")[0].precision = Some(Precision { price: 1, qty: 8 });
+        kraken
+    }
+
+    fn level(price: f64, amount: f64) -> MarketDatSourceLevel {
+        MarketDatSourceLevel::from_f64(price, amount)
+    }
+
+    #[test]
+    fn test_symbol_mapping() {
+        assert_eq!(kraken().symbols, vec!["BTC/USD".to_string()]);
+    }
+
+    #[test]
+    fn test_checksum_field() {
+        let field =
+            |value: &str, precision| checksum_field(value.parse().expect("Error"), precision);
+        assert_eq!(field("0.05005", 5), "5005");
+        assert_eq!(field("0.5", 8), "50000000");
+        assert_eq!(field("1234.5", 1), "12345");
+        assert_eq!(field("0.00000", 5), "");
+    }
+
+    #[test]
+    fn test_normalize_snapshot_and_update() {
+        let kraken = kraken();
+        assert!(kraken.normalize(UPDATE).is_err());
+
+        let snap = kraken.normalize(SNAPSHOT).expect("Error");
+        assert_eq!(snap.venue, VenueId(0));
+        assert_eq!(snap.order_book.bids.len(), 10);
+        assert_eq!(
+            snap.order_book.bids[..2].to_vec(),
+            vec![level(45283.5, 0.1), level(45283.4, 1.54582015)]
+        );
+        assert_eq!(snap.order_book.asks[0], level(45285.2, 0.001));
+
+        let snap = kraken.normalize(UPDATE).expect("Error");
+        assert_eq!(
+            snap.order_book.bids[..2].to_vec(),
+            vec![level(45284.0, 0.25), level(45283.4, 1.54582015)]
+        );
+        assert_eq!(snap.order_book.asks[0], level(45285.2, 0.5));
+        assert_eq!(
+            snap.exchange_time,
+            Some(from_unix_us(1_696_613_755_440_295))
+        );
+        assert!(kraken.take_resubscribe().is_empty());
+    }
+
+    #[test]
+    fn test_normalize_checksum_mismatch() {
+        let kraken = kraken();
+        kraken.normalize(SNAPSHOT).expect("Error");
+        let update = UPDATE.replace("2914067378", "1");
+        assert!(kraken.normalize(&update).is_err());
+        assert_eq!(kraken.take_resubscribe(), vec!["BTC/USD"]);
+        //Updates are ignored until the next snapshot
+        assert!(kraken.normalize(UPDATE).is_err());
+        assert!(kraken.normalize(SNAPSHOT).is_ok());
+    }
+
+    #[test]
+    fn test_handle_response() {
+        let kraken = kraken();
+        kraken.reset();
+        let instrument = r#"{"channel":"instrument","type":"snapshot","data":{"assets":[],"pairs":[{"symbol":"BTC/USD","price_precision":1,"qty_precision":8},{"symbol":"ETH/BTC","price_precision":5,"qty_precision":8}]}}"#;
+        assert_eq!(kraken.handle_response(instrument), Ok(true));
+        assert_eq!(
+            kraken.state.lock().expect("Error")[0].precision,
+            Some(Precision { price: 1, qty: 8 })
+        );
+        assert_eq!(kraken.handle_response(instrument), Ok(false));
+        assert_eq!(
+            kraken.handle_response(r#"{"channel":"heartbeat"}"#),
+            Ok(false)
+        );
+        assert!(kraken
+            .handle_response(
+                r#"{"method":"subscribe","success":false,"error":"Currency pair not supported"}"#
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_several_symbols() {
+        let (tx, _rx) = mpsc::channel(1);
+        let kraken = Kraken::new(
+            "",
+            &["ethbtc".to_string(), "btcusd".to_string()],
+            tx,
+            VenueId(0),
+            "kraken",
+        );
+        let instrument = r#"{"channel":"instrument","type":"snapshot","data":{"assets":[],"pairs":[{"symbol":"BTC/USD","price_precision":1,"qty_precision":8},{"symbol":"ETH/BTC","price_precision":5,"qty_precision":8}]}}"#;
+        assert_eq!(kraken.handle_response(instrument), Ok(true));
+        assert_eq!(
+            kraken.state.lock().expect("Error")[1].precision,
+            Some(Precision { price: 1, qty: 8 })
+        );
+
+        let snapshot = r#"{"channel":"book","type":"snapshot","data":[{"symbol":"ETH/BTC","bids":[{"price":0.06512,"qty":0.5}],"asks":[{"price":0.06513,"qty":1.0}],"checksum":0}]}"#;
+        //The checksum doesn't match, only ETH/BTC is subscribed again
+        assert!(kraken.normalize(snapshot).is_err());
+        assert_eq!(kraken.take_resubscribe(), vec!["ETH/BTC"]);
+        assert!(kraken.normalize(SNAPSHOT).is_ok());
+    }
+
+    #[test]
+    fn test_book_depths() {
+        let (tx, _rx) = mpsc::channel(1);
+        let kraken = Kraken::new(
+            "",
+            &[
+                "ethbtc".to_string(),
+                "btcusd".to_string(),
+                "ethusd".to_string(),
+            ],
+            tx,
+            VenueId(0),
+            "kraken",
+        )
+        .with_depths(&[20, 5, 50]);
+        assert_eq!(kraken.book_depth(InstrumentId(0)), 25);
+        assert_eq!(kraken.book_depth(InstrumentId(1)), 10);
+        assert_eq!(kraken.book_depth(InstrumentId(2)), 100);
+
+        let symbols: Vec<&String> = kraken.symbols.iter().collect();
+        let messages = kraken.book_messages("subscribe", &symbols[..2]);
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].contains(r#""depth":25"#) && messages[0].contains("ETH/BTC"));
+        assert!(messages[1].contains(r#""depth":10"#) && messages[1].contains("BTC/USD"));
+        assert_eq!(kraken.book_messages("subscribe", &symbols).len(), 3);
+    }
+}
@@ -0,0 +1,72 @@
+use crate::aggregator::BookUpdate;
+use crate::market_data_source::InstrumentId;
+use std::sync::{Arc, RwLock};
+
+//The latest update published for each instrument, indexed by instrument id. The aggregator replaces
+//it on every publish, so it can be read at any time without waiting for the next update
+#[derive(Debug)]
+pub struct LastValueCache {
+    updates: Vec<RwLock<Option<Arc<BookUpdate>>>>,
+}
+
+impl LastValueCache {
+    pub fn new(instrument_count: usize) -> LastValueCache {
+        LastValueCache {
+            updates: (0..instrument_count).map(|_| RwLock::new(None)).collect(),
+        }
+    }
+
+    pub fn set(&self, instrument: InstrumentId, update: Arc<BookUpdate>) {
+        if let Some(Ok(mut latest)) = self.updates.get(instrument.index()).map(|u| u.write()) {
+            *latest = Some(update);
+        }
+    }
+
+    //None until the instrument is published for the first time
+    pub fn get(&self, instrument: InstrumentId) -> Option<Arc<BookUpdate>> {
+        self.updates.get(instrument.index())?.read().ok()?.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::encoded_summary::EncodedSummary;
+    use crate::orderbook::Summary;
+    use crate::scale::Scale;
+    use std::time::SystemTime;
+
+    fn update(symbol: &str) -> Arc<BookUpdate> {
+        let summary = Summary {
+            symbol: symbol.to_string(),
+            ..Default::default()
+        };
+        Arc::new(BookUpdate {
+            encoded: EncodedSummary::new(&summary),
+            summary,
+            books: Vec::new(),
+            full_books: Vec::new(),
+            scale: Scale::default(),
+            generated_at: SystemTime::now(),
+        })
+    }
+
+    #[test]
+    fn test_set_and_get() {
+        let cache = LastValueCache::new(2);
+        assert!(cache.get(InstrumentId(0)).is_none());
+        cache.set(InstrumentId(0), update("ethbtc"));
+        cache.set(InstrumentId(1), update("btcusdt"));
+        cache.set(InstrumentId(1), update("btcusdt2"));
+        cache.set(InstrumentId(2), update("ltcbtc"));
+        assert_eq!(
+            cache.get(InstrumentId(0)).expect("Error").summary.symbol,
+            "ethbtc"
+        );
+        assert_eq!(
+            cache.get(InstrumentId(1)).expect("Error").summary.symbol,
+            "btcusdt2"
+        );
+        assert!(cache.get(InstrumentId(2)).is_none());
+    }
+}
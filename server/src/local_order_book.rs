@@ -0,0 +1,148 @@
+use crate::market_data_source::{FullOrderBook, MarketDatSourceLevel, OrderBook, MAX_DEPTH};
+use crate::scale::Decimal;
+use std::collections::BTreeMap;
+
+//A full price level book maintained by the sources that receive incremental updates.
+//Only the top levels are copied into the OrderBook sent to the aggregator
+#[derive(Debug, Default, Clone)]
+pub struct LocalOrderBook {
+    bids: BTreeMap<Decimal, Decimal>,
+    asks: BTreeMap<Decimal, Decimal>,
+}
+
+impl LocalOrderBook {
+    pub fn clear(&mut self) {
+        self.bids.clear();
+        self.asks.clear();
+    }
+
+    //An amount of 0 removes the price level
+    pub fn update_bid(&mut self, level: &MarketDatSourceLevel) {
+        Self::update(&mut self.bids, level);
+    }
+
+    pub fn update_ask(&mut self, level: &MarketDatSourceLevel) {
+        Self::update(&mut self.asks, level);
+    }
+
+    fn update(side: &mut BTreeMap<Decimal, Decimal>, level: &MarketDatSourceLevel) {
+        if level.amount.is_zero() {
+            side.remove(&level.price);
+        } else {
+            side.insert(level.price, level.amount);
+        }
+    }
+
+    //Remove the levels beyond depth on both sides, for venues whose updates assume a fixed depth
+    pub fn truncate(&mut self, depth: usize) {
+        while self.bids.len() > depth {
+            self.bids.pop_first();
+        }
+        while self.asks.len() > depth {
+            self.asks.pop_last();
+        }
+    }
+
+    pub fn bids(&self) -> impl Iterator<Item = MarketDatSourceLevel> + '_ {
+        self.bids
+            .iter()
+            .rev()
+            .map(|(price, amount)| MarketDatSourceLevel {
+                price: *price,
+                amount: *amount,
+            })
+    }
+
+    pub fn asks(&self) -> impl Iterator<Item = MarketDatSourceLevel> + '_ {
+        self.asks
+            .iter()
+            .map(|(price, amount)| MarketDatSourceLevel {
+                price: *price,
+                amount: *amount,
+            })
+    }
+
+    //The best depth bids and asks, at most MAX_DEPTH
+    pub fn top(&self, depth: usize) -> OrderBook {
+        let depth = depth.min(MAX_DEPTH);
+        OrderBook {
+            bids: self.bids().take(depth).collect(),
+            asks: self.asks().take(depth).collect(),
+        }
+    }
+
+    pub fn full(&self) -> FullOrderBook {
+        FullOrderBook {
+            bids: self.bids().collect(),
+            asks: self.asks().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::market_data_source::{DEFAULT_DEPTH, MAX_DEPTH};
+
+    fn level(price: f64, amount: f64) -> MarketDatSourceLevel {
+        MarketDatSourceLevel::from_f64(price, amount)
+    }
+
+    #[test]
+    fn test_update_and_remove_levels() {
+        let mut book = LocalOrderBook::default();
+        book.update_bid(&level(1.0, 1.0));
+        book.update_bid(&level(3.0, 1.0));
+        book.update_bid(&level(2.0, 1.0));
+        book.update_bid(&level(2.0, 5.0));
+        book.update_ask(&level(5.0, 1.0));
+        book.update_ask(&level(4.0, 1.0));
+        book.update_ask(&level(4.0, 0.0));
+
+        let top = book.top(DEFAULT_DEPTH);
+        assert_eq!(
+            top.bids.to_vec(),
+            vec![level(3.0, 1.0), level(2.0, 5.0), level(1.0, 1.0)]
+        );
+        assert_eq!(top.asks.to_vec(), vec![level(5.0, 1.0)]);
+        assert_eq!(book.full(), FullOrderBook::from(&top));
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut book = LocalOrderBook::default();
+        for n in 1..5 {
+            book.update_bid(&level(n as f64, 1.0));
+            book.update_ask(&level(10.0 + n as f64, 1.0));
+        }
+        book.truncate(2);
+        let top = book.top(DEFAULT_DEPTH);
+        assert_eq!(top.bids.to_vec(), vec![level(4.0, 1.0), level(3.0, 1.0)]);
+        assert_eq!(top.asks.to_vec(), vec![level(11.0, 1.0), level(12.0, 1.0)]);
+    }
+
+    #[test]
+    fn test_top_is_capped_at_depth() {
+        let mut book = LocalOrderBook::default();
+        for n in 0..DEFAULT_DEPTH * 2 {
+            book.update_bid(&level(n as f64, 1.0));
+            book.update_ask(&level(100.0 + n as f64, 1.0));
+        }
+        let top = book.top(DEFAULT_DEPTH);
+        assert_eq!(top.bids.len(), DEFAULT_DEPTH);
+        assert_eq!(top.bids[0].price.to_f64(), (DEFAULT_DEPTH * 2 - 1) as f64);
+        assert_eq!(top.asks[0].price.to_f64(), 100.0);
+        assert_eq!(
+            top.asks[DEFAULT_DEPTH - 1].price.to_f64(),
+            100.0 + (DEFAULT_DEPTH - 1) as f64
+        );
+        assert_eq!(book.top(3).asks.len(), 3);
+
+        //The depth of an instrument cannot be more than an OrderBook holds
+        for n in 0..MAX_DEPTH {
+            book.update_bid(&level(-(n as f64), 1.0));
+        }
+        assert_eq!(book.top(MAX_DEPTH + 1).bids.len(), MAX_DEPTH);
+        assert_eq!(book.top(MAX_DEPTH + 1).asks.len(), DEFAULT_DEPTH * 2);
+    }
+}
@@ -1,39 +1,72 @@
 mod aggregator;
 mod aggregator_grpc_server;
+mod backoff;
 mod binance;
 mod bitstamp;
+mod book_validator;
+mod coinbase;
+mod config;
+mod connection;
+mod cost_to_fill;
+mod cross_detector;
+mod encoded_summary;
+mod kraken;
+mod last_value_cache;
+mod local_order_book;
 mod market_data_source;
 mod market_data_source_container;
 mod multi_receiver_channels;
+mod okx;
+mod payload;
+mod permessage_deflate;
+mod scale;
+mod sequence_tracker;
+mod summary_view;
+#[cfg(test)]
+mod test_stand_in;
+mod venue_registry;
 
 pub mod orderbook {
     tonic::include_proto!("orderbook");
+    //The service is defined in build.rs
+    include!(concat!(
+        env!("OUT_DIR"),
+        "/orderbook.OrderbookAggregator.rs"
+    ));
 }
 
 use crate::market_data_source_container::MarketSources;
-use aggregator::Aggregator;
+use aggregator::{Aggregator, BookChannel, CrossChannel};
 use aggregator_grpc_server::OrderBookAggregatorService;
-use binance::Binance;
-use bitstamp::Bitstamp;
+use binance::{Binance, BinanceMode};
+use bitstamp::{Bitstamp, BitstampMode};
+use book_validator::BookValidator;
+use coinbase::Coinbase;
+use config::ServerConfig;
 use fast_log::config::Config;
 use fast_log::consts::LogSize;
 use fast_log::plugin::file_split::RollingType;
 use fast_log::plugin::packer::LogPacker;
+use kraken::Kraken;
+use last_value_cache::LastValueCache;
 use log::LevelFilter;
-use market_data_source::{OrderBookSnap, DEFAULT_DEPTH};
+use market_data_source::{MarketDataSource, OrderBookSnap};
 use market_data_source_container::MarketDataSourceContainer;
 use multi_receiver_channels::MultiReceiverChannel;
-use orderbook::Summary;
+use okx::Okx;
+use scale::Scale;
 use std::env;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
 use tokio::{sync::mpsc, sync::mpsc::Receiver, sync::mpsc::Sender};
 use tonic::transport::Server;
+use venue_registry::VenueRegistry;
 
 const GRPC_SERVER_URL: &str = "[::1]:";
 const CHANNEL_SIZE: usize = 10000;
 const DEFAULT_CURRENCY: &str = "ethbtc";
 const GRPC_SERVER_DEFAULT_PORT: usize = 30253;
+const METRICS_LOG_INTERVAL: Duration = Duration::from_secs(60);
 
 #[tokio::main]
 pub async fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
@@ -54,12 +87,21 @@ pub async fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
 
     let (ob_tx, ob_rx): (Sender<OrderBookSnap>, Receiver<OrderBookSnap>) =
         mpsc::channel(CHANNEL_SIZE);
-    let ob_tx2 = ob_tx.clone();
 
-    let currency = match args.len() {
+    //A comma separated list of instruments, each one gets its instrument id in this order
+    let mut currencies: Vec<String> = Vec::new();
+    let arg = match args.len() {
         1 => DEFAULT_CURRENCY,
         _ => &args[1],
     };
+    for currency in arg.split(',').map(|c| c.trim().to_lowercase()) {
+        if !currency.is_empty() && !currencies.contains(&currency) {
+            currencies.push(currency);
+        }
+    }
+    if currencies.is_empty() {
+        panic!("No currency given");
+    }
 
     let grpc_port = match args.len() {
         1 | 2 => GRPC_SERVER_DEFAULT_PORT,
@@ -68,28 +110,123 @@ pub async fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
             .expect("Cannot get the grpc port from command line argument"),
     };
 
+    let config = match args.len() {
+        1..=3 => ServerConfig::default(),
+        _ => ServerConfig::load(&args[3]).expect("Cannot load the config file"),
+    };
+
+    //The number of levels of each instrument
+    let depths: Vec<usize> = currencies
+        .iter()
+        .map(|currency| config.depth(currency))
+        .collect();
+    //The decimals of the prices and amounts of each instrument
+    let scales: Vec<Scale> = currencies
+        .iter()
+        .map(|currency| config.scale(currency))
+        .collect();
+
     let mut grpc_url = GRPC_SERVER_URL.to_owned();
     grpc_url.push_str(&grpc_port.to_string());
 
-    let bitstamp = Bitstamp::new("wss://ws.bitstamp.net", &currency, ob_tx, "bitstamp");
-    let binance = Binance::new(
-        "wss://stream.binance.com:9443/stream?streams=",
-        &currency,
-        ob_tx2,
-        "binance",
-    ); //ethbtc@depth10@100ms
+    //Each configured venue gets the next id, which is also the index of its book in the aggregator
+    let mut registry = VenueRegistry::new();
     let mut mds_container = MarketDataSourceContainer::new();
-    mds_container.add(MarketSources::Bitstamp(bitstamp));
-    mds_container.add(MarketSources::Binance(binance));
+    for venue in &config.venues {
+        let id = registry
+            .register(&venue.name(), venue.exchange)
+            .expect("Invalid venue configuration");
+        let source =
+            MarketSources::from_config(venue, id, &currencies, &depths, ob_tx.clone(), &config);
+        registry.set_reconnect_signal(id, source.reconnect_signal());
+        if config.fee_adjusted {
+            registry.set_taker_fee(id, venue.taker_fee_bps / 10_000.0);
+        }
+        mds_container.add(source);
+    }
+    if registry.is_empty() {
+        panic!("No venue configured");
+    }
+    for (id, venue) in registry.iter() {
+        log::info!(
+            "Venue {} ({}) registered as {:?}, taker fee {}",
+            venue.name,
+            venue.exchange,
+            id,
+            venue.taker_fee
+        );
+    }
+    for ((currency, depth), scale) in currencies.iter().zip(&depths).zip(&scales) {
+        log::info!(
+            "Instrument {} with {} levels, {} price and {} amount decimals",
+            currency,
+            depth,
+            scale.price_decimals,
+            scale.amount_decimals
+        );
+    }
+    drop(ob_tx);
     mds_container.wait_resources();
 
-    let mrc = Arc::new(Mutex::new(MultiReceiverChannel::<Summary>::new()));
-    let mut aggregator = Aggregator::new(ob_rx, mrc.clone());
+    //One merged book, and so one channel to the grpc clients, per instrument
+    let mrc_array: Vec<BookChannel> = currencies
+        .iter()
+        .map(|_| Arc::new(MultiReceiverChannel::new(config.max_subscriber_lag)))
+        .collect();
+    let last_value_cache = Arc::new(LastValueCache::new(currencies.len()));
+    let cross_channel: CrossChannel =
+        Arc::new(MultiReceiverChannel::new(config.max_subscriber_lag));
+    let validator = BookValidator::new(&config.validation, currencies.len(), &registry);
+    let validation_metrics = validator.metrics();
+    let registry = Arc::new(registry);
+    let mut aggregator = Aggregator::new(
+        ob_rx,
+        currencies.clone(),
+        mrc_array.clone(),
+        last_value_cache.clone(),
+        cross_channel.clone(),
+        registry.clone(),
+        Duration::from_millis(config.max_book_age_ms),
+    )
+    .with_depths(depths.clone())
+    .with_scales(scales)
+    .with_validator(validator);
+    let sequence_metrics = aggregator.sequence_metrics();
     let aggregator_stream = tokio::spawn(async move {
         aggregator.run().await;
     });
 
-    let server: OrderBookAggregatorService = OrderBookAggregatorService::new(mrc.clone());
+    //Log the counters of the slow grpc clients of each instrument, and of the rejected and out of
+    //order books of each venue
+    let mut channel_metrics = Vec::new();
+    for (symbol, mrc) in currencies.iter().zip(&mrc_array) {
+        channel_metrics.push((symbol.clone(), mrc.metrics()));
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(METRICS_LOG_INTERVAL);
+        loop {
+            interval.tick().await;
+            for (symbol, metrics) in channel_metrics.iter().filter(|(_, m)| !m.is_empty()) {
+                log::info!("Slow subscribers of {}: {}", symbol, metrics);
+            }
+            if !validation_metrics.is_empty() {
+                log::info!("Rejected books: {}", validation_metrics);
+            }
+            if !sequence_metrics.is_empty() {
+                log::info!("Out of order books: {}", sequence_metrics);
+            }
+        }
+    });
+
+    let server: OrderBookAggregatorService = OrderBookAggregatorService::new(
+        currencies,
+        depths,
+        mrc_array,
+        last_value_cache,
+        cross_channel,
+        registry,
+        config.slow_consumer_policy,
+    );
 
     Server::builder()
         .add_service(orderbook::orderbook_aggregator_server::OrderbookAggregatorServer::new(server))
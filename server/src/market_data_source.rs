@@ -1,22 +1,34 @@
-use arrayvec::ArrayVec;
 use async_trait::async_trait;
 use enum_dispatch::enum_dispatch;
-use num_derive::FromPrimitive;
 use orderbook::Level;
-use serde::{de, de::IgnoredAny, de::SeqAccess, Deserialize, Deserializer};
+use serde::{de, Deserialize, Deserializer};
 use serde_json::Value;
 use std::fmt;
-use tokio::sync::mpsc::Sender;
-use variant_count::VariantCount;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc::Sender, Notify};
 
+use crate::backoff::Backoff;
+use crate::connection;
 use crate::orderbook;
+use crate::scale::{Decimal, Scale};
+use crate::venue_registry::VenueId;
 
+//The depth of an instrument when it is not configured
 pub const DEFAULT_DEPTH: usize = 10;
+//The most levels an instrument can be configured with
+pub const MAX_DEPTH: usize = 100;
 
-#[derive(VariantCount, Debug, FromPrimitive, Copy, Clone, PartialEq)]
+//The exchanges a venue can connect to, the venues themselves are registered at startup
+#[derive(Debug, Deserialize, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
 pub enum Exchange {
-    Binance = 0,
-    Bitstamp = 1,
+    Binance,
+    Bitstamp,
+    Kraken,
+    Coinbase,
+    Okx,
 }
 
 impl fmt::Display for Exchange {
@@ -24,121 +36,423 @@ impl fmt::Display for Exchange {
         match self {
             Exchange::Binance => write!(f, "binance"),
             Exchange::Bitstamp => write!(f, "bitstamp"),
+            Exchange::Kraken => write!(f, "kraken"),
+            Exchange::Coinbase => write!(f, "coinbase"),
+            Exchange::Okx => write!(f, "okx"),
         }
     }
 }
 
+//Quote currencies used to split our lowercase symbols, longer ones first so that usdt is not read as usd
+const QUOTE_CURRENCIES: [&str; 12] = [
+    "usdt", "usdc", "busd", "tusd", "btc", "eth", "bnb", "dai", "eur", "usd", "gbp", "jpy",
+];
+
+//Split a symbol like ethbtc into its base and quote currency, for the venues that need them separated
+pub fn split_currency(currency: &str) -> Option<(&str, &str)> {
+    QUOTE_CURRENCIES.iter().find_map(|quote| {
+        currency
+            .strip_suffix(quote)
+            .filter(|base| !base.is_empty())
+            .map(|base| (base, *quote))
+    })
+}
+
+//The top of the book of a venue, up to the depth of the instrument. The levels are sized to the depth
+//when the source builds the book, which is then moved to the aggregator and shared with the published
+//updates rather than copied
 #[derive(Debug, Default, Clone)]
 pub struct OrderBook {
-    pub bids: ArrayVec<MarketDatSourceLevel, DEFAULT_DEPTH>,
-    pub asks: ArrayVec<MarketDatSourceLevel, DEFAULT_DEPTH>,
+    pub bids: Vec<MarketDatSourceLevel>,
+    pub asks: Vec<MarketDatSourceLevel>,
 }
 
 impl OrderBook {
     pub fn new() -> OrderBook {
         OrderBook {
-            bids: ArrayVec::<MarketDatSourceLevel, DEFAULT_DEPTH>::new(),
-            asks: ArrayVec::<MarketDatSourceLevel, DEFAULT_DEPTH>::new(),
+            bids: Vec::new(),
+            asks: Vec::new(),
         }
     }
+
+    //A copy of the best depth levels of each side, at most MAX_DEPTH
+    pub fn top(&self, depth: usize) -> OrderBook {
+        let depth = depth.min(MAX_DEPTH);
+        OrderBook {
+            bids: self.bids.iter().take(depth).copied().collect(),
+            asks: self.asks.iter().take(depth).copied().collect(),
+        }
+    }
+
+    //Keep the best depth levels of each side
+    pub fn truncate(&mut self, depth: usize) {
+        self.bids.truncate(depth);
+        self.asks.truncate(depth);
+    }
+}
+
+//All the levels a source holds, the best first. The merge only uses the top of the book, the cost to
+//fill quotes walk all the levels
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FullOrderBook {
+    pub bids: Vec<MarketDatSourceLevel>,
+    pub asks: Vec<MarketDatSourceLevel>,
+}
+
+impl From<&OrderBook> for FullOrderBook {
+    fn from(order_book: &OrderBook) -> FullOrderBook {
+        FullOrderBook {
+            bids: order_book.bids.to_vec(),
+            asks: order_book.asks.to_vec(),
+        }
+    }
+}
+
+//All the levels of the book of a source, read when a cost to fill quote needs them rather than copied
+//with every update. The sources maintaining a local book hand out a view of it, so it is read under
+//the lock of the source and can be ahead of the latest update
+#[derive(Clone)]
+pub struct FullBookRef(Arc<dyn Fn() -> FullOrderBook + Send + Sync>);
+
+impl FullBookRef {
+    //The book of an instrument among the books of a source, indexed by instrument id. full returns
+    //None while the book is not synced, which reads as an empty book
+    pub fn shared<T: Send + 'static>(
+        books: &Arc<Mutex<Vec<T>>>,
+        instrument: InstrumentId,
+        full: fn(&T) -> Option<FullOrderBook>,
+    ) -> FullBookRef {
+        let books = books.clone();
+        FullBookRef(Arc::new(move || {
+            books
+                .lock()
+                .ok()
+                .and_then(|books| books.get(instrument.index()).and_then(full))
+                .unwrap_or_default()
+        }))
+    }
+
+    pub fn read(&self) -> FullOrderBook {
+        (self.0)()
+    }
+}
+
+//The levels of a snapshot, converted when read
+impl From<OrderBook> for FullBookRef {
+    fn from(order_book: OrderBook) -> FullBookRef {
+        FullBookRef(Arc::new(move || FullOrderBook::from(&order_book)))
+    }
 }
 
+impl fmt::Debug for FullBookRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FullBookRef")
+    }
+}
+
+//A price level exactly as the venue sent it, e.g. ["0.06512300", "1.5"]
 #[derive(Debug, Deserialize, Copy, Clone, PartialEq)]
 pub struct MarketDatSourceLevel {
-    #[serde(deserialize_with = "de_f64_or_string_as_f64")]
-    pub price: f64,
-    #[serde(deserialize_with = "de_f64_or_string_as_f64")]
-    pub amount: f64,
+    pub price: Decimal,
+    pub amount: Decimal,
 }
 
 impl MarketDatSourceLevel {
-    pub fn to_orderbook_level(&self, exchange: String) -> Level {
+    #[cfg(test)]
+    pub fn from_f64(price: f64, amount: f64) -> MarketDatSourceLevel {
+        MarketDatSourceLevel {
+            price: Decimal::from_f64(price).expect("Error"),
+            amount: Decimal::from_f64(amount).expect("Error"),
+        }
+    }
+
+    //The price and the amount in ticks of the instrument, exact for the books passing the off_tick rule
+    pub fn to_orderbook_level(self, exchange: String, scale: &Scale) -> Level {
+        let price_units = scale.price_units(self.price);
+        let amount_units = scale.amount_units(self.amount);
         Level {
-            amount: self.amount,
-            price: self.price,
+            amount: scale.amount(amount_units),
+            price: scale.price(price_units),
+            raw_price: scale.price(price_units),
             exchange,
+            venues: Vec::new(),
+            price_units,
+            amount_units,
+            raw_price_units: price_units,
         }
     }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct MarketDataSourceData {
-    #[serde(deserialize_with = "deserialie_vector")]
-    pub bids: ArrayVec<MarketDatSourceLevel, DEFAULT_DEPTH>,
-    #[serde(deserialize_with = "deserialie_vector")]
-    pub asks: ArrayVec<MarketDatSourceLevel, DEFAULT_DEPTH>,
+    pub bids: Vec<MarketDatSourceLevel>,
+    pub asks: Vec<MarketDatSourceLevel>,
 }
 
-fn deserialie_vector<'de, D>(
+pub fn de_u64_or_string_as_u64<'de, D: Deserializer<'de>>(
     deserializer: D,
-) -> Result<ArrayVec<MarketDatSourceLevel, DEFAULT_DEPTH>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    struct SeqVisitor;
-    impl<'de> de::Visitor<'de> for SeqVisitor {
-        type Value = ArrayVec<MarketDatSourceLevel, DEFAULT_DEPTH>;
-        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            formatter.write_str("BitstampData")
-        }
-        fn visit_seq<V>(self, mut seq: V) -> Result<Self::Value, V::Error>
-        where
-            V: SeqAccess<'de>,
-        {
-            let mut elems = ArrayVec::<MarketDatSourceLevel, DEFAULT_DEPTH>::new();
-
-            while let (false, Some(elem)) =
-                (elems.is_full(), seq.next_element::<MarketDatSourceLevel>()?)
-            {
-                elems.push(elem);
-            }
-
-            // Consume remaining elements, doesn't work if we don't consume all
-            while seq.next_element::<IgnoredAny>()?.is_some() {}
-            Ok(elems)
-        }
-    }
-    deserializer.deserialize_seq(SeqVisitor {})
-}
-
-pub fn de_f64_or_string_as_f64<'de, D: Deserializer<'de>>(
-    deserializer: D,
-) -> Result<f64, D::Error> {
+) -> Result<u64, D::Error> {
     Ok(match Value::deserialize(deserializer)? {
         Value::String(s) => s.parse().map_err(de::Error::custom)?,
         Value::Number(num) => num
-            .as_f64()
+            .as_u64()
             .ok_or_else(|| de::Error::custom("Invalid number"))?,
         _ => return Err(de::Error::custom("wrong type")),
     })
 }
 
+//Microseconds since the unix epoch, the unit of the times of the grpc messages
+pub fn unix_time_us(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or_default()
+}
+
+pub fn from_unix_us(us: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_micros(us)
+}
+
+//An RFC 3339 time in UTC, e.g. 2023-10-06T17:35:55.440295Z as sent by Kraken and Coinbase. The digits
+//beyond the microseconds are dropped, None when it doesn't parse
+pub fn parse_rfc3339(time: &str) -> Option<SystemTime> {
+    let time = humantime::parse_rfc3339(time).ok()?;
+    Some(from_unix_us(unix_time_us(time)))
+}
+
+//Index of an instrument in the list of instruments given to the server
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct InstrumentId(pub u16);
+
+impl InstrumentId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
 #[derive(Debug)]
 pub struct OrderBookSnap {
-    pub exchange: Exchange,
+    pub venue: VenueId,
+    pub instrument: InstrumentId,
     pub order_book: OrderBook,
+    //Set by the sources holding more levels than the top of the book, None when order_book is all
+    //they hold
+    pub full_book: Option<FullBookRef>,
+    //When the venue generated the book, None when its messages have no time
+    pub exchange_time: Option<SystemTime>,
+    //When the message of the book was received, set by the connection
+    pub received_at: SystemTime,
+    //The sequence id of the book on its venue, increasing with each message, None when the venue
+    //has none
+    pub sequence: Option<u64>,
+    //The sequence id of the message right before the book, to detect the missed messages. None when
+    //the sequence ids of the venue are not contiguous
+    pub previous_sequence: Option<u64>,
 }
 
 impl OrderBookSnap {
-    pub fn new(exchange: Exchange) -> OrderBookSnap {
+    pub fn new(venue: VenueId, instrument: InstrumentId) -> OrderBookSnap {
         OrderBookSnap {
-            exchange,
+            venue,
+            instrument,
             order_book: OrderBook::new(),
+            full_book: None,
+            exchange_time: None,
+            received_at: SystemTime::now(),
+            sequence: None,
+            previous_sequence: None,
         }
     }
 }
 
 #[derive(Clone)]
 pub struct MarketDataSourceInfo {
-    pub address: &'static str,
-    pub currency: String,
+    pub address: String,
+    //Our lowercase symbols, e.g. ethbtc, all subscribed on the same connection
+    pub currencies: Vec<String>,
     pub sender: Sender<OrderBookSnap>,
-    pub name: &'static str,
+    //The number of levels sent for each currency, DEFAULT_DEPTH unless configured
+    pub depths: Vec<usize>,
+    pub venue: VenueId,
+    pub name: String,
+    pub reconnects: Arc<AtomicUsize>,
+    //Notified when the connection has to be restarted, e.g. by the stale feed watchdog. The signal is
+    //stored when the source is not waiting for it, e.g. while it is processing a message
+    pub reconnect: Arc<Notify>,
+    //Offer the permessage-deflate extension when connecting, for the venues which compress with it
+    pub permessage_deflate: bool,
+}
+
+impl MarketDataSourceInfo {
+    pub fn new(
+        address: &str,
+        currencies: &[String],
+        sender: Sender<OrderBookSnap>,
+        venue: VenueId,
+        name: &str,
+    ) -> MarketDataSourceInfo {
+        MarketDataSourceInfo {
+            address: address.to_string(),
+            currencies: currencies.to_vec(),
+            sender,
+            depths: vec![DEFAULT_DEPTH; currencies.len()],
+            venue,
+            name: name.to_string(),
+            reconnects: Arc::new(AtomicUsize::new(0)),
+            reconnect: Arc::new(Notify::new()),
+            permessage_deflate: false,
+        }
+    }
+
+    pub fn instrument(&self, currency: &str) -> Result<InstrumentId, String> {
+        self.currencies
+            .iter()
+            .position(|c| c == currency)
+            .map(|index| InstrumentId(index as u16))
+            .ok_or_else(|| "Receive depth for incorrect currency".to_string())
+    }
+
+    pub fn instruments(&self) -> impl Iterator<Item = InstrumentId> {
+        (0..self.currencies.len()).map(|index| InstrumentId(index as u16))
+    }
+
+    //The number of levels sent for an instrument, never more than an OrderBook holds
+    pub fn depth(&self, instrument: InstrumentId) -> usize {
+        self.depths
+            .get(instrument.index())
+            .map_or(DEFAULT_DEPTH, |depth| (*depth).min(MAX_DEPTH))
+    }
+
+    //Called whenever the connection is lost. The first time after a healthy connection, an empty
+    //book is sent for each instrument so the aggregator stops merging the stale ones, then wait for
+    //the backoff delay
+    pub async fn prepare_reconnect(&self, backoff: &mut Backoff, reason: &str) {
+        if backoff.attempt() == 0 {
+            for instrument in self.instruments() {
+                let order_book_snap = OrderBookSnap::new(self.venue, instrument);
+                if let Err(e) = self.sender.send(order_book_snap).await {
+                    log::error!("Failed to clear orderbook for {}: {e}", self.name);
+                }
+            }
+        }
+
+        let delay = backoff.next_delay();
+        let reconnects = self.reconnects.fetch_add(1, Ordering::Relaxed) + 1;
+        log::warn!(
+            "{} disconnected: {}. Reconnecting in {:?} (attempt {}, {} reconnects in total)",
+            self.name,
+            reason,
+            delay,
+            backoff.attempt(),
+            reconnects
+        );
+        tokio::time::sleep(delay).await;
+    }
 }
 
 #[async_trait]
 #[enum_dispatch]
 pub trait MarketDataSource {
     async fn run(&self);
+    fn reconnect_signal(&self) -> Arc<Notify>;
+}
+
+//Every venue runs on the connection engine, which owns the socket lifecycle
+#[async_trait]
+impl<V: Venue> MarketDataSource for V {
+    async fn run(&self) {
+        connection::run(self).await;
+    }
+
+    fn reconnect_signal(&self) -> Arc<Notify> {
+        self.info().reconnect.clone()
+    }
+}
+
+//What the connection engine should do with a message that is not market data
+pub enum Control {
+    //Nothing to do, e.g. an acknowledgement or a heartbeat
+    Ignore,
+    //Send these messages to the venue
+    Send(Vec<String>),
+    //Restart the connection for the given reason
+    Reconnect(String),
+}
+
+//The venue specific part of a market data source, the connection engine does the rest
+#[async_trait]
+pub trait Venue: Send + Sync {
+    fn info(&self) -> &MarketDataSourceInfo;
+    fn url(&self) -> String;
+    //Messages sent right after connecting
+    fn subscribe_messages(&self) -> Vec<String>;
     fn normalize(&self, msg: &str) -> Result<OrderBookSnap, String>;
+
+    //When true, the first message after subscribing must pass is_successful
+    fn requires_ack(&self) -> bool {
+        false
+    }
+
+    fn is_successful(&self, _msg: &str) -> bool {
+        true
+    }
+
+    //None when the message is market data for normalize
+    fn handle_control(&self, _msg: &str) -> Option<Control> {
+        None
+    }
+
+    //Called on each new connection, before subscribing, to drop the state of the previous one
+    fn on_connect(&self) {}
+
+    //Called before normalize, e.g. to load a REST snapshot. An error restarts the connection
+    async fn prepare(&self, _msg: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    //Messages to send after normalize, e.g. to resubscribe after a checksum mismatch
+    fn pending_requests(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    //Application level heartbeat message, sent when nothing is received for the interval
+    fn heartbeat(&self) -> Option<(Duration, &'static str)> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_currency() {
+        assert_eq!(split_currency("ethbtc"), Some(("eth", "btc")));
+        assert_eq!(split_currency("btcusdt"), Some(("btc", "usdt")));
+        assert_eq!(split_currency("btcusd"), Some(("btc", "usd")));
+        assert_eq!(split_currency("btc"), None);
+        assert_eq!(split_currency("abcxyz"), None);
+    }
+
+    #[test]
+    fn test_parse_rfc3339() {
+        let time = parse_rfc3339("2023-10-06T17:35:55.440295Z").expect("Error");
+        assert_eq!(unix_time_us(time), 1_696_613_755_440_295);
+        assert_eq!(
+            parse_rfc3339("2024-02-29T00:00:00Z"),
+            Some(from_unix_us(1_709_164_800_000_000))
+        );
+        //Nanoseconds are truncated to microseconds
+        assert_eq!(
+            parse_rfc3339("1970-01-01T00:00:01.1234567Z"),
+            Some(from_unix_us(1_123_456))
+        );
+        for time in [
+            "",
+            "2023-10-06 17:35:55Z",
+            "2023-13-06T17:35:55Z",
+            "2023-10-06T17:35:55+01:00",
+        ] {
+            assert_eq!(parse_rfc3339(time), None);
+        }
+    }
 }
@@ -1,7 +1,11 @@
-use crate::market_data_source::MarketDataSource;
-use crate::OrderBookSnap;
-use crate::{Binance, Bitstamp};
+use crate::config::{ServerConfig, VenueConfig};
+use crate::market_data_source::{Exchange, MarketDataSource, OrderBookSnap};
+use crate::venue_registry::VenueId;
+use crate::{binance, bitstamp, coinbase, kraken, okx};
+use crate::{Binance, BinanceMode, Bitstamp, BitstampMode, Coinbase, Kraken, Okx};
 use enum_dispatch::enum_dispatch;
+use std::sync::Arc;
+use tokio::sync::{mpsc::Sender, Notify};
 
 pub struct MarketDataSourceContainer {
     market_data_sources: Vec<MarketSources>,
@@ -15,6 +19,95 @@ pub struct MarketDataSourceContainer {
 pub enum MarketSources {
     Binance(Binance),
     Bitstamp(Bitstamp),
+    Kraken(Kraken),
+    Coinbase(Coinbase),
+    Okx(Okx),
+}
+
+impl MarketSources {
+    //Create the market data source of a configured venue, the endpoints default to the public ones.
+    //depths is the number of levels of each currency
+    pub fn from_config(
+        venue: &VenueConfig,
+        id: VenueId,
+        currencies: &[String],
+        depths: &[usize],
+        sender: Sender<OrderBookSnap>,
+        config: &ServerConfig,
+    ) -> MarketSources {
+        let name = venue.name();
+        let address = |default: &'static str| venue.address.as_deref().unwrap_or(default);
+        let rest_address = |default: &'static str| venue.rest_address.as_deref().unwrap_or(default);
+        let source = match venue.exchange {
+            Exchange::Binance => match config.binance_mode {
+                BinanceMode::Snapshot => MarketSources::Binance(
+                    Binance::new(address(binance::ADDRESS), currencies, sender, id, &name)
+                        .with_depths(depths),
+                ),
+                BinanceMode::Diff => MarketSources::Binance(
+                    Binance::new_diff(
+                        address(binance::ADDRESS),
+                        rest_address(binance::REST_ADDRESS),
+                        currencies,
+                        sender,
+                        id,
+                        &name,
+                    )
+                    .with_depths(depths),
+                ),
+            },
+            Exchange::Bitstamp => match config.bitstamp_mode {
+                BitstampMode::Snapshot => MarketSources::Bitstamp(
+                    Bitstamp::new(address(bitstamp::ADDRESS), currencies, sender, id, &name)
+                        .with_depths(depths),
+                ),
+                BitstampMode::Diff => MarketSources::Bitstamp(
+                    Bitstamp::new_diff(
+                        address(bitstamp::ADDRESS),
+                        rest_address(bitstamp::REST_ADDRESS),
+                        currencies,
+                        sender,
+                        id,
+                        &name,
+                    )
+                    .with_depths(depths),
+                ),
+            },
+            Exchange::Kraken => MarketSources::Kraken(
+                Kraken::new(address(kraken::ADDRESS), currencies, sender, id, &name)
+                    .with_depths(depths),
+            ),
+            Exchange::Coinbase => MarketSources::Coinbase(
+                Coinbase::new(address(coinbase::ADDRESS), currencies, sender, id, &name)
+                    .with_depths(depths),
+            ),
+            Exchange::Okx => MarketSources::Okx(
+                Okx::new(address(okx::ADDRESS), currencies, sender, id, &name).with_depths(depths),
+            ),
+        };
+        source.with_permessage_deflate(venue.permessage_deflate)
+    }
+
+    //Offer the permessage-deflate extension when connecting
+    fn with_permessage_deflate(self, permessage_deflate: bool) -> MarketSources {
+        match self {
+            MarketSources::Binance(b) => {
+                MarketSources::Binance(b.with_permessage_deflate(permessage_deflate))
+            }
+            MarketSources::Bitstamp(b) => {
+                MarketSources::Bitstamp(b.with_permessage_deflate(permessage_deflate))
+            }
+            MarketSources::Kraken(k) => {
+                MarketSources::Kraken(k.with_permessage_deflate(permessage_deflate))
+            }
+            MarketSources::Coinbase(c) => {
+                MarketSources::Coinbase(c.with_permessage_deflate(permessage_deflate))
+            }
+            MarketSources::Okx(o) => {
+                MarketSources::Okx(o.with_permessage_deflate(permessage_deflate))
+            }
+        }
+    }
 }
 
 impl MarketDataSourceContainer {
@@ -46,6 +139,9 @@ macro_rules! start_exchange {
         match $source {
             MarketSources::Binance(b) => Self::start(b).await,
             MarketSources::Bitstamp(b) => Self::start(b).await,
+            MarketSources::Kraken(k) => Self::start(k).await,
+            MarketSources::Coinbase(c) => Self::start(c).await,
+            MarketSources::Okx(o) => Self::start(o).await,
         }
     };
 }
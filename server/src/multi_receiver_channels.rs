@@ -1,41 +1,250 @@
-use tokio::sync::mpsc::{self, Receiver, Sender};
+use serde::Deserialize;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError, error::TryRecvError};
 
+//What to do with a subscriber that falls behind by more than the buffer of the channel
+#[derive(Debug, Deserialize, Copy, Clone, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    //Only keep the latest update
+    Conflate,
+    //Skip the oldest updates, the subscriber is told how many updates it lost
+    #[default]
+    DropOldest,
+    //Disconnect the subscriber
+    Disconnect,
+}
+
+//Counters of the updates not delivered to the slow subscribers of a channel
+#[derive(Debug, Default)]
+pub struct ChannelMetrics {
+    pub conflated: AtomicU64,
+    pub dropped: AtomicU64,
+    pub disconnected: AtomicU64,
+}
+
+impl ChannelMetrics {
+    pub fn is_empty(&self) -> bool {
+        self.conflated.load(Ordering::Relaxed) == 0
+            && self.dropped.load(Ordering::Relaxed) == 0
+            && self.disconnected.load(Ordering::Relaxed) == 0
+    }
+}
+
+impl fmt::Display for ChannelMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "conflated: {}, dropped: {}, disconnected: {}",
+            self.conflated.load(Ordering::Relaxed),
+            self.dropped.load(Ordering::Relaxed),
+            self.disconnected.load(Ordering::Relaxed)
+        )
+    }
+}
+
+//Publishes each update once into a ring buffer shared by all the subscribers. Sending doesn't depend on
+//the number of subscribers and never waits for them: a subscriber more than the buffer size behind
+//notices it on its next read and applies its slow consumer policy. No lock is needed to send or subscribe
 #[derive(Debug)]
 pub struct MultiReceiverChannel<T: Clone> {
-    senders: Vec<Sender<T>>,
+    sender: broadcast::Sender<T>,
+    metrics: Arc<ChannelMetrics>,
+    next_id: AtomicUsize,
 }
 
 impl<T: Clone> MultiReceiverChannel<T> {
-    pub fn new() -> MultiReceiverChannel<T> {
+    pub fn new(buffer: usize) -> MultiReceiverChannel<T> {
+        let (sender, _) = broadcast::channel(buffer.max(1));
         MultiReceiverChannel {
-            senders: Vec::<Sender<T>>::new(),
+            sender,
+            metrics: Arc::new(ChannelMetrics::default()),
+            next_id: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn send(&self, t: T) {
+        //Only fails when there is no subscriber
+        let _ = self.sender.send(t);
+    }
+
+    pub fn create_receiver(&self, policy: SlowConsumerPolicy) -> Receiver<T> {
+        Receiver {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            rx: self.sender.subscribe(),
+            policy,
+            metrics: self.metrics.clone(),
+            gaps: 0,
+            lagging: false,
+            disconnected: false,
+        }
+    }
+
+    pub fn metrics(&self) -> Arc<ChannelMetrics> {
+        self.metrics.clone()
+    }
+}
+
+#[derive(Debug)]
+pub struct Receiver<T: Clone> {
+    id: usize,
+    rx: broadcast::Receiver<T>,
+    policy: SlowConsumerPolicy,
+    metrics: Arc<ChannelMetrics>,
+    //Updates dropped since the last call to take_gaps
+    gaps: u64,
+    //Whether the subscriber is behind, to only log when it starts lagging
+    lagging: bool,
+    disconnected: bool,
+}
+
+impl<T: Clone> Receiver<T> {
+    //None once the subscriber is disconnected, or the channel is dropped and the buffered updates are read
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            if self.disconnected {
+                return None;
+            }
+            match self.rx.recv().await {
+                Ok(t) => {
+                    return Some(self.received(t));
+                }
+                Err(RecvError::Lagged(skipped)) => self.lagged(skipped),
+                Err(RecvError::Closed) => {
+                    return None;
+                }
+            }
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Option<T> {
+        loop {
+            if self.disconnected {
+                return None;
+            }
+            match self.rx.try_recv() {
+                Ok(t) => {
+                    return Some(self.received(t));
+                }
+                Err(TryRecvError::Lagged(skipped)) => self.lagged(skipped),
+                Err(_) => {
+                    return None;
+                }
+            }
         }
     }
 
-    pub async fn send(&mut self, t: T)
-    where
-    T: Clone 
-    {
-        if self.senders.is_empty() {
-            return;
+    fn received(&mut self, mut t: T) -> T {
+        if self.policy == SlowConsumerPolicy::Conflate {
+            while let Ok(latest) = self.rx.try_recv() {
+                t = latest;
+                self.metrics.conflated.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if self.lagging && self.rx.is_empty() {
+            log::info!("Subscriber {} caught up", self.id);
+            self.lagging = false;
         }
+        t
+    }
 
-        let mut i = 0 as usize;
-        while i < self.senders.len() {
-            let sender = &self.senders[i];
-            match sender.send(t.clone()).await {
-                Ok(_) => { i += 1; },
-                Err(e) => { 
-                    log::error!("MultiReceiverChannel failed to send {:?}", e.to_string()); 
-                    self.senders.swap_remove(i);
-                } 
+    fn lagged(&mut self, skipped: u64) {
+        match self.policy {
+            SlowConsumerPolicy::Conflate => {
+                self.metrics.conflated.fetch_add(skipped, Ordering::Relaxed);
             }
-        };
+            SlowConsumerPolicy::DropOldest => {
+                self.gaps += skipped;
+                self.metrics.dropped.fetch_add(skipped, Ordering::Relaxed);
+            }
+            SlowConsumerPolicy::Disconnect => {
+                let disconnected = self.metrics.disconnected.fetch_add(1, Ordering::Relaxed) + 1;
+                log::warn!(
+                    "Subscriber {} disconnected, {} updates behind ({} disconnected in total)",
+                    self.id,
+                    skipped,
+                    disconnected
+                );
+                self.disconnected = true;
+                return;
+            }
+        }
+        if !self.lagging {
+            log::warn!(
+                "Subscriber {} is lagging, {:?} ({} in total)",
+                self.id,
+                self.policy,
+                self.metrics
+            );
+            self.lagging = true;
+        }
+    }
+
+    //The number of updates dropped since the last call
+    pub fn take_gaps(&mut self) -> u64 {
+        std::mem::take(&mut self.gaps)
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.disconnected
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_policies() {
+        let channel = MultiReceiverChannel::<usize>::new(2);
+        let mut conflate = channel.create_receiver(SlowConsumerPolicy::Conflate);
+        let mut drop_oldest = channel.create_receiver(SlowConsumerPolicy::DropOldest);
+        let mut disconnect = channel.create_receiver(SlowConsumerPolicy::Disconnect);
+        for n in 0..3 {
+            channel.send(n);
+        }
+        let metrics = channel.metrics();
+
+        assert_eq!(conflate.recv().await, Some(2));
+        assert_eq!(conflate.try_recv(), None);
+        assert_eq!(metrics.conflated.load(Ordering::Relaxed), 2);
+
+        assert_eq!(drop_oldest.recv().await, Some(1));
+        assert_eq!(drop_oldest.take_gaps(), 1);
+        assert_eq!(drop_oldest.recv().await, Some(2));
+        assert_eq!(drop_oldest.take_gaps(), 0);
+        assert_eq!(metrics.dropped.load(Ordering::Relaxed), 1);
+
+        assert_eq!(disconnect.recv().await, None);
+        assert!(disconnect.is_disconnected());
+        assert_eq!(metrics.disconnected.load(Ordering::Relaxed), 1);
+
+        channel.send(3);
+        assert_eq!(conflate.recv().await, Some(3));
+        assert_eq!(drop_oldest.recv().await, Some(3));
+        assert_eq!(disconnect.recv().await, None);
+
+        //The buffered updates are still read once the channel is dropped
+        channel.send(4);
+        drop(channel);
+        assert_eq!(drop_oldest.recv().await, Some(4));
+        assert_eq!(drop_oldest.recv().await, None);
+        assert!(!drop_oldest.is_disconnected());
     }
 
-    pub fn create_receiver(&mut self, buffer: usize) -> Receiver<T> {
-        let (tx, rx) = mpsc::channel(buffer);
-        self.senders.push(tx);
-        rx
+    #[tokio::test]
+    async fn test_recv_waits_for_update() {
+        let channel = MultiReceiverChannel::<usize>::new(1);
+        let mut rx = channel.create_receiver(SlowConsumerPolicy::DropOldest);
+        let handle = tokio::spawn(async move { rx.recv().await });
+        tokio::task::yield_now().await;
+        channel.send(1);
+        assert_eq!(handle.await.expect("Error"), Some(1));
     }
 }
@@ -0,0 +1,439 @@
+use crate::market_data_source::*;
+use crate::scale::Decimal;
+use crate::venue_registry::VenueId;
+use async_trait::async_trait;
+use serde::{de::IgnoredAny, Deserialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+
+pub const ADDRESS: &str = "wss://ws.okx.com:8443/ws/v5/public";
+const CHANNEL: &str = "books";
+const EVENT_TAG: &str = r#""event":"#;
+const CHECKSUM_DEPTH: usize = 25;
+//OKX closes the connection when nothing is sent for 30 seconds
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+
+#[derive(Debug, Deserialize)]
+struct OkxJson {
+    arg: OkxArg,
+    action: String,
+    data: Vec<OkxBookData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OkxArg {
+    #[serde(rename = "instId")]
+    inst_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct OkxBookData {
+    bids: Vec<OkxLevel>,
+    asks: Vec<OkxLevel>,
+    checksum: i32,
+    //In milliseconds since the unix epoch
+    #[serde(deserialize_with = "de_u64_or_string_as_u64")]
+    ts: u64,
+    //-1 for the previous one of a snapshot. Both are equal when the book didn't change
+    #[serde(rename = "seqId", default)]
+    seq_id: i64,
+    #[serde(rename = "prevSeqId", default)]
+    prev_seq_id: i64,
+}
+
+//["8476.98", "415", "0", "13"]: price, size, deprecated and number of orders.
+//The strings are kept as received because the checksum is computed on them
+#[derive(Debug, Deserialize, Clone)]
+struct OkxLevel(String, String, IgnoredAny, IgnoredAny);
+
+impl OkxLevel {
+    fn to_level(&self) -> Result<MarketDatSourceLevel, String> {
+        Ok(MarketDatSourceLevel {
+            price: self
+                .0
+                .parse()
+                .map_err(|_| format!("Invalid price {}", self.0))?,
+            amount: self
+                .1
+                .parse()
+                .map_err(|_| format!("Invalid size {}", self.1))?,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct OkxBook {
+    bids: BTreeMap<Decimal, OkxLevel>,
+    asks: BTreeMap<Decimal, OkxLevel>,
+    //Set once the snapshot is received, updates before that are ignored
+    synced: bool,
+    //Set when the checksum doesn't match, the channel has to be subscribed again
+    resubscribe: bool,
+}
+
+impl OkxBook {
+    fn update(side: &mut BTreeMap<Decimal, OkxLevel>, level: &OkxLevel) -> Result<(), String> {
+        let parsed = level.to_level()?;
+        if parsed.amount.is_zero() {
+            side.remove(&parsed.price);
+        } else {
+            side.insert(parsed.price, level.clone());
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, data: &OkxBookData) -> Result<(), String> {
+        for level in &data.bids {
+            Self::update(&mut self.bids, level)?;
+        }
+        for level in &data.asks {
+            Self::update(&mut self.asks, level)?;
+        }
+        Ok(())
+    }
+
+    //OKX checksum: the top 25 bids and asks interleaved as bid1:ask1:bid2:ask2..., each level
+    //written price:size with the original strings, CRC32'ed and read as a signed integer
+    fn checksum(&self) -> i32 {
+        let mut bids = self.bids.values().rev().take(CHECKSUM_DEPTH);
+        let mut asks = self.asks.values().take(CHECKSUM_DEPTH);
+        let mut fields = Vec::with_capacity(CHECKSUM_DEPTH * 4);
+        for _ in 0..CHECKSUM_DEPTH {
+            for level in [bids.next(), asks.next()].into_iter().flatten() {
+                fields.push(level.0.as_str());
+                fields.push(level.1.as_str());
+            }
+        }
+        crc32fast::hash(fields.join(":").as_bytes()) as i32
+    }
+
+    fn top(&self, depth: usize) -> Result<OrderBook, String> {
+        let depth = depth.min(MAX_DEPTH);
+        let mut order_book = OrderBook {
+            bids: Vec::with_capacity(depth.min(self.bids.len())),
+            asks: Vec::with_capacity(depth.min(self.asks.len())),
+        };
+        for level in self.bids.values().rev().take(depth) {
+            order_book.bids.push(level.to_level()?);
+        }
+        for level in self.asks.values().take(depth) {
+            order_book.asks.push(level.to_level()?);
+        }
+        Ok(order_book)
+    }
+
+    fn full(&self) -> Result<FullOrderBook, String> {
+        Ok(FullOrderBook {
+            bids: self
+                .bids
+                .values()
+                .rev()
+                .map(OkxLevel::to_level)
+                .collect::<Result<_, _>>()?,
+            asks: self
+                .asks
+                .values()
+                .map(OkxLevel::to_level)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct Okx {
+    info: MarketDataSourceInfo,
+    //OKX instrument ids, e.g. ETH-BTC for ethbtc, in the order of the currencies
+    inst_ids: Vec<String>,
+    //One book per instrument
+    state: Arc<Mutex<Vec<OkxBook>>>,
+}
+
+impl Okx {
+    pub fn new(
+        address: &str,
+        currencies: &[String],
+        sender: Sender<OrderBookSnap>,
+        venue: VenueId,
+        name: &str,
+    ) -> Self {
+        let inst_ids = currencies
+            .iter()
+            .map(|currency| match split_currency(currency) {
+                Some((base, quote)) => format!("{}-{}", base, quote).to_uppercase(),
+                None => currency.to_uppercase(),
+            })
+            .collect();
+        Okx {
+            info: MarketDataSourceInfo::new(address, currencies, sender, venue, name),
+            inst_ids,
+            state: Arc::new(Mutex::new(
+                currencies.iter().map(|_| OkxBook::default()).collect(),
+            )),
+        }
+    }
+
+    //The number of levels sent for each currency, DEFAULT_DEPTH for the ones not given
+    pub fn with_depths(mut self, depths: &[usize]) -> Self {
+        self.info.depths = depths.to_vec();
+        self
+    }
+
+    //Offer the permessage-deflate extension when connecting
+    pub fn with_permessage_deflate(mut self, permessage_deflate: bool) -> Self {
+        self.info.permessage_deflate = permessage_deflate;
+        self
+    }
+
+    fn subscribe_message(&self, op: &str, inst_ids: &[&String]) -> String {
+        let args: Vec<Value> = inst_ids
+            .iter()
+            .map(|inst_id| json!({"channel": CHANNEL, "instId": inst_id}))
+            .collect();
+        json!({"op": op, "args": args}).to_string()
+    }
+
+    //Handle the event messages (subscribe, unsubscribe and error) and the pong. Returns Err when the
+    //connection has to be restarted, Ok(false) when the message is not one of them
+    fn handle_event(&self, msg: &str) -> Result<bool, String> {
+        if msg == "pong" {
+            return Ok(true);
+        }
+        //Avoid parsing the book messages twice
+        if !msg.contains(EVENT_TAG) {
+            return Ok(false);
+        }
+        let json_msg: Value = serde_json::from_str(msg).map_err(|e| e.to_string())?;
+        match json_msg["event"].as_str() {
+            Some("error") => Err(format!(
+                "request rejected: {} {}",
+                json_msg["code"], json_msg["msg"]
+            )),
+            Some(_) => Ok(true),
+            None => Ok(false),
+        }
+    }
+
+    fn reset(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.iter_mut().for_each(|book| *book = OkxBook::default());
+        }
+    }
+
+    //The instruments whose book has to be subscribed again
+    fn take_resubscribe(&self) -> Vec<&String> {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return Vec::new(),
+        };
+        state
+            .iter_mut()
+            .zip(&self.inst_ids)
+            .filter(|(book, _)| book.resubscribe)
+            .map(|(book, inst_id)| {
+                book.resubscribe = false;
+                inst_id
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl Venue for Okx {
+    fn info(&self) -> &MarketDataSourceInfo {
+        &self.info
+    }
+
+    fn url(&self) -> String {
+        self.info.address.clone()
+    }
+
+    fn subscribe_messages(&self) -> Vec<String> {
+        let inst_ids: Vec<&String> = self.inst_ids.iter().collect();
+        vec![self.subscribe_message("subscribe", &inst_ids)]
+    }
+
+    fn normalize(&self, msg: &str) -> Result<OrderBookSnap, String> {
+        let json_msg: OkxJson = serde_json::from_str(msg).map_err(|e| e.to_string())?;
+        let instrument = self
+            .inst_ids
+            .iter()
+            .position(|inst_id| *inst_id == json_msg.arg.inst_id)
+            .map(|index| InstrumentId(index as u16))
+            .ok_or_else(|| "Receive depth for incorrect currency".to_string())?;
+
+        let mut state = self.state.lock().map_err(|e| e.to_string())?;
+        let state = &mut state[instrument.index()];
+        for data in &json_msg.data {
+            match json_msg.action.as_str() {
+                "snapshot" => {
+                    state.bids.clear();
+                    state.asks.clear();
+                    state.synced = true;
+                }
+                "update" if state.synced => {}
+                "update" => return Err("Update received before the snapshot".to_string()),
+                action => return Err(format!("Unknown book action {action}")),
+            }
+            state.apply(data)?;
+
+            let checksum = state.checksum();
+            if checksum != data.checksum {
+                state.synced = false;
+                state.resubscribe = true;
+                return Err(format!(
+                    "Checksum mismatch, expected {} but got {}",
+                    data.checksum, checksum
+                ));
+            }
+        }
+
+        let mut order_book_snap = OrderBookSnap::new(self.info.venue, instrument);
+        order_book_snap.order_book = state.top(self.info.depth(instrument))?;
+        order_book_snap.full_book = Some(FullBookRef::shared(
+            &self.state,
+            instrument,
+            |state: &OkxBook| state.synced.then(|| state.full().ok()).flatten(),
+        ));
+        order_book_snap.exchange_time = json_msg
+            .data
+            .last()
+            .map(|data| from_unix_us(data.ts * 1000));
+        if let (Some(first), Some(last)) = (json_msg.data.first(), json_msg.data.last()) {
+            if last.seq_id > 0 && last.seq_id != first.prev_seq_id {
+                order_book_snap.sequence = Some(last.seq_id as u64);
+                order_book_snap.previous_sequence =
+                    (first.prev_seq_id >= 0).then_some(first.prev_seq_id as u64);
+            }
+        }
+        Ok(order_book_snap)
+    }
+
+    fn handle_control(&self, msg: &str) -> Option<Control> {
+        match self.handle_event(msg) {
+            Ok(true) => Some(Control::Ignore),
+            Ok(false) => None,
+            Err(e) => Some(Control::Reconnect(e)),
+        }
+    }
+
+    fn on_connect(&self) {
+        self.reset();
+    }
+
+    fn pending_requests(&self) -> Vec<String> {
+        let inst_ids = self.take_resubscribe();
+        if inst_ids.is_empty() {
+            return Vec::new();
+        }
+        log::warn!(
+            "Resubscribe the book of {:?} on {}",
+            inst_ids,
+            self.info.name
+        );
+        ["unsubscribe", "subscribe"]
+            .iter()
+            .map(|op| self.subscribe_message(op, &inst_ids))
+            .collect()
+    }
+
+    fn heartbeat(&self) -> Option<(Duration, &'static str)> {
+        Some((PING_INTERVAL, "ping"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    //The two books of the checksum examples of the OKX v5 websocket documentation, the snapshot is the
+    //first one, "3366.1:7:3366.8:9:3366:6:3368:8", and the update turns it into the second one,
+    //"3366.1:7:3366.8:9:3368:8:3372:8". The checksums are the signed CRC32 of these strings
+    const SNAPSHOT: &str = r#"{"arg":{"channel":"books","instId":"ETH-USDT"},"action":"snapshot","data":[{"asks":[["3366.8","9","10","3"],["3368","8","3","4"]],"bids":[["3366.1","7","0","3"],["3366","6","3","4"]],"ts":"1696613755440","checksum":-1881014294,"seqId":100,"prevSeqId":-1}]}"#;
+    const UPDATE: &str = r#"{"arg":{"channel":"books","instId":"ETH-USDT"},"action":"update","data":[{"asks":[["3372","8","3","4"]],"bids":[["3366","0","0","0"]],"ts":"1696613755540","checksum":831078360,"seqId":101,"prevSeqId":100}]}"#;
+
+    fn okx() -> Okx {
+        let (tx, _rx) = mpsc::channel(1);
+        Okx::new(
+            "",
+            &["ethusdt".to_string(), "btcusdt".to_string()],
+            tx,
+            VenueId(0),
+            "okx",
+        )
+    }
+
+    fn level(price: f64, amount: f64) -> MarketDatSourceLevel {
+        MarketDatSourceLevel::from_f64(price, amount)
+    }
+
+    #[test]
+    fn test_inst_id_mapping() {
+        assert_eq!(okx().inst_ids, vec!["ETH-USDT", "BTC-USDT"]);
+    }
+
+    #[test]
+    fn test_normalize_snapshot_and_update() {
+        let okx = okx();
+        assert!(okx.normalize(UPDATE).is_err());
+
+        let snap = okx.normalize(SNAPSHOT).expect("Error");
+        assert_eq!(snap.venue, VenueId(0));
+        assert_eq!(snap.instrument, InstrumentId(0));
+        assert_eq!(
+            snap.order_book.bids.to_vec(),
+            vec![level(3366.1, 7.0), level(3366.0, 6.0)]
+        );
+        assert_eq!(
+            snap.order_book.asks.to_vec(),
+            vec![level(3366.8, 9.0), level(3368.0, 8.0)]
+        );
+        assert_eq!((snap.previous_sequence, snap.sequence), (None, Some(100)));
+
+        let snap = okx.normalize(UPDATE).expect("Error");
+        assert_eq!(snap.order_book.bids.to_vec(), vec![level(3366.1, 7.0)]);
+        assert_eq!(
+            snap.order_book.asks.to_vec(),
+            vec![level(3366.8, 9.0), level(3368.0, 8.0), level(3372.0, 8.0)]
+        );
+        assert_eq!(
+            snap.exchange_time,
+            Some(from_unix_us(1_696_613_755_540_000))
+        );
+        assert_eq!(
+            (snap.previous_sequence, snap.sequence),
+            (Some(100), Some(101))
+        );
+        assert!(okx.take_resubscribe().is_empty());
+    }
+
+    #[test]
+    fn test_normalize_checksum_mismatch() {
+        let okx = okx();
+        okx.normalize(SNAPSHOT).expect("Error");
+        let update = UPDATE.replace("831078360", "1");
+        assert!(okx.normalize(&update).is_err());
+        assert_eq!(okx.take_resubscribe(), vec!["ETH-USDT"]);
+        assert!(okx.pending_requests().is_empty());
+        //Updates are ignored until the next snapshot
+        assert!(okx.normalize(UPDATE).is_err());
+        assert!(okx.normalize(SNAPSHOT).is_ok());
+    }
+
+    #[test]
+    fn test_handle_event() {
+        let okx = okx();
+        assert_eq!(okx.handle_event("pong"), Ok(true));
+        assert_eq!(
+            okx.handle_event(r#"{"event":"subscribe","arg":{"channel":"books","instId":"ETH-USDT"},"connId":"a4d3ae55"}"#),
+            Ok(true)
+        );
+        assert!(okx
+            .handle_event(r#"{"event":"error","code":"60018","msg":"Wrong URL or channel:books,instId:ETH-XYZ doesn't exist","connId":"a4d3ae55"}"#)
+            .is_err());
+        assert_eq!(okx.handle_event(SNAPSHOT), Ok(false));
+    }
+}
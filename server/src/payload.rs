@@ -0,0 +1,79 @@
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use std::io::Read;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+//Turn a binary frame into the text given to normalize. Some venues compress their json, e.g. HTX
+//with gzip and OKX/Bybit with raw deflate, others send plain json in binary frames. The frames
+//compressed by the permessage-deflate extension are inflated by the client before, see the README
+pub fn decode_binary(data: &[u8]) -> Result<String, String> {
+    if data.starts_with(&GZIP_MAGIC) {
+        return inflate(GzDecoder::new(data));
+    }
+    if is_zlib_header(data) {
+        return inflate(ZlibDecoder::new(data));
+    }
+    if let Some(b'{' | b'[') = data.first() {
+        if let Ok(text) = std::str::from_utf8(data) {
+            return Ok(text.to_string());
+        }
+    }
+    //Raw deflate has no header to detect it, so it is the last resort
+    inflate(DeflateDecoder::new(data))
+}
+
+//The first byte is 0x78 for a 32K window, and the first two bytes as a big endian number are a multiple of 31
+fn is_zlib_header(data: &[u8]) -> bool {
+    match data {
+        [cmf, flg, ..] => *cmf == 0x78 && (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 == 0,
+        _ => false,
+    }
+}
+
+fn inflate(mut decoder: impl Read) -> Result<String, String> {
+    let mut text = String::new();
+    decoder
+        .read_to_string(&mut text)
+        .map_err(|e| format!("Cannot decompress binary message: {e}"))?;
+    Ok(text)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+    use std::io::Write;
+
+    const MSG: &str = r#"{"ch":"market.ethbtc.depth.step0","tick":{"bids":[[0.05312,1.5]],"asks":[[0.05313,0.75]]}}"#;
+
+    #[test]
+    fn test_decode_gzip() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(MSG.as_bytes()).expect("Error");
+        let data = encoder.finish().expect("Error");
+        assert_eq!(decode_binary(&data), Ok(MSG.to_string()));
+    }
+
+    #[test]
+    fn test_decode_zlib() {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(MSG.as_bytes()).expect("Error");
+        let data = encoder.finish().expect("Error");
+        assert_eq!(decode_binary(&data), Ok(MSG.to_string()));
+    }
+
+    #[test]
+    fn test_decode_raw_deflate() {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(MSG.as_bytes()).expect("Error");
+        let data = encoder.finish().expect("Error");
+        assert_eq!(decode_binary(&data), Ok(MSG.to_string()));
+    }
+
+    #[test]
+    fn test_decode_plain_and_invalid() {
+        assert_eq!(decode_binary(MSG.as_bytes()), Ok(MSG.to_string()));
+        assert!(decode_binary(&[0xff, 0xfe, 0xfd]).is_err());
+    }
+}
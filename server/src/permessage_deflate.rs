@@ -0,0 +1,102 @@
+//WebSocket client offering the permessage-deflate extension, for the venues configured with it.
+//tungstenite doesn't implement the extension, so these connections use soketto. The venue compresses
+//its frames when it accepts the offer, and sends them uncompressed otherwise
+use crate::connection::{WsSink, WsStream};
+use futures_util::{sink, stream};
+use soketto::connection::{Error, Receiver, Sender};
+use soketto::extension::deflate::Deflate;
+use soketto::handshake::{Client, ServerResponse};
+use soketto::{Data, Mode};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_native_tls::{native_tls, TlsConnector};
+use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+//A plain or a TLS connection
+trait Socket: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Socket for T {}
+
+type Connection = Compat<Box<dyn Socket>>;
+
+//Connect and offer the extension in the handshake, the messages are given and received as the
+//tungstenite ones, so the connection engine handles both clients the same way
+pub async fn connect(url: &url::Url) -> Result<(WsSink, WsStream), String> {
+    let host = url.host_str().ok_or("No host in the address")?;
+    let port = url
+        .port_or_known_default()
+        .ok_or("No port in the address")?;
+    let tcp = TcpStream::connect((host, port))
+        .await
+        .map_err(|e| e.to_string())?;
+    let socket: Box<dyn Socket> = match url.scheme() {
+        "ws" => Box::new(tcp),
+        "wss" => {
+            let connector = native_tls::TlsConnector::new().map_err(|e| e.to_string())?;
+            let tls = TlsConnector::from(connector)
+                .connect(host, tcp)
+                .await
+                .map_err(|e| e.to_string())?;
+            Box::new(tls)
+        }
+        scheme => return Err(format!("Unsupported scheme {scheme}")),
+    };
+
+    //The Host header has the port only when it is not the default one of the scheme
+    let host_header = match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    };
+    let resource = &url[url::Position::BeforePath..];
+    let mut client = Client::new(socket.compat(), &host_header, resource);
+    client.add_extension(Box::new(Deflate::new(Mode::Client)));
+    match client.handshake().await.map_err(|e| e.to_string())? {
+        ServerResponse::Accepted { .. } => {}
+        ServerResponse::Redirect {
+            status_code,
+            location,
+        } => return Err(format!("Redirected with {status_code} to {location}")),
+        ServerResponse::Rejected { status_code } => {
+            return Err(format!("Handshake rejected with {status_code}"))
+        }
+    }
+    let (sender, receiver) = client.into_builder().finish();
+    Ok((Box::pin(write(sender)), Box::pin(read(receiver))))
+}
+
+//soketto answers the pings itself, so only the text and close messages are sent
+fn write(sender: Sender<Connection>) -> impl futures_util::Sink<Message, Error = String> {
+    sink::unfold(sender, |mut sender, message: Message| async move {
+        match message {
+            Message::Text(text) => sender.send_text_owned(text).await,
+            Message::Binary(data) => sender.send_binary_mut(data).await,
+            Message::Close(_) => sender.close().await,
+            Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => Ok(()),
+        }
+        .map_err(|e| e.to_string())?;
+        sender.flush().await.map_err(|e| e.to_string())?;
+        Ok(sender)
+    })
+}
+
+//Ends when the venue closes the connection, or after the first I/O error as the connection is lost
+fn read(
+    receiver: Receiver<Connection>,
+) -> impl futures_util::Stream<Item = Result<Message, String>> {
+    stream::unfold(Some(receiver), |receiver| async move {
+        let mut receiver = receiver?;
+        let mut data = Vec::new();
+        match receiver.receive_data(&mut data).await {
+            Ok(Data::Text(_)) => {
+                let message = String::from_utf8(data)
+                    .map(Message::Text)
+                    .map_err(|e| e.to_string());
+                Some((message, Some(receiver)))
+            }
+            Ok(Data::Binary(_)) => Some((Ok(Message::Binary(data)), Some(receiver))),
+            Err(Error::Closed) => None,
+            Err(e @ Error::Io(_)) => Some((Err(e.to_string()), None)),
+            Err(e) => Some((Err(e.to_string()), Some(receiver))),
+        }
+    })
+}
@@ -0,0 +1,346 @@
+use serde::{de, Deserialize, Deserializer};
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+//The most decimals of a tick size. A value is held as an i64 number of ticks, so with 12 decimals it
+//is at most 9,223,372 and a larger value doesn't fit the ticks of the instrument
+pub const MAX_DECIMALS: u32 = 12;
+const DEFAULT_DECIMALS: u32 = 8;
+//The most decimals of a value sent by a venue, the trailing zeros aside. Any i64 mantissa times
+//10^MAX_PARSED_DECIMALS fits in an i128, so two decimals always compare exactly
+const MAX_PARSED_DECIMALS: u32 = 18;
+
+//A price or an amount exactly as the venue sent it, mantissa * 10^-decimals, e.g. "0.06512300" is
+//65123 with 6 decimals. It is parsed from the decimal string without going through a double
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Decimal {
+    mantissa: i64,
+    //Without the trailing zeros
+    decimals: u32,
+}
+
+impl Decimal {
+    pub fn new(mantissa: i64, decimals: u32) -> Result<Decimal, String> {
+        if decimals > MAX_PARSED_DECIMALS {
+            return Err(format!(
+                "At most {MAX_PARSED_DECIMALS} decimals, got {decimals}"
+            ));
+        }
+        let mut decimal = Decimal { mantissa, decimals };
+        while decimal.decimals > 0 && decimal.mantissa % 10 == 0 {
+            decimal.mantissa /= 10;
+            decimal.decimals -= 1;
+        }
+        Ok(decimal)
+    }
+
+    //The decimal written by the shortest representation of a double, which is the number a venue sent
+    //as a json number when it has at most 15 significant digits
+    pub fn from_f64(value: f64) -> Result<Decimal, String> {
+        if !value.is_finite() {
+            return Err(format!("Invalid number {value}"));
+        }
+        format!("{value}").parse()
+    }
+
+    //The nearest double, for the computations which are not exact anyway, e.g. the fee adjusted prices
+    //and the cost to fill quotes
+    pub fn to_f64(self) -> f64 {
+        self.mantissa as f64 / 10f64.powi(self.decimals as i32)
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.mantissa == 0
+    }
+
+    pub fn is_positive(self) -> bool {
+        self.mantissa > 0
+    }
+
+    //The exact number of ticks of 10^-decimals, an error when the value is not on a tick or the number
+    //of ticks doesn't fit in an i64
+    pub fn units(self, decimals: u32) -> Result<i64, String> {
+        let units = match self.decimals <= decimals {
+            true => 10i64
+                .checked_pow(decimals - self.decimals)
+                .and_then(|factor| self.mantissa.checked_mul(factor)),
+            false => {
+                let divisor = 10i64.pow(self.decimals - decimals);
+                if self.mantissa % divisor != 0 {
+                    return Err(format!("{self} is not on a tick of 10^-{decimals}"));
+                }
+                Some(self.mantissa / divisor)
+            }
+        };
+        units.ok_or_else(|| format!("{self} is too large for ticks of 10^-{decimals}"))
+    }
+
+    //The nearest number of ticks of 10^-decimals, half away from zero, saturated to the range of an i64
+    pub fn round_units(self, decimals: u32) -> i64 {
+        let mantissa = self.mantissa as i128;
+        let units = match self.decimals <= decimals {
+            true => mantissa * 10i128.pow(decimals - self.decimals),
+            false => {
+                let divisor = 10i128.pow(self.decimals - decimals);
+                let (quotient, remainder) = (mantissa / divisor, mantissa % divisor);
+                match 2 * remainder.abs() >= divisor {
+                    true => quotient + mantissa.signum(),
+                    false => quotient,
+                }
+            }
+        };
+        units.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+    }
+
+    fn scaled(self, decimals: u32) -> i128 {
+        self.mantissa as i128 * 10i128.pow(decimals - self.decimals)
+    }
+}
+
+impl FromStr for Decimal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Decimal, String> {
+        let invalid = || format!("Invalid decimal {s}");
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, s),
+        };
+        let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        if integer.is_empty() && fraction.is_empty() {
+            return Err(invalid());
+        }
+        let fraction = fraction.trim_end_matches('0');
+        let mut mantissa: i64 = 0;
+        for digit in integer.bytes().chain(fraction.bytes()) {
+            if !digit.is_ascii_digit() {
+                return Err(invalid());
+            }
+            mantissa = mantissa
+                .checked_mul(10)
+                .and_then(|mantissa| mantissa.checked_add((digit - b'0') as i64))
+                .ok_or_else(|| format!("Too many digits in {s}"))?;
+        }
+        let mantissa = if negative { -mantissa } else { mantissa };
+        Decimal::new(mantissa, fraction.len() as u32)
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let digits = self.mantissa.unsigned_abs().to_string();
+        let sign = if self.mantissa < 0 { "-" } else { "" };
+        let decimals = self.decimals as usize;
+        match decimals {
+            0 => write!(f, "{sign}{digits}"),
+            _ => {
+                let digits = format!("{digits:0>width$}", width = decimals + 1);
+                let (integer, fraction) = digits.split_at(digits.len() - decimals);
+                write!(f, "{sign}{integer}.{fraction}")
+            }
+        }
+    }
+}
+
+//By value, 1.50 equals 1.5
+impl Ord for Decimal {
+    fn cmp(&self, other: &Decimal) -> Ordering {
+        let decimals = self.decimals.max(other.decimals);
+        self.scaled(decimals).cmp(&other.scaled(decimals))
+    }
+}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Decimal) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Decimal) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Decimal {}
+
+//A decimal string, or a json number for the venues sending them, e.g. kraken
+impl<'de> Deserialize<'de> for Decimal {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Decimal, D::Error> {
+        struct DecimalVisitor;
+        impl<'de> de::Visitor<'de> for DecimalVisitor {
+            type Value = Decimal;
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a decimal string or number")
+            }
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Decimal, E> {
+                value.parse().map_err(E::custom)
+            }
+            fn visit_u64<E: de::Error>(self, value: u64) -> Result<Decimal, E> {
+                let mantissa = i64::try_from(value).map_err(E::custom)?;
+                Decimal::new(mantissa, 0).map_err(E::custom)
+            }
+            fn visit_i64<E: de::Error>(self, value: i64) -> Result<Decimal, E> {
+                Decimal::new(value, 0).map_err(E::custom)
+            }
+            fn visit_f64<E: de::Error>(self, value: f64) -> Result<Decimal, E> {
+                Decimal::from_f64(value).map_err(E::custom)
+            }
+        }
+        deserializer.deserialize_any(DecimalVisitor)
+    }
+}
+
+//The tick sizes of the prices and the amounts of an instrument. A value is held as an integer number
+//of ticks, e.g. 0.065123 is 6512300 with 8 decimals, so the prices of the venues compare exactly and
+//are published without the binary floating point noise
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Scale {
+    //The price tick is 10^-price_decimals
+    pub price_decimals: u32,
+    //The amount tick is 10^-amount_decimals
+    pub amount_decimals: u32,
+}
+
+impl Default for Scale {
+    fn default() -> Self {
+        Scale {
+            price_decimals: DEFAULT_DECIMALS,
+            amount_decimals: DEFAULT_DECIMALS,
+        }
+    }
+}
+
+impl Scale {
+    //The exact number of ticks of a price of a venue. The books with a price or an amount off the ticks
+    //are rejected by the off_tick rule, the values are only rounded to the nearest tick when the rule
+    //is disabled
+    pub fn price_units(&self, price: Decimal) -> i64 {
+        price.round_units(self.price_decimals)
+    }
+
+    pub fn amount_units(&self, amount: Decimal) -> i64 {
+        amount.round_units(self.amount_decimals)
+    }
+
+    //The ticks of a price times a fee factor, exact when there is no fee. Otherwise it is computed with
+    //doubles and rounded to the nearest tick
+    pub fn adjusted_price_units(&self, price: Decimal, factor: f64) -> i64 {
+        if factor == 1.0 {
+            return self.price_units(price);
+        }
+        (price.to_f64() * factor * 10f64.powi(self.price_decimals as i32)).round() as i64
+    }
+
+    //Whether a price and an amount of a venue are exactly on the ticks
+    pub fn check(&self, price: Decimal, amount: Decimal) -> Result<(), String> {
+        price.units(self.price_decimals)?;
+        amount.units(self.amount_decimals)?;
+        Ok(())
+    }
+
+    //The nearest double of a number of price ticks
+    pub fn price(&self, units: i64) -> f64 {
+        from_units(units, self.price_decimals)
+    }
+
+    pub fn amount(&self, units: i64) -> f64 {
+        from_units(units, self.amount_decimals)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.price_decimals > MAX_DECIMALS || self.amount_decimals > MAX_DECIMALS {
+            return Err(format!("At most {MAX_DECIMALS} decimals, got {:?}", self));
+        }
+        Ok(())
+    }
+}
+
+//The division is correctly rounded, so this is the nearest double of the exact value
+fn from_units(units: i64, decimals: u32) -> f64 {
+    units as f64 / 10f64.powi(decimals as i32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decimal(value: &str) -> Decimal {
+        value.parse().expect("Error")
+    }
+
+    #[test]
+    fn test_parse_decimal() {
+        assert_eq!(decimal("0.06512300").to_string(), "0.065123");
+        assert_eq!(decimal("0.06512300"), decimal("0.065123"));
+        assert_eq!(decimal("27000").to_string(), "27000");
+        assert_eq!(decimal("-1.50").to_string(), "-1.5");
+        assert_eq!(decimal(".5").to_string(), "0.5");
+        assert_eq!(decimal("0.000"), Decimal::default());
+        assert!(decimal("0.1") < decimal("0.10000000000000001"));
+        for invalid in [
+            "",
+            ".",
+            "1e-8",
+            "1,5",
+            "NaN",
+            "1.2.3",
+            "12345678901234567890",
+        ] {
+            assert!(invalid.parse::<Decimal>().is_err(), "{invalid}");
+        }
+        //The json numbers of the venues are read back from the double
+        assert_eq!(
+            Decimal::from_f64(0.05312).expect("Error"),
+            decimal("0.05312")
+        );
+        assert!(Decimal::from_f64(f64::NAN).is_err());
+
+        let level: (Decimal, Decimal) = serde_json::from_str(r#"["0.065", 1.5]"#).expect("Error");
+        assert_eq!(level, (decimal("0.065"), decimal("1.5")));
+    }
+
+    #[test]
+    fn test_exact_units() {
+        let scale = Scale::default();
+        assert_eq!(scale.price_units(decimal("0.06512300")), 6_512_300);
+        assert_eq!(scale.price(6_512_300), 0.065123);
+        //Exact where a double is not, 27000 at 12 decimals is beyond 2^53
+        assert_eq!(
+            decimal("27000.000000000001").units(12),
+            Ok(27_000_000_000_000_001)
+        );
+        assert!(decimal("9223373").units(12).is_err());
+        assert!(scale.check(decimal("0.065"), decimal("1.5")).is_ok());
+
+        let scale = Scale {
+            price_decimals: 2,
+            amount_decimals: 0,
+        };
+        //Off the ticks
+        assert!(decimal("20000.014").units(2).is_err());
+        assert!(scale.check(decimal("20000.01"), decimal("1.6")).is_err());
+        assert!(scale.check(decimal("20000.01"), decimal("2")).is_ok());
+        //Only rounded when the off_tick rule is disabled
+        assert_eq!(scale.price_units(decimal("20000.015")), 2_000_002);
+        assert_eq!(scale.price_units(decimal("-1.505")), -151);
+        assert_eq!(scale.amount_units(decimal("1.4")), 1);
+        assert_eq!(scale.price(-150), -1.5);
+
+        //The fee adjusted prices are rounded
+        assert_eq!(scale.adjusted_price_units(decimal("100"), 0.999), 9_990);
+        assert_eq!(scale.adjusted_price_units(decimal("100.01"), 1.0), 10_001);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Scale::default().validate().is_ok());
+        let scale = Scale {
+            price_decimals: MAX_DECIMALS + 1,
+            amount_decimals: 0,
+        };
+        assert!(scale.validate().is_err());
+    }
+}
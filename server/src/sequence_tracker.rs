@@ -0,0 +1,261 @@
+use crate::market_data_source::OrderBookSnap;
+use crate::venue_registry::{VenueId, VenueRegistry};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+//Counters of the books dropped for being out of order and of the gaps in the sequences of each venue
+#[derive(Debug)]
+pub struct SequenceMetrics {
+    //The name of each venue, indexed by venue id
+    venues: Vec<String>,
+    //Indexed by venue id
+    stale: Vec<AtomicU64>,
+    gaps: Vec<AtomicU64>,
+}
+
+impl SequenceMetrics {
+    fn new(registry: &VenueRegistry) -> SequenceMetrics {
+        SequenceMetrics {
+            venues: registry
+                .iter()
+                .map(|(_, venue)| venue.name.clone())
+                .collect(),
+            stale: registry.iter().map(|_| AtomicU64::new(0)).collect(),
+            gaps: registry.iter().map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    pub fn stale(&self, venue: VenueId) -> u64 {
+        self.stale
+            .get(venue.index())
+            .map_or(0, |counter| counter.load(Ordering::Relaxed))
+    }
+
+    pub fn gaps(&self, venue: VenueId) -> u64 {
+        self.gaps
+            .get(venue.index())
+            .map_or(0, |counter| counter.load(Ordering::Relaxed))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stale
+            .iter()
+            .chain(&self.gaps)
+            .all(|counter| counter.load(Ordering::Relaxed) == 0)
+    }
+}
+
+impl fmt::Display for SequenceMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let venues: Vec<String> = self
+            .venues
+            .iter()
+            .enumerate()
+            .map(|(index, name)| (name, VenueId(index as u16)))
+            .filter(|(_, venue)| self.stale(*venue) > 0 || self.gaps(*venue) > 0)
+            .map(|(name, venue)| {
+                format!(
+                    "{name} (stale: {}, gaps: {})",
+                    self.stale(venue),
+                    self.gaps(venue)
+                )
+            })
+            .collect();
+        write!(f, "{}", venues.join(", "))
+    }
+}
+
+//Where a book is in the sequence of its venue
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Sequence {
+    //After the latest book of the venue, or the venue has no sequence
+    InOrder,
+    //After the latest book of the venue, but this many messages in between were missed. The book is
+    //applied
+    Gap(u64),
+    //Not after the latest book of the venue, a duplicate or a replayed message. The book is dropped,
+    //so it doesn't overwrite a newer one
+    Stale { sequence: u64, latest: u64 },
+}
+
+//Follows the sequence of the books of each venue and instrument, before they are merged
+#[derive(Debug)]
+pub struct SequenceTracker {
+    //The sequence of the latest book of each venue, indexed by instrument id then venue id
+    latest: Vec<Vec<Option<u64>>>,
+    metrics: Arc<SequenceMetrics>,
+}
+
+impl SequenceTracker {
+    pub fn new(instruments: usize, registry: &VenueRegistry) -> SequenceTracker {
+        SequenceTracker {
+            latest: vec![vec![None; registry.len()]; instruments],
+            metrics: Arc::new(SequenceMetrics::new(registry)),
+        }
+    }
+
+    pub fn metrics(&self) -> Arc<SequenceMetrics> {
+        self.metrics.clone()
+    }
+
+    pub fn check(&mut self, order_book_snap: &OrderBookSnap) -> Sequence {
+        let index = order_book_snap.venue.index();
+        let latest = match self
+            .latest
+            .get_mut(order_book_snap.instrument.index())
+            .and_then(|latest| latest.get_mut(index))
+        {
+            Some(latest) => latest,
+            //Unknown to the aggregator, which rejects it
+            None => {
+                return Sequence::InOrder;
+            }
+        };
+        let sequence = match order_book_snap.sequence {
+            Some(sequence) => sequence,
+            None => {
+                //The empty books sent when the source disconnects, the sequences of some venues restart
+                //on a new connection
+                let order_book = &order_book_snap.order_book;
+                if order_book.bids.is_empty() && order_book.asks.is_empty() {
+                    *latest = None;
+                }
+                return Sequence::InOrder;
+            }
+        };
+        let previous = match *latest {
+            Some(previous) if sequence <= previous => {
+                self.metrics.stale[index].fetch_add(1, Ordering::Relaxed);
+                return Sequence::Stale {
+                    sequence,
+                    latest: previous,
+                };
+            }
+            previous => previous,
+        };
+        *latest = Some(sequence);
+        match (order_book_snap.previous_sequence, previous) {
+            (Some(expected), Some(previous)) if expected > previous => {
+                self.metrics.gaps[index].fetch_add(1, Ordering::Relaxed);
+                Sequence::Gap(expected - previous)
+            }
+            _ => Sequence::InOrder,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::market_data_source::{Exchange, InstrumentId, MarketDatSourceLevel};
+
+    const BINANCE: VenueId = VenueId(0);
+    const BITSTAMP: VenueId = VenueId(1);
+
+    fn registry() -> VenueRegistry {
+        let mut registry = VenueRegistry::new();
+        for exchange in [Exchange::Binance, Exchange::Bitstamp] {
+            registry
+                .register(&exchange.to_string(), exchange)
+                .expect("Error");
+        }
+        registry
+    }
+
+    fn snap(
+        venue: VenueId,
+        previous_sequence: Option<u64>,
+        sequence: Option<u64>,
+    ) -> OrderBookSnap {
+        let mut order_book_snap = OrderBookSnap::new(venue, InstrumentId(0));
+        order_book_snap
+            .order_book
+            .bids
+            .push(MarketDatSourceLevel::from_f64(1.0, 1.0));
+        order_book_snap.sequence = sequence;
+        order_book_snap.previous_sequence = previous_sequence;
+        order_book_snap
+    }
+
+    #[test]
+    fn test_stale_books() {
+        let mut tracker = SequenceTracker::new(2, &registry());
+        assert_eq!(
+            tracker.check(&snap(BINANCE, None, Some(10))),
+            Sequence::InOrder
+        );
+        assert_eq!(
+            tracker.check(&snap(BINANCE, None, Some(12))),
+            Sequence::InOrder
+        );
+        //A duplicate and a replayed book
+        assert_eq!(
+            tracker.check(&snap(BINANCE, None, Some(12))),
+            Sequence::Stale {
+                sequence: 12,
+                latest: 12
+            }
+        );
+        assert_eq!(
+            tracker.check(&snap(BINANCE, None, Some(11))),
+            Sequence::Stale {
+                sequence: 11,
+                latest: 12
+            }
+        );
+        //Each venue and instrument has its own sequence
+        assert_eq!(
+            tracker.check(&snap(BITSTAMP, None, Some(1))),
+            Sequence::InOrder
+        );
+        let mut order_book_snap = snap(BINANCE, None, Some(1));
+        order_book_snap.instrument = InstrumentId(1);
+        assert_eq!(tracker.check(&order_book_snap), Sequence::InOrder);
+
+        //Forgotten when the source disconnects
+        let disconnected = OrderBookSnap::new(BINANCE, InstrumentId(0));
+        assert_eq!(tracker.check(&disconnected), Sequence::InOrder);
+        assert_eq!(
+            tracker.check(&snap(BINANCE, None, Some(1))),
+            Sequence::InOrder
+        );
+        //But not when the venue has no sequence
+        assert_eq!(tracker.check(&snap(BINANCE, None, None)), Sequence::InOrder);
+        assert!(matches!(
+            tracker.check(&snap(BINANCE, None, Some(1))),
+            Sequence::Stale { .. }
+        ));
+
+        let metrics = tracker.metrics();
+        assert_eq!(metrics.stale(BINANCE), 3);
+        assert_eq!(metrics.stale(BITSTAMP), 0);
+        assert_eq!(metrics.to_string(), "binance (stale: 3, gaps: 0)");
+    }
+
+    #[test]
+    fn test_gaps() {
+        let mut tracker = SequenceTracker::new(1, &registry());
+        assert!(tracker.metrics().is_empty());
+        //Nothing to compare the first book with
+        assert_eq!(
+            tracker.check(&snap(BINANCE, Some(5), Some(10))),
+            Sequence::InOrder
+        );
+        assert_eq!(
+            tracker.check(&snap(BINANCE, Some(10), Some(11))),
+            Sequence::InOrder
+        );
+        //The messages after 11 up to 15 were missed
+        assert_eq!(
+            tracker.check(&snap(BINANCE, Some(15), Some(17))),
+            Sequence::Gap(4)
+        );
+        assert_eq!(
+            tracker.check(&snap(BINANCE, Some(17), Some(18))),
+            Sequence::InOrder
+        );
+        assert_eq!(tracker.metrics().gaps(BINANCE), 1);
+        assert!(!tracker.metrics().is_empty());
+    }
+}
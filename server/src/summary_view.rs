@@ -0,0 +1,689 @@
+use crate::aggregator::{Aggregator, BookUpdate};
+use crate::encoded_summary::EncodedSummary;
+use crate::market_data_source::{InstrumentId, MarketDatSourceLevel, OrderBook};
+use crate::multi_receiver_channels::SlowConsumerPolicy;
+use crate::orderbook::{self, Level, Summary, SummaryRequest, VenueAmount};
+use crate::scale::Scale;
+use crate::venue_registry::VenueRegistry;
+use std::borrow::Borrow;
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::Status;
+
+//Why a request is rejected, each one maps to a grpc status code
+#[derive(Debug, PartialEq)]
+pub enum RequestError {
+    UnknownSymbol(String),
+    UnknownVenue(String),
+    InvalidArgument(String),
+    //The venues don't have the quantity of a cost to fill request
+    NotEnoughDepth(String),
+}
+
+impl From<RequestError> for Status {
+    fn from(e: RequestError) -> Status {
+        match e {
+            RequestError::UnknownSymbol(symbol) => {
+                Status::not_found(format!("Unknown symbol {symbol}"))
+            }
+            RequestError::UnknownVenue(venue) => {
+                Status::not_found(format!("Unknown venue {venue}"))
+            }
+            RequestError::InvalidArgument(msg) => Status::invalid_argument(msg),
+            RequestError::NotEnoughDepth(msg) => Status::failed_precondition(msg),
+        }
+    }
+}
+
+//The instrument of a symbol, case insensitive. The empty request of an older client has no symbol
+//and asks for the first instrument
+pub fn instrument_id(symbol: &str, symbols: &[String]) -> Result<InstrumentId, RequestError> {
+    let index = if symbol.is_empty() {
+        0
+    } else {
+        let symbol = symbol.to_lowercase();
+        symbols
+            .iter()
+            .position(|s| *s == symbol)
+            .ok_or(RequestError::UnknownSymbol(symbol))?
+    };
+    if index >= symbols.len() {
+        return Err(RequestError::UnknownSymbol(symbol.to_string()));
+    }
+    Ok(InstrumentId(index as u16))
+}
+
+//What a client asked for, validated against the instruments and the venues of the server
+#[derive(Debug, Clone, PartialEq)]
+pub struct SummaryView {
+    pub instrument: InstrumentId,
+    pub depth: usize,
+    //Whether each venue is merged, indexed by venue id. None to merge all of them
+    pub venues: Option<Vec<bool>>,
+    //The minimum time between two updates, None to send every update
+    pub min_interval: Option<Duration>,
+    //None to use the policy of the server
+    pub policy: Option<SlowConsumerPolicy>,
+    //One level per price with the amount of each venue, instead of one level per venue and price
+    pub consolidated: bool,
+}
+
+impl SummaryView {
+    //depths is the number of levels merged for each instrument, the most a client can ask for
+    pub fn from_request(
+        request: &SummaryRequest,
+        symbols: &[String],
+        depths: &[usize],
+        registry: &VenueRegistry,
+    ) -> Result<SummaryView, RequestError> {
+        let instrument = instrument_id(&request.symbol, symbols)?;
+
+        let max_depth = depths[instrument.index()];
+        let depth = match request.depth as usize {
+            0 => max_depth,
+            depth if depth > max_depth => {
+                return Err(RequestError::InvalidArgument(format!(
+                    "The depth of {} cannot be more than {max_depth}",
+                    symbols[instrument.index()]
+                )));
+            }
+            depth => depth,
+        };
+
+        let venues = match (
+            request.include_venues.is_empty(),
+            request.exclude_venues.is_empty(),
+        ) {
+            (true, true) => None,
+            (false, true) => Some(Self::venue_mask(&request.include_venues, registry, true)?),
+            (true, false) => Some(Self::venue_mask(&request.exclude_venues, registry, false)?),
+            (false, false) => {
+                return Err(RequestError::InvalidArgument(
+                    "Cannot both include and exclude venues".to_string(),
+                ));
+            }
+        };
+
+        let min_interval = match request.max_updates_per_second {
+            0 => None,
+            rate => Some(Duration::from_secs(1) / rate),
+        };
+
+        let policy = match orderbook::SlowConsumerPolicy::from_i32(request.slow_consumer_policy) {
+            Some(orderbook::SlowConsumerPolicy::ServerDefault) => None,
+            Some(orderbook::SlowConsumerPolicy::Conflate) => Some(SlowConsumerPolicy::Conflate),
+            Some(orderbook::SlowConsumerPolicy::DropOldest) => Some(SlowConsumerPolicy::DropOldest),
+            Some(orderbook::SlowConsumerPolicy::Disconnect) => Some(SlowConsumerPolicy::Disconnect),
+            None => {
+                return Err(RequestError::InvalidArgument(format!(
+                    "Unknown slow consumer policy {}",
+                    request.slow_consumer_policy
+                )));
+            }
+        };
+
+        Ok(SummaryView {
+            instrument,
+            depth,
+            venues,
+            min_interval,
+            policy,
+            consolidated: request.consolidated,
+        })
+    }
+
+    //The listed venues are set to selected, the others to !selected
+    fn venue_mask(
+        names: &[String],
+        registry: &VenueRegistry,
+        selected: bool,
+    ) -> Result<Vec<bool>, RequestError> {
+        let mut mask = vec![!selected; registry.len()];
+        for name in names {
+            let (id, _) = registry
+                .iter()
+                .find(|(_, venue)| venue.name == *name)
+                .ok_or_else(|| RequestError::UnknownVenue(name.clone()))?;
+            mask[id.index()] = selected;
+        }
+        Ok(mask)
+    }
+
+    //The clients streaming the merged book as it is share the summary encoded by the aggregator
+    pub fn render_encoded(
+        &self,
+        update: &BookUpdate,
+        registry: &VenueRegistry,
+    ) -> Result<EncodedSummary, String> {
+        let summary = &update.summary;
+        if self.venues.is_none()
+            && !self.consolidated
+            && self.depth >= summary.bids.len().max(summary.asks.len())
+        {
+            return Ok(update.encoded.clone());
+        }
+        Ok(EncodedSummary::new(&self.render(update, registry)?))
+    }
+
+    //The summary sent to the client. The merged book is reused when all the venues are requested
+    //per venue, otherwise the requested venues are merged again
+    pub fn render(&self, update: &BookUpdate, registry: &VenueRegistry) -> Result<Summary, String> {
+        if self.venues.is_none() && !self.consolidated {
+            let mut summary = update.summary.clone();
+            summary.bids.truncate(self.depth);
+            summary.asks.truncate(self.depth);
+            return Ok(summary);
+        }
+
+        let masked: Vec<Arc<OrderBook>>;
+        let books = match &self.venues {
+            Some(mask) => {
+                masked = update
+                    .books
+                    .iter()
+                    .zip(mask)
+                    .map(|(book, selected)| match selected {
+                        true => book.clone(),
+                        false => Arc::default(),
+                    })
+                    .collect();
+                &masked
+            }
+            None => &update.books,
+        };
+        let scale = &update.scale;
+        let (bids, asks) = if self.consolidated {
+            (
+                Self::consolidate(
+                    registry,
+                    books,
+                    |book| book.bids.as_slice(),
+                    self.depth,
+                    scale,
+                    true,
+                ),
+                Self::consolidate(
+                    registry,
+                    books,
+                    |book| book.asks.as_slice(),
+                    self.depth,
+                    scale,
+                    false,
+                ),
+            )
+        } else {
+            (
+                Aggregator::merge_bid(registry, books, self.depth, scale)?,
+                Aggregator::merge_ask(registry, books, self.depth, scale)?,
+            )
+        };
+        let selected = |name: &str| match &self.venues {
+            Some(mask) => registry
+                .iter()
+                .any(|(id, venue)| venue.name == name && mask[id.index()]),
+            None => true,
+        };
+        let excluded_venues = update
+            .summary
+            .excluded_venues
+            .iter()
+            .filter(|name| selected(name))
+            .cloned()
+            .collect();
+        let mut summary = Aggregator::summary(
+            scale,
+            update.summary.symbol.clone(),
+            bids,
+            asks,
+            excluded_venues,
+        );
+        summary.publish_time_us = update.summary.publish_time_us;
+        summary.sequence = update.summary.sequence;
+        summary.venue_times = update
+            .summary
+            .venue_times
+            .iter()
+            .filter(|time| selected(&time.exchange))
+            .cloned()
+            .collect();
+        Ok(summary)
+    }
+
+    //Combine the levels of all the venues at the same price, the best price first. The venues of a
+    //level are in venue id order. All the levels of the venues are considered, so the depth covers
+    //as many prices as possible. The prices are adjusted for the taker fee of each venue like in the
+    //merge, so only the venues with the same fee adjusted price are combined
+    fn consolidate<B: Borrow<OrderBook>>(
+        registry: &VenueRegistry,
+        books: &[B],
+        side: impl Fn(&OrderBook) -> &[MarketDatSourceLevel],
+        depth: usize,
+        scale: &Scale,
+        bids: bool,
+    ) -> Vec<Level> {
+        //The venue, the fee adjusted price in ticks and the level
+        let mut levels: Vec<(&str, i64, &MarketDatSourceLevel)> = Vec::new();
+        for ((_, venue), book) in registry.iter().zip(books) {
+            let fee = match bids {
+                true => 1.0 - venue.taker_fee,
+                false => 1.0 + venue.taker_fee,
+            };
+            levels.extend(side(book.borrow()).iter().map(|level| {
+                (
+                    venue.name.as_str(),
+                    scale.adjusted_price_units(level.price, fee),
+                    level,
+                )
+            }));
+        }
+        //The sort is stable, the venues at the same price stay in venue id order
+        levels.sort_by(|(_, a, _), (_, b, _)| match bids {
+            true => b.cmp(a),
+            false => a.cmp(b),
+        });
+
+        let mut result: Vec<Level> = Vec::with_capacity(depth);
+        for (name, price_units, level) in levels {
+            let venue_level = level.to_orderbook_level(name.to_string(), scale);
+            let venue = VenueAmount {
+                exchange: name.to_string(),
+                amount: venue_level.amount,
+                raw_price: venue_level.raw_price,
+                amount_units: venue_level.amount_units,
+                raw_price_units: venue_level.raw_price_units,
+            };
+            if let Some(last) = result
+                .last_mut()
+                .filter(|last| last.price_units == price_units)
+            {
+                last.exchange.push(',');
+                last.exchange.push_str(name);
+                last.amount_units += venue.amount_units;
+                last.amount = scale.amount(last.amount_units);
+                last.venues.push(venue);
+                continue;
+            }
+            if result.len() == depth {
+                break;
+            }
+            //The raw price of the first venue, each venue has its own in venues
+            result.push(Level {
+                price: scale.price(price_units),
+                price_units,
+                venues: vec![venue],
+                ..venue_level
+            });
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::market_data_source::{Exchange, DEFAULT_DEPTH};
+    use crate::orderbook::VenueTime;
+    use crate::scale::Decimal;
+
+    fn registry() -> VenueRegistry {
+        let mut registry = VenueRegistry::new();
+        for exchange in [Exchange::Binance, Exchange::Bitstamp, Exchange::Kraken] {
+            registry
+                .register(&exchange.to_string(), exchange)
+                .expect("Error");
+        }
+        registry
+    }
+
+    fn symbols() -> Vec<String> {
+        vec!["ethbtc".to_string(), "btcusdt".to_string()]
+    }
+
+    //btcusdt is configured with more levels than the default
+    fn depths() -> Vec<usize> {
+        vec![DEFAULT_DEPTH, 20]
+    }
+
+    fn request(symbol: &str) -> SummaryRequest {
+        SummaryRequest {
+            symbol: symbol.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_from_request() {
+        let registry = registry();
+        let view =
+            SummaryView::from_request(&SummaryRequest::default(), &symbols(), &depths(), &registry)
+                .expect("Error");
+        assert_eq!(
+            view,
+            SummaryView {
+                instrument: InstrumentId(0),
+                depth: DEFAULT_DEPTH,
+                venues: None,
+                min_interval: None,
+                policy: None,
+                consolidated: false
+            }
+        );
+
+        let view = SummaryView::from_request(
+            &SummaryRequest {
+                depth: 3,
+                exclude_venues: vec!["bitstamp".to_string()],
+                max_updates_per_second: 4,
+                slow_consumer_policy: orderbook::SlowConsumerPolicy::Conflate as i32,
+                ..request("BTCUSDT")
+            },
+            &symbols(),
+            &depths(),
+            &registry,
+        )
+        .expect("Error");
+        assert_eq!(view.instrument, InstrumentId(1));
+        assert_eq!(view.depth, 3);
+        assert_eq!(view.venues, Some(vec![true, false, true]));
+        assert_eq!(view.min_interval, Some(Duration::from_millis(250)));
+        assert_eq!(view.policy, Some(SlowConsumerPolicy::Conflate));
+
+        let view = SummaryView::from_request(
+            &SummaryRequest {
+                include_venues: vec!["kraken".to_string()],
+                ..request("ethbtc")
+            },
+            &symbols(),
+            &depths(),
+            &registry,
+        )
+        .expect("Error");
+        assert_eq!(view.venues, Some(vec![false, false, true]));
+
+        //All the levels of the instrument by default
+        let view = SummaryView::from_request(&request("btcusdt"), &symbols(), &depths(), &registry)
+            .expect("Error");
+        assert_eq!(view.depth, 20);
+    }
+
+    #[test]
+    fn test_from_request_rejected() {
+        let registry = registry();
+        let error = |request: SummaryRequest| {
+            SummaryView::from_request(&request, &symbols(), &depths(), &registry)
+                .expect_err("Not rejected")
+        };
+        assert_eq!(
+            error(request("ltcbtc")),
+            RequestError::UnknownSymbol("ltcbtc".to_string())
+        );
+        assert_eq!(
+            error(SummaryRequest {
+                include_venues: vec!["ftx".to_string()],
+                ..request("")
+            }),
+            RequestError::UnknownVenue("ftx".to_string())
+        );
+        assert!(matches!(
+            error(SummaryRequest {
+                depth: DEFAULT_DEPTH as u32 + 1,
+                ..request("")
+            }),
+            RequestError::InvalidArgument(_)
+        ));
+        assert!(matches!(
+            error(SummaryRequest {
+                depth: 21,
+                ..request("btcusdt")
+            }),
+            RequestError::InvalidArgument(_)
+        ));
+        assert!(matches!(
+            error(SummaryRequest {
+                include_venues: vec!["binance".to_string()],
+                exclude_venues: vec!["kraken".to_string()],
+                ..request("")
+            }),
+            RequestError::InvalidArgument(_)
+        ));
+        assert!(matches!(
+            error(SummaryRequest {
+                slow_consumer_policy: 10,
+                ..request("")
+            }),
+            RequestError::InvalidArgument(_)
+        ));
+        assert!(SummaryView::from_request(&request(""), &[], &[], &registry).is_err());
+
+        let status = Status::from(RequestError::UnknownVenue("ftx".to_string()));
+        assert_eq!(status.code(), tonic::Code::NotFound);
+    }
+
+    #[test]
+    fn test_render() {
+        let registry = registry();
+        let mut books = vec![OrderBook::new(); registry.len()];
+        for (index, book) in books.iter_mut().enumerate() {
+            for level in 0..DEFAULT_DEPTH {
+                let price = 100.0 - (level * 3 + index) as f64;
+                book.bids.push(MarketDatSourceLevel::from_f64(price, 1.0));
+                book.asks
+                    .push(MarketDatSourceLevel::from_f64(price + 50.0, 1.0));
+            }
+        }
+        let bids = Aggregator::merge_bid(&registry, &books, DEFAULT_DEPTH, &Scale::default())
+            .expect("Error")
+            .to_vec();
+        let asks = Aggregator::merge_ask(&registry, &books, DEFAULT_DEPTH, &Scale::default())
+            .expect("Error")
+            .to_vec();
+        let mut summary = Aggregator::summary(
+            &Scale::default(),
+            "ethbtc".to_string(),
+            bids,
+            asks,
+            vec!["kraken".to_string()],
+        );
+        summary.publish_time_us = 2_000;
+        summary.sequence = 7;
+        summary.venue_times = ["binance", "bitstamp"]
+            .into_iter()
+            .map(|exchange| VenueTime {
+                exchange: exchange.to_string(),
+                received_time_us: 1_000,
+                book_age_us: 1_000,
+                ..Default::default()
+            })
+            .collect();
+        let update = BookUpdate {
+            encoded: EncodedSummary::new(&summary),
+            summary,
+            books: books.into_iter().map(Arc::new).collect(),
+            full_books: Vec::new(),
+            scale: Scale::default(),
+            generated_at: std::time::SystemTime::now(),
+        };
+
+        let view = SummaryView::from_request(
+            &SummaryRequest {
+                depth: 2,
+                ..request("")
+            },
+            &symbols(),
+            &depths(),
+            &registry,
+        )
+        .expect("Error");
+        let summary = view.render(&update, &registry).expect("Error");
+        assert_eq!(summary.bids, update.summary.bids[..2].to_vec());
+        let encoded = view.render_encoded(&update, &registry).expect("Error");
+        assert_eq!(encoded, EncodedSummary::new(&summary));
+
+        let view = SummaryView::from_request(&request(""), &symbols(), &depths(), &registry)
+            .expect("Error");
+        let encoded = view.render_encoded(&update, &registry).expect("Error");
+        assert_eq!(encoded, update.encoded);
+        assert_eq!(summary.excluded_venues, vec!["kraken".to_string()]);
+
+        let view = SummaryView::from_request(
+            &SummaryRequest {
+                include_venues: vec!["bitstamp".to_string()],
+                ..request("")
+            },
+            &symbols(),
+            &depths(),
+            &registry,
+        )
+        .expect("Error");
+        let summary = view.render(&update, &registry).expect("Error");
+        assert_eq!(summary.bids.len(), DEFAULT_DEPTH);
+        assert!(summary
+            .bids
+            .iter()
+            .all(|level| level.exchange == "bitstamp"));
+        assert_eq!(
+            summary.bids[0],
+            Level {
+                exchange: "bitstamp".to_string(),
+                price: 99.0,
+                raw_price: 99.0,
+                amount: 1.0,
+                venues: Vec::new(),
+                price_units: 9_900_000_000,
+                amount_units: 100_000_000,
+                raw_price_units: 9_900_000_000
+            }
+        );
+        assert_eq!(summary.spread, 50.0);
+        assert_eq!(summary.spread_units, 5_000_000_000);
+        assert_eq!(summary.price_decimals, 8);
+        assert!(summary.excluded_venues.is_empty());
+        assert_eq!(summary.symbol, "ethbtc");
+        //The times of the requested venues
+        assert_eq!(summary.publish_time_us, 2_000);
+        assert_eq!(summary.sequence, 7);
+        assert_eq!(summary.venue_times.len(), 1);
+        assert_eq!(summary.venue_times[0].exchange, "bitstamp");
+    }
+
+    #[test]
+    fn test_render_consolidated() {
+        let registry = registry();
+        let mut books = vec![OrderBook::new(); registry.len()];
+        for (index, book) in books.iter_mut().enumerate() {
+            for level in 0..DEFAULT_DEPTH {
+                //Binance and Bitstamp quote the same prices, Kraken every other price
+                let price = 100.0 - (level * (1 + index / 2)) as f64;
+                let amount = (index + 1) as f64;
+                book.bids
+                    .push(MarketDatSourceLevel::from_f64(price, amount));
+                book.asks
+                    .push(MarketDatSourceLevel::from_f64(250.0 - price, amount));
+            }
+        }
+        let summary = Summary {
+            excluded_venues: vec!["kraken".to_string()],
+            symbol: "ethbtc".to_string(),
+            ..Default::default()
+        };
+        let update = BookUpdate {
+            encoded: EncodedSummary::new(&summary),
+            summary,
+            books: books.into_iter().map(Arc::new).collect(),
+            full_books: Vec::new(),
+            scale: Scale::default(),
+            generated_at: std::time::SystemTime::now(),
+        };
+        let venue = |exchange: &str, amount: f64| VenueAmount {
+            exchange: exchange.to_string(),
+            amount,
+            raw_price: 100.0,
+            amount_units: Scale::default().amount_units(Decimal::from_f64(amount).expect("Error")),
+            raw_price_units: 10_000_000_000,
+        };
+
+        let view = SummaryView::from_request(
+            &SummaryRequest {
+                consolidated: true,
+                ..request("")
+            },
+            &symbols(),
+            &depths(),
+            &registry,
+        )
+        .expect("Error");
+        let summary = view.render(&update, &registry).expect("Error");
+        assert_eq!(summary.bids.len(), DEFAULT_DEPTH);
+        assert_eq!(
+            summary.bids[0],
+            Level {
+                exchange: "binance,bitstamp,kraken".to_string(),
+                price: 100.0,
+                raw_price: 100.0,
+                amount: 6.0,
+                venues: vec![
+                    venue("binance", 1.0),
+                    venue("bitstamp", 2.0),
+                    venue("kraken", 3.0)
+                ],
+                price_units: 10_000_000_000,
+                amount_units: 600_000_000,
+                raw_price_units: 10_000_000_000
+            }
+        );
+        assert_eq!(summary.bids[1].exchange, "binance,bitstamp");
+        assert_eq!(summary.bids[1].amount, 3.0);
+        //The 10 levels cover the prices from 100 to 91
+        assert_eq!(summary.bids[DEFAULT_DEPTH - 1].price, 91.0);
+        assert_eq!(summary.asks[0].price, 150.0);
+        assert_eq!(summary.asks[0].amount, 6.0);
+        assert_eq!(summary.spread, 50.0);
+        assert_eq!(summary.excluded_venues, vec!["kraken".to_string()]);
+        let encoded = view.render_encoded(&update, &registry).expect("Error");
+        assert_eq!(encoded, EncodedSummary::new(&summary));
+
+        let view = SummaryView::from_request(
+            &SummaryRequest {
+                consolidated: true,
+                depth: 1,
+                exclude_venues: vec!["kraken".to_string()],
+                ..request("")
+            },
+            &symbols(),
+            &depths(),
+            &registry,
+        )
+        .expect("Error");
+        let summary = view.render(&update, &registry).expect("Error");
+        assert_eq!(summary.bids.len(), 1);
+        assert_eq!(summary.bids[0].exchange, "binance,bitstamp");
+        assert_eq!(summary.bids[0].amount, 3.0);
+        assert!(summary.excluded_venues.is_empty());
+    }
+
+    #[test]
+    fn test_consolidate_in_ticks() {
+        let registry = registry();
+        let mut books = vec![OrderBook::new(); registry.len()];
+        //The same price, the second one off the ticks as merged when the off_tick rule is disabled, and
+        //amounts which don't add up exactly as doubles
+        books[0].bids.push(MarketDatSourceLevel::from_f64(0.3, 0.1));
+        books[1]
+            .bids
+            .push(MarketDatSourceLevel::from_f64(0.1 + 0.2, 0.2));
+        let bids = SummaryView::consolidate(
+            &registry,
+            &books,
+            |book| book.bids.as_slice(),
+            DEFAULT_DEPTH,
+            &Scale::default(),
+            true,
+        );
+        assert_eq!(bids.len(), 1);
+        assert_eq!(bids[0].exchange, "binance,bitstamp");
+        assert_eq!(bids[0].price, 0.3);
+        assert_eq!(bids[0].venues[1].raw_price, 0.3);
+        assert_eq!(bids[0].amount, 0.3);
+        assert_eq!(bids[0].amount_units, 30_000_000);
+    }
+}
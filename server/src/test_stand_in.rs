@@ -0,0 +1,92 @@
+//Local stand-ins for the exchanges, so the sources can be tested without network access
+use crate::market_data_source::OrderBookSnap;
+use futures_util::{SinkExt, StreamExt};
+use soketto::extension::{deflate::Deflate, Extension};
+use soketto::handshake::{server::Response, Server};
+use soketto::Mode;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::Receiver;
+use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+//Minimal HTTP server that answers each request with the next body, returns the address including path
+pub async fn start_rest_stand_in(path: &str, bodies: Vec<&'static str>) -> &'static str {
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("Error");
+    let address = format!("http://{}{}", listener.local_addr().expect("Error"), path);
+    tokio::spawn(async move {
+        for body in bodies {
+            let (mut socket, _) = listener.accept().await.expect("Error");
+            let mut buf = [0u8; 1024];
+            let _ = socket.read(&mut buf).await;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            socket.write_all(response.as_bytes()).await.expect("Error");
+        }
+    });
+    Box::leak(address.into_boxed_str())
+}
+
+//WebSocket server that pushes the given messages to the first client, returns the address including path
+pub async fn start_ws_stand_in(path: &str, messages: Vec<String>) -> &'static str {
+    start_ws_stand_in_frames(path, messages.into_iter().map(Message::Text).collect()).await
+}
+
+//Same as start_ws_stand_in with any frame, e.g. a compressed binary frame
+pub async fn start_ws_stand_in_frames(path: &str, frames: Vec<Message>) -> &'static str {
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("Error");
+    let address = format!("ws://{}{}", listener.local_addr().expect("Error"), path);
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.expect("Error");
+        let mut ws = tokio_tungstenite::accept_async(socket)
+            .await
+            .expect("Error");
+        for frame in frames {
+            ws.send(frame).await.expect("Error");
+        }
+        while ws.next().await.is_some() {}
+    });
+    Box::leak(address.into_boxed_str())
+}
+
+//WebSocket server accepting the permessage-deflate extension, which compresses each text message it
+//pushes to the first client. It panics when the client doesn't negotiate the extension, so the client
+//receives nothing
+pub async fn start_ws_deflate_stand_in(path: &str, messages: Vec<String>) -> &'static str {
+    let listener = TcpListener::bind("127.0.0.1:0").await.expect("Error");
+    let address = format!("ws://{}{}", listener.local_addr().expect("Error"), path);
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.expect("Error");
+        let mut server = Server::new(socket.compat());
+        server.add_extension(Box::new(Deflate::new(Mode::Server)));
+        let key = server.receive_request().await.expect("Error").key();
+        let accept = Response::Accept {
+            key,
+            protocol: None,
+        };
+        server.send_response(&accept).await.expect("Error");
+        let extensions: Vec<_> = server.drain_extensions().collect();
+        assert!(extensions.iter().all(|e| e.is_enabled()), "Not negotiated");
+        let mut builder = server.into_builder();
+        builder.add_extensions(extensions);
+        let (mut sender, mut receiver) = builder.finish();
+        for message in messages {
+            sender.send_text_owned(message).await.expect("Error");
+            sender.flush().await.expect("Error");
+        }
+        let mut data = Vec::new();
+        while receiver.receive_data(&mut data).await.is_ok() {}
+    });
+    Box::leak(address.into_boxed_str())
+}
+
+pub async fn recv(rx: &mut Receiver<OrderBookSnap>) -> OrderBookSnap {
+    tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .expect("Timeout")
+        .expect("Channel closed")
+}
@@ -0,0 +1,120 @@
+use crate::market_data_source::Exchange;
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+//Compact id of a registered venue, also the index of its book in the aggregator
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct VenueId(pub u16);
+
+impl VenueId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VenueEntry {
+    pub name: String,
+    pub exchange: Exchange,
+    //Wakes the connection of the venue up to force a reconnect, e.g. when its feed is stale
+    pub reconnect: Arc<Notify>,
+    //The taker fee the merge adjusts the prices of the venue with, as a fraction of the price.
+    //0 unless the merge is fee adjusted
+    pub taker_fee: f64,
+}
+
+//The venues known by the server, registered at startup from the configuration. Several venues can
+//connect to the same exchange (e.g. a backup endpoint) as long as their names are different
+#[derive(Debug, Default, Clone)]
+pub struct VenueRegistry {
+    venues: Vec<VenueEntry>,
+}
+
+impl VenueRegistry {
+    pub fn new() -> VenueRegistry {
+        VenueRegistry { venues: Vec::new() }
+    }
+
+    pub fn register(&mut self, name: &str, exchange: Exchange) -> Result<VenueId, String> {
+        if self.venues.iter().any(|v| v.name == name) {
+            return Err(format!("Venue {name} is registered twice"));
+        }
+        let id = u16::try_from(self.venues.len())
+            .map_err(|_| format!("Too many venues, cannot register {name}"))?;
+        self.venues.push(VenueEntry {
+            name: name.to_string(),
+            exchange,
+            reconnect: Arc::new(Notify::new()),
+            taker_fee: 0.0,
+        });
+        Ok(VenueId(id))
+    }
+
+    pub fn len(&self) -> usize {
+        self.venues.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.venues.is_empty()
+    }
+
+    pub fn get(&self, id: VenueId) -> Option<&VenueEntry> {
+        self.venues.get(id.index())
+    }
+
+    pub fn name(&self, id: VenueId) -> Option<&str> {
+        self.get(id).map(|v| v.name.as_str())
+    }
+
+    //Share the reconnect signal of the market data source of the venue
+    pub fn set_reconnect_signal(&mut self, id: VenueId, reconnect: Arc<Notify>) {
+        if let Some(venue) = self.venues.get_mut(id.index()) {
+            venue.reconnect = reconnect;
+        }
+    }
+
+    pub fn set_taker_fee(&mut self, id: VenueId, taker_fee: f64) {
+        if let Some(venue) = self.venues.get_mut(id.index()) {
+            venue.taker_fee = taker_fee;
+        }
+    }
+
+    //0 for an unknown venue
+    pub fn taker_fee(&self, id: VenueId) -> f64 {
+        self.get(id).map(|v| v.taker_fee).unwrap_or_default()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (VenueId, &VenueEntry)> {
+        self.venues
+            .iter()
+            .enumerate()
+            .map(|(index, venue)| (VenueId(index as u16), venue))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_register() {
+        let mut registry = VenueRegistry::new();
+        assert!(registry.is_empty());
+        assert_eq!(
+            registry.register("binance", Exchange::Binance),
+            Ok(VenueId(0))
+        );
+        assert_eq!(
+            registry.register("binance_backup", Exchange::Binance),
+            Ok(VenueId(1))
+        );
+        assert!(registry.register("binance", Exchange::Bitstamp).is_err());
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry.name(VenueId(1)), Some("binance_backup"));
+        assert_eq!(
+            registry.get(VenueId(1)).map(|v| v.exchange),
+            Some(Exchange::Binance)
+        );
+        assert_eq!(registry.name(VenueId(2)), None);
+    }
+}